@@ -0,0 +1,1866 @@
+#![windows_subsystem = "windows"]
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+use iced::clipboard;
+use iced::keyboard::KeyCode;
+use iced::widget::{
+    button, column, container, horizontal_rule, image, row, scrollable, text, text_input,
+};
+use iced::{theme, Alignment, Application, Color, Element, Font, Length, Settings};
+use tokio::sync::mpsc::Sender;
+use tracing::info;
+use websocket_chatroom::{
+    command,
+    highlight::{self, HighlightedSpan},
+    markdown::{self, Block, Span},
+    outbox::Outbox,
+    tokens::Tokens,
+    Attachment, Connection, MessageData, MessageFormat, MessageKind, MessageReport, ReportAction,
+    Role, RoomInfo, RoomVisibility, WebSocketClientToServerMessage, WebSocketServerToClientMessage,
+};
+
+use crate::transfers::{Purpose, Staged, Transfers};
+
+mod transfers;
+
+/// the width of image previews that have no thumbnail from the server
+const THUMBNAIL_WIDTH: f32 = 256.0;
+
+/// used for inline code and code blocks
+const MONOSPACE: Font = Font::External {
+    name: "SourceCodePro",
+    bytes: include_bytes!("../../../assets/SourceCodePro-VariableFont_wght.ttf"),
+};
+
+/// code blocks longer than this are collapsed until the user expands them
+const COLLAPSED_LINES: usize = 12;
+
+#[derive(Parser)]
+struct Cli {
+    #[clap(short, long)]
+    socket_addr: Option<String>,
+    /// the file keeping messages that are not delivered yet
+    #[clap(short, long)]
+    outbox: Option<PathBuf>,
+    /// where downloaded attachments are saved
+    #[clap(short, long)]
+    download_dir: Option<PathBuf>,
+    /// a font to draw the text with, like one covering CJK
+    #[clap(short, long)]
+    font: Option<PathBuf>,
+}
+
+/// the font of `--font`, iced wants it for the whole run
+static FONT: OnceLock<Vec<u8>> = OnceLock::new();
+
+pub fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::builder()
+                .with_default_directive("warn".parse().unwrap())
+                .from_env_lossy(),
+        )
+        .with_ansi(true)
+        .try_init()
+        .unwrap_or_else(|e| {
+            eprintln!("failed to init logger: {}", e);
+        });
+
+    let cli = Cli::parse();
+    let socket_addr = cli
+        .socket_addr
+        .unwrap_or("wss://chat.thesjq.com".to_string())
+        .parse()?;
+    let outbox_path = cli.outbox.unwrap_or_else(Outbox::default_path);
+    let download_dir = cli
+        .download_dir
+        .or_else(dirs::download_dir)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let mut settings = Settings::with_flags(Flags {
+        socket_addr,
+        outbox_path,
+        download_dir,
+    });
+    if let Some(path) = cli.font {
+        let font = std::fs::read(&path)
+            .map_err(|e| eyre::eyre!("cannot read {}: {}", path.display(), e))?;
+        settings.default_font = Some(FONT.get_or_init(|| font));
+    }
+    ChatRoom::run(settings)?;
+    Ok(())
+}
+
+#[allow(clippy::large_enum_variant)]
+enum ConnectionStatus {
+    Disconnected,
+    Connected {
+        connection: Connection,
+        user_id: u32,
+        all_users: BTreeSet<(u32, String)>,
+        rooms: RoomState,
+    },
+}
+
+/// the rooms as far as this client knows them
+#[derive(Default)]
+struct RoomState {
+    /// the room messages are sent to
+    current: RoomInfo,
+    /// pending invitations: the room and the user that sent it
+    invitations: Vec<(String, String)>,
+    /// the rooms listed by the server, empty until asked for
+    listed: Vec<RoomInfo>,
+    /// the roles of the users in the current room
+    roles: HashMap<u32, Role>,
+    /// the moderation queue, empty until asked for
+    reports: Vec<MessageReport>,
+}
+
+/// the delivery state of a message sent by this client
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DeliveryState {
+    /// waiting in the outbox for the connection to come back
+    Queued,
+    /// handed to the connection, waiting for the ack from the server
+    Pending,
+    /// acknowledged by the server
+    Sent,
+    /// not delivered, the reason is shown next to the retry button
+    Failed(String),
+}
+
+struct ChatEntry {
+    /// true if the message is sent by this client
+    own: bool,
+    data: MessageData,
+    /// only meaningful for own messages
+    state: DeliveryState,
+    /// parsed once when the entry is created, `None` for plain text messages
+    blocks: Option<Vec<Block>>,
+    /// the highlighted lines of every code block, in document order
+    code_blocks: Vec<Vec<Vec<HighlightedSpan>>>,
+    /// the indices of the code blocks expanded by the user
+    expanded: HashSet<usize>,
+}
+
+impl ChatEntry {
+    fn new(own: bool, data: MessageData, state: DeliveryState) -> Self {
+        let blocks = match data.format {
+            MessageFormat::Plain => None,
+            MessageFormat::Markdown => Some(markdown::parse(&data.data)),
+        };
+        let mut code_blocks = Vec::new();
+        if let Some(blocks) = &blocks {
+            highlight_code_blocks(blocks, &mut code_blocks);
+        }
+        Self {
+            own,
+            data,
+            state,
+            blocks,
+            code_blocks,
+            expanded: HashSet::new(),
+        }
+    }
+
+    /// move a message that never reached the server back to the outbox state,
+    /// attachments are not kept in the outbox and have to be retried
+    fn requeue(&mut self) {
+        if self.own && self.state == DeliveryState::Pending {
+            self.state = match self.data.attachment {
+                Some(_) => DeliveryState::Failed("upload interrupted".to_string()),
+                None => DeliveryState::Queued,
+            };
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Page {
+    /// the sender to send the url
+    Welcome(Sender<(String, String)>),
+    Main {
+        connections_status: ConnectionStatus,
+        /// kept outside of the connection so the user can keep typing while disconnected
+        input_message: String,
+        message_queue: VecDeque<ChatEntry>,
+        log_queue: VecDeque<String>,
+    },
+}
+
+#[allow(clippy::large_enum_variant)]
+enum AppStatus {
+    /// waiting for the subscription to be ready
+    WaitingSubscribtion,
+
+    /// data: the url sender, the user name
+    SubReady {
+        /// the page type
+        page: Page,
+    },
+}
+
+struct ChatRoom {
+    app_status: AppStatus,
+    user_name: String,
+    url: String,
+    /// the nonce attached to the next outgoing message
+    next_nonce: u64,
+    /// own messages that are not acknowledged by the server yet
+    outbox: Outbox,
+    /// send the input as markdown instead of plain text
+    markdown: bool,
+    transfers: Transfers,
+    /// a pasted, dropped or attached file shown for confirmation before it is uploaded
+    staged: Option<Staged>,
+    /// why the server closed the last connection, shown on the welcome page
+    closed: Option<String>,
+}
+
+struct Flags {
+    socket_addr: String,
+    outbox_path: PathBuf,
+    download_dir: PathBuf,
+}
+
+impl ChatRoom {
+    fn take_nonce(&mut self) -> u64 {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        nonce
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    /// the sender send url and user name
+    EnterWelcome(Sender<(String, String)>),
+    EnterMain,
+    Connected(Connection, u32, Vec<(u32, String)>),
+    Disconnected(String),
+    /// the server closed the connection and is not connected to again
+    Closed(String),
+    Received(WebSocketServerToClientMessage),
+    InputChange(String),
+    UserNameChange(String),
+    UrlChange(String),
+    Copy(String),
+    Send,
+    /// the message with the nonce is handed to the connection
+    Sent(u64),
+    /// the message with the nonce cannot be handed to the connection
+    SendFailed(u64, String),
+    /// send the failed message with the nonce again
+    Retry(u64),
+    /// this many messages from the outbox are handed to the connection
+    Flushed(usize),
+    ToggleMarkdown,
+    OpenLink(String),
+    /// expand or collapse a code block: the index of the entry and of the block
+    ToggleCode(usize, usize),
+    /// upload the file at the path in the input
+    Attach,
+    /// use the input as the topic of the room
+    SetTopic,
+    ListRooms,
+    /// switch to the room, password protected rooms are joined with `/join`
+    JoinRoom(String),
+    /// accept or decline the invitation to the room
+    AnswerInvite(String, bool),
+    /// report the message with the id, the input is the reason
+    Report(u64),
+    ListReports,
+    ResolveReport(u64, ReportAction),
+    /// upload the image or the file in the clipboard
+    Paste,
+    FileDropped(PathBuf),
+    /// the name and content of the file to upload have been read
+    FileLoaded(Result<(String, Arc<Vec<u8>>), String>),
+    /// upload the staged file
+    ConfirmUpload,
+    CancelUpload,
+    Download(Attachment),
+    /// switch an image attachment between its thumbnail and the full image
+    ToggleImage(Attachment),
+    /// a chunk of a download: the transfer id and the data
+    Chunk(u64, Vec<u8>),
+    OpenFile(PathBuf),
+    Clear,
+    Exit,
+}
+
+impl Application for ChatRoom {
+    type Message = Message;
+
+    type Executor = iced::executor::Default;
+
+    type Theme = iced::Theme;
+
+    type Flags = Flags;
+
+    fn new(flags: Self::Flags) -> (Self, iced::Command<Self::Message>) {
+        (
+            Self {
+                app_status: AppStatus::WaitingSubscribtion,
+                user_name: "Guest".to_string(),
+                url: flags.socket_addr,
+                outbox: Outbox::load(flags.outbox_path),
+                markdown: true,
+                transfers: Transfers::new(flags.download_dir),
+                staged: None,
+                closed: None,
+                // start from the current time so nonces of different runs do not collide
+                next_nonce: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or_default(),
+            },
+            iced::Command::none(),
+        )
+    }
+
+    fn title(&self) -> String {
+        String::from("chatroom")
+    }
+
+    fn update(&mut self, message: Message) -> iced::Command<Message> {
+        match message {
+            Message::EnterWelcome(url_sender) => {
+                self.app_status = AppStatus::SubReady {
+                    page: Page::Welcome(url_sender),
+                };
+                iced::Command::none()
+            }
+            Message::EnterMain => {
+                if let AppStatus::SubReady { page } = &mut self.app_status {
+                    if let Page::Welcome(sender) = page {
+                        sender
+                            .try_send((self.url.clone(), self.user_name.clone()))
+                            .unwrap();
+                        self.closed = None;
+                        self.outbox.select(&self.url, &self.user_name);
+                        // show what is left from the last run, it is sent once connected
+                        let message_queue = self
+                            .outbox
+                            .messages()
+                            .iter()
+                            .map(|data| ChatEntry::new(true, data.clone(), DeliveryState::Queued))
+                            .collect();
+                        *page = Page::Main {
+                            connections_status: ConnectionStatus::Disconnected,
+                            input_message: String::new(),
+                            message_queue,
+                            log_queue: VecDeque::new(),
+                        };
+                    }
+
+                    iced::Command::none()
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::Connected(connection, user_id, all_users) => {
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status,
+                            message_queue,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    *connections_status = ConnectionStatus::Connected {
+                        connection: connection.clone(),
+                        user_id,
+                        all_users: all_users.into_iter().collect(),
+                        rooms: RoomState {
+                            current: RoomInfo {
+                                name: command::LOBBY.to_string(),
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        },
+                    };
+                    if self.outbox.is_empty() {
+                        return iced::Command::none();
+                    }
+                    // flush the outbox in order, the server drops nonces it has already relayed
+                    let mut messages = Vec::new();
+                    for data in self.outbox.messages() {
+                        let mut data = data.clone();
+                        data.id = user_id;
+                        if let Some(entry) = message_queue
+                            .iter_mut()
+                            .find(|entry| entry.own && entry.data.nonce == data.nonce)
+                        {
+                            entry.data.id = user_id;
+                            entry.state = DeliveryState::Pending;
+                        }
+                        messages.push((
+                            data.nonce,
+                            WebSocketClientToServerMessage::UserMessage(data),
+                        ));
+                    }
+                    flush_outbox(connection, messages)
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::Disconnected(_error_message) => {
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status,
+                            message_queue,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    *connections_status = ConnectionStatus::Disconnected;
+                    // still in the outbox, they are sent again after reconnecting
+                    for entry in message_queue.iter_mut() {
+                        entry.requeue();
+                    }
+                }
+                iced::Command::none()
+            }
+            Message::Closed(reason) => {
+                // the subscription asks for a new url, which brings back the welcome page
+                self.closed = Some(reason);
+                iced::Command::none()
+            }
+            Message::Received(message) => {
+                let transfer_id = self.take_nonce();
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            message_queue,
+                            connections_status,
+                            log_queue,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    match connections_status {
+                        ConnectionStatus::Disconnected => {}
+                        ConnectionStatus::Connected {
+                            all_users,
+                            connection,
+                            user_id,
+                            rooms,
+                        } => match message {
+                            WebSocketServerToClientMessage::UserMessage(message) => {
+                                info!("message: {:?}", message);
+                                let thumbnail = message
+                                    .attachment
+                                    .as_ref()
+                                    .and_then(|attachment| attachment.thumbnail.clone())
+                                    .filter(|thumbnail| {
+                                        self.transfers.image(&thumbnail.sha256).is_none()
+                                    });
+                                message_queue.push_back(ChatEntry::new(
+                                    false,
+                                    message,
+                                    DeliveryState::Sent,
+                                ));
+                                if let Some(thumbnail) = thumbnail {
+                                    self.transfers.start_download(
+                                        transfer_id,
+                                        &thumbnail.sha256,
+                                        "thumbnail",
+                                        Purpose::Show,
+                                    );
+                                    return transfers::download(
+                                        connection.clone(),
+                                        transfer_id,
+                                        thumbnail.sha256,
+                                    );
+                                }
+                            }
+                            WebSocketServerToClientMessage::Ack { nonce, message_id } => {
+                                info!("message ack: {} {}", nonce, message_id);
+                                if let Some(entry) = message_queue
+                                    .iter_mut()
+                                    .find(|entry| entry.own && entry.data.nonce == nonce)
+                                {
+                                    entry.data.message_id = message_id;
+                                    entry.state = DeliveryState::Sent;
+                                }
+                                self.outbox.remove(nonce);
+                                self.transfers.upload_done(nonce);
+                            }
+                            WebSocketServerToClientMessage::Rejected { nonce, reason } => {
+                                info!("message rejected: {} {}", nonce, reason);
+                                if let Some(entry) = message_queue
+                                    .iter_mut()
+                                    .find(|entry| entry.own && entry.data.nonce == nonce)
+                                {
+                                    entry.state = DeliveryState::Failed(reason);
+                                } else {
+                                    // a failed command
+                                    log_queue.push_back(reason);
+                                }
+                                self.outbox.remove(nonce);
+                                self.transfers.upload_done(nonce);
+                            }
+                            WebSocketServerToClientMessage::DownloadStarted {
+                                transfer_id,
+                                size,
+                            } => self.transfers.download_started(transfer_id, size),
+                            WebSocketServerToClientMessage::DownloadFinished { transfer_id } => {
+                                match self.transfers.download_finished(transfer_id) {
+                                    Some(Ok(Some(path))) => {
+                                        log_queue.push_back(format!("saved to {}", path.display()))
+                                    }
+                                    Some(Err(e)) => log_queue.push_back(e),
+                                    Some(Ok(None)) | None => {}
+                                }
+                            }
+                            WebSocketServerToClientMessage::DownloadFailed {
+                                transfer_id,
+                                reason,
+                            } => {
+                                self.transfers.download_failed(transfer_id);
+                                log_queue.push_back(format!("download failed: {reason}"));
+                            }
+                            WebSocketServerToClientMessage::Disconnected(id, name) => {
+                                info!("message disconnected: {:?} {}", id, name);
+                                all_users.remove(&(id, name));
+                            }
+                            WebSocketServerToClientMessage::NewUserAdded(id, name) => {
+                                info!("message new user: {:?} {}", id, name);
+                                all_users.insert((id, name));
+                            }
+                            WebSocketServerToClientMessage::Renamed(id, name) => {
+                                all_users.retain(|(user, _)| *user != id);
+                                if id == *user_id {
+                                    self.user_name = name.clone();
+                                }
+                                all_users.insert((id, name));
+                            }
+                            WebSocketServerToClientMessage::Joined { room } => {
+                                rooms
+                                    .invitations
+                                    .retain(|(invited, _)| *invited != room.name);
+                                rooms.current = room;
+                                rooms.roles.clear();
+                            }
+                            WebSocketServerToClientMessage::RoomRoles(roles) => {
+                                rooms.roles = roles.into_iter().collect();
+                            }
+                            WebSocketServerToClientMessage::Reports(reports) => {
+                                rooms.reports = reports;
+                            }
+                            WebSocketServerToClientMessage::MessageDeleted(message_id) => {
+                                message_queue.retain(|entry| entry.data.message_id != message_id);
+                            }
+                            WebSocketServerToClientMessage::RoomUpdated(info)
+                                if info.name == rooms.current.name =>
+                            {
+                                rooms.current = info;
+                            }
+                            WebSocketServerToClientMessage::Invited { room, from } => {
+                                log_queue.push_back(format!("{from} invited you to {room}"));
+                                rooms.invitations.retain(|(invited, _)| *invited != room);
+                                rooms.invitations.push((room, from));
+                            }
+                            WebSocketServerToClientMessage::RoomList(listed) => {
+                                rooms.listed = listed;
+                            }
+                            WebSocketServerToClientMessage::Notice(text) => {
+                                log_queue.push_back(text);
+                            }
+                            // kept by the subscription, see `Tokens`
+                            WebSocketServerToClientMessage::Token(_) => {
+                                log_queue.push_back(format!(
+                                    "your name is registered now, its token is kept in {}",
+                                    Tokens::default_path().display()
+                                ));
+                            }
+                            WebSocketServerToClientMessage::CommandOutput { text, .. } => {
+                                log_queue.extend(text.lines().map(str::to_string));
+                            }
+                            _ => {}
+                        },
+                    }
+                }
+                iced::Command::none()
+            }
+            Message::InputChange(input) => {
+                if let AppStatus::SubReady {
+                    page: Page::Main { input_message, .. },
+                } = &mut self.app_status
+                {
+                    *input_message = input;
+                }
+                iced::Command::none()
+            }
+            Message::UrlChange(url) => {
+                self.url = url;
+                iced::Command::none()
+            }
+            Message::UserNameChange(user_name) => {
+                self.user_name = user_name;
+                iced::Command::none()
+            }
+            Message::Send => {
+                let nonce = self.take_nonce();
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status,
+                            input_message,
+                            message_queue,
+                            log_queue,
+                        },
+                } = &mut self.app_status
+                {
+                    let input = std::mem::take(input_message);
+                    if let Some((name, args)) = command::parse(&input) {
+                        let ConnectionStatus::Connected { connection, .. } = connections_status
+                        else {
+                            log_queue
+                                .push_back("cannot run commands while disconnected".to_string());
+                            return iced::Command::none();
+                        };
+                        let message = WebSocketClientToServerMessage::Command {
+                            nonce,
+                            name: name.to_string(),
+                            args: args.to_string(),
+                        };
+                        return send_to_connection(connection.clone(), message, nonce);
+                    }
+                    let mut data = MessageData {
+                        id: 0,
+                        name: self.user_name.clone(),
+                        data: command::unescape(&input).to_string(),
+                        nonce,
+                        message_id: 0,
+                        format: if self.markdown {
+                            MessageFormat::Markdown
+                        } else {
+                            MessageFormat::Plain
+                        },
+                        kind: MessageKind::Normal,
+                        attachment: None,
+                        recipient: None,
+                    };
+                    match connections_status {
+                        ConnectionStatus::Connected {
+                            connection,
+                            user_id,
+                            ..
+                        } => {
+                            data.id = *user_id;
+                            self.outbox.push(data.clone());
+                            let message = WebSocketClientToServerMessage::UserMessage(data.clone());
+                            message_queue.push_back(ChatEntry::new(
+                                true,
+                                data,
+                                DeliveryState::Pending,
+                            ));
+                            send_to_connection(connection.clone(), message, nonce)
+                        }
+                        ConnectionStatus::Disconnected => {
+                            self.outbox.push(data.clone());
+                            message_queue.push_back(ChatEntry::new(
+                                true,
+                                data,
+                                DeliveryState::Queued,
+                            ));
+                            log_queue.push_back("queued".to_string());
+                            iced::Command::none()
+                        }
+                    }
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::Retry(nonce) => {
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status,
+                            message_queue,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    let Some(entry) = message_queue
+                        .iter_mut()
+                        .find(|entry| entry.own && entry.data.nonce == nonce)
+                    else {
+                        return iced::Command::none();
+                    };
+                    match connections_status {
+                        ConnectionStatus::Connected {
+                            connection,
+                            user_id,
+                            ..
+                        } => {
+                            entry.data.id = *user_id;
+                            if let Some(attachment) = &entry.data.attachment {
+                                let Some(content) = self.transfers.upload_content(nonce) else {
+                                    return iced::Command::none();
+                                };
+                                entry.state = DeliveryState::Pending;
+                                return transfers::upload(
+                                    connection.clone(),
+                                    nonce,
+                                    attachment.clone(),
+                                    content,
+                                );
+                            }
+                            entry.state = DeliveryState::Pending;
+                            self.outbox.push(entry.data.clone());
+                            let message =
+                                WebSocketClientToServerMessage::UserMessage(entry.data.clone());
+                            send_to_connection(connection.clone(), message, nonce)
+                        }
+                        ConnectionStatus::Disconnected if entry.data.attachment.is_some() => {
+                            iced::Command::none()
+                        }
+                        ConnectionStatus::Disconnected => {
+                            entry.state = DeliveryState::Queued;
+                            self.outbox.push(entry.data.clone());
+                            iced::Command::none()
+                        }
+                    }
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::SendFailed(nonce, reason) => {
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            message_queue,
+                            log_queue,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    if let Some(entry) = message_queue
+                        .iter_mut()
+                        .find(|entry| entry.own && entry.data.nonce == nonce)
+                    {
+                        entry.requeue();
+                    }
+                    log_queue.push_back(reason);
+                }
+                iced::Command::none()
+            }
+            Message::Flushed(count) => {
+                if let AppStatus::SubReady {
+                    page: Page::Main { log_queue, .. },
+                } = &mut self.app_status
+                {
+                    log_queue.push_back(format!("flushed {count} queued messages"));
+                }
+                iced::Command::none()
+            }
+            Message::Sent(_nonce) => {
+                // the input is already cleared when the message entered the queue
+                if let AppStatus::SubReady {
+                    page: Page::Main { log_queue, .. },
+                } = &mut self.app_status
+                {
+                    log_queue.push_back("sent".to_string());
+                }
+                iced::Command::none()
+            }
+            Message::Clear => {
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            log_queue,
+                            message_queue,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    log_queue.clear();
+                    message_queue.clear();
+                }
+                iced::Command::none()
+            }
+            Message::ToggleMarkdown => {
+                self.markdown = !self.markdown;
+                iced::Command::none()
+            }
+            Message::ToggleCode(entry, block) => {
+                if let AppStatus::SubReady {
+                    page: Page::Main { message_queue, .. },
+                } = &mut self.app_status
+                {
+                    if let Some(entry) = message_queue.get_mut(entry) {
+                        if !entry.expanded.remove(&block) {
+                            entry.expanded.insert(block);
+                        }
+                    }
+                }
+                iced::Command::none()
+            }
+            Message::Attach => {
+                let Some(path) = (match &mut self.app_status {
+                    AppStatus::SubReady {
+                        page: Page::Main { input_message, .. },
+                    } => Some(std::mem::take(input_message)),
+                    _ => None,
+                }) else {
+                    return iced::Command::none();
+                };
+                let path = PathBuf::from(path.trim());
+                iced::Command::perform(transfers::read_file(path), Message::FileLoaded)
+            }
+            Message::SetTopic => {
+                let nonce = self.take_nonce();
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status:
+                                ConnectionStatus::Connected {
+                                    connection, rooms, ..
+                                },
+                            input_message,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    let message = WebSocketClientToServerMessage::SetTopic {
+                        nonce,
+                        room: rooms.current.name.clone(),
+                        topic: std::mem::take(input_message).trim().to_string(),
+                    };
+                    send_to_connection(connection.clone(), message, nonce)
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::ListRooms => {
+                let nonce = self.take_nonce();
+                self.send_when_connected(WebSocketClientToServerMessage::ListRooms, nonce)
+            }
+            Message::JoinRoom(room) => {
+                let nonce = self.take_nonce();
+                let message = WebSocketClientToServerMessage::Command {
+                    nonce,
+                    name: "join".to_string(),
+                    args: room,
+                };
+                self.send_when_connected(message, nonce)
+            }
+            Message::AnswerInvite(room, accept) => {
+                let nonce = self.take_nonce();
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status: ConnectionStatus::Connected { rooms, .. },
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    rooms.invitations.retain(|(invited, _)| *invited != room);
+                }
+                let message = if accept {
+                    WebSocketClientToServerMessage::AcceptInvite { room }
+                } else {
+                    WebSocketClientToServerMessage::DeclineInvite { room }
+                };
+                self.send_when_connected(message, nonce)
+            }
+            Message::Report(message_id) => {
+                let nonce = self.take_nonce();
+                let reason = match &mut self.app_status {
+                    AppStatus::SubReady {
+                        page: Page::Main { input_message, .. },
+                    } => std::mem::take(input_message).trim().to_string(),
+                    _ => String::new(),
+                };
+                let message = WebSocketClientToServerMessage::Report { message_id, reason };
+                self.send_when_connected(message, nonce)
+            }
+            Message::ListReports => {
+                let nonce = self.take_nonce();
+                self.send_when_connected(WebSocketClientToServerMessage::ListReports, nonce)
+            }
+            Message::ResolveReport(report_id, action) => {
+                let nonce = self.take_nonce();
+                let message = WebSocketClientToServerMessage::ResolveReport { report_id, action };
+                self.send_when_connected(message, nonce)
+            }
+            Message::Paste => {
+                iced::Command::perform(transfers::read_clipboard(), Message::FileLoaded)
+            }
+            Message::FileDropped(path) => {
+                iced::Command::perform(transfers::read_file(path), Message::FileLoaded)
+            }
+            Message::FileLoaded(result) => {
+                match result {
+                    Ok((file_name, content)) => {
+                        self.staged = Some(Staged::new(file_name, content));
+                    }
+                    Err(e) => {
+                        if let AppStatus::SubReady {
+                            page: Page::Main { log_queue, .. },
+                        } = &mut self.app_status
+                        {
+                            log_queue.push_back(e);
+                        }
+                    }
+                }
+                iced::Command::none()
+            }
+            Message::CancelUpload => {
+                self.staged = None;
+                iced::Command::none()
+            }
+            Message::ConfirmUpload => {
+                let nonce = self.take_nonce();
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status,
+                            message_queue,
+                            log_queue,
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    // the staged file is kept until it can be uploaded
+                    let ConnectionStatus::Connected {
+                        connection,
+                        user_id,
+                        ..
+                    } = connections_status
+                    else {
+                        log_queue.push_back("cannot upload while disconnected".to_string());
+                        return iced::Command::none();
+                    };
+                    let Some(Staged {
+                        file_name, content, ..
+                    }) = self.staged.take()
+                    else {
+                        return iced::Command::none();
+                    };
+                    let attachment = transfers::attachment_for(&file_name, &content);
+                    let data = MessageData {
+                        id: *user_id,
+                        name: self.user_name.clone(),
+                        data: attachment.file_name.clone(),
+                        nonce,
+                        message_id: 0,
+                        format: MessageFormat::Plain,
+                        kind: MessageKind::Normal,
+                        attachment: Some(attachment.clone()),
+                        recipient: None,
+                    };
+                    message_queue.push_back(ChatEntry::new(true, data, DeliveryState::Pending));
+                    self.transfers.keep_upload(nonce, content.clone());
+                    if attachment.mime.starts_with("image/") {
+                        self.transfers
+                            .show_image(&attachment.sha256, content.to_vec());
+                    }
+                    transfers::upload(connection.clone(), nonce, attachment, content)
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::Download(attachment) => {
+                let transfer_id = self.take_nonce();
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status: ConnectionStatus::Connected { connection, .. },
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    self.transfers.start_download(
+                        transfer_id,
+                        &attachment.sha256,
+                        &attachment.file_name,
+                        Purpose::Save,
+                    );
+                    transfers::download(connection.clone(), transfer_id, attachment.sha256)
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::ToggleImage(attachment) => {
+                let transfer_id = self.take_nonce();
+                let expanded = self.transfers.toggle_expanded(&attachment.sha256);
+                if !expanded
+                    || self.transfers.image(&attachment.sha256).is_some()
+                    || self.transfers.progress(&attachment.sha256).is_some()
+                {
+                    return iced::Command::none();
+                }
+                if let AppStatus::SubReady {
+                    page:
+                        Page::Main {
+                            connections_status: ConnectionStatus::Connected { connection, .. },
+                            ..
+                        },
+                } = &mut self.app_status
+                {
+                    self.transfers.start_download(
+                        transfer_id,
+                        &attachment.sha256,
+                        &attachment.file_name,
+                        Purpose::Show,
+                    );
+                    transfers::download(connection.clone(), transfer_id, attachment.sha256)
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Message::Chunk(transfer_id, data) => {
+                self.transfers.chunk(transfer_id, &data);
+                iced::Command::none()
+            }
+            Message::OpenFile(path) => {
+                if let Err(e) = open::that(&path) {
+                    tracing::warn!("cannot open {}: {}", path.display(), e);
+                }
+                iced::Command::none()
+            }
+            Message::OpenLink(url) => {
+                if let Err(e) = open::that(&url) {
+                    tracing::warn!("cannot open {}: {}", url, e);
+                }
+                iced::Command::none()
+            }
+            Message::Exit => {
+                process::exit(0);
+            }
+            Message::Copy(text) => clipboard::write(text),
+        }
+    }
+
+    fn subscription(&self) -> iced::Subscription<Self::Message> {
+        let web_socket_sub = websocket_chatroom::connect().map(|event| match event {
+            websocket_chatroom::Event::Connected(sender, id, all_users) => {
+                Message::Connected(sender, id, all_users)
+            }
+            websocket_chatroom::Event::Disconnected => {
+                Message::Disconnected("Disconnected".to_string())
+            }
+            websocket_chatroom::Event::Closed(reason) => Message::Closed(reason),
+            websocket_chatroom::Event::MessageReceived(message) => Message::Received(message),
+            websocket_chatroom::Event::ChunkReceived(transfer_id, data) => {
+                Message::Chunk(transfer_id, data)
+            }
+            websocket_chatroom::Event::ReadyToConnect(url_sender) => {
+                // enter the welcome stat
+                Message::EnterWelcome(url_sender)
+            }
+        });
+        let func: fn(iced::Event, iced::event::Status) -> Option<Message> =
+            |event, _status| match event {
+                iced::Event::Keyboard(iced::keyboard::Event::KeyReleased {
+                    key_code: KeyCode::Enter,
+                    modifiers: _,
+                }) => Some(Message::Send),
+                iced::Event::Keyboard(iced::keyboard::Event::KeyPressed {
+                    key_code: KeyCode::V,
+                    modifiers,
+                }) if modifiers.control() && modifiers.shift() => Some(Message::Paste),
+                iced::Event::Window(iced::window::Event::FileDropped(path)) => {
+                    Some(Message::FileDropped(path))
+                }
+                _ => None,
+            };
+        let key_board_sub = iced::subscription::events_with(func);
+        iced::Subscription::batch(vec![web_socket_sub, key_board_sub])
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        match &self.app_status {
+            AppStatus::WaitingSubscribtion => text("Waiting for subscribtion init").size(20).into(),
+            AppStatus::SubReady { page } => match page {
+                Page::Welcome(_) => self.welcome_view(),
+                Page::Main {
+                    connections_status,
+                    input_message,
+                    message_queue,
+                    log_queue,
+                } => match connections_status {
+                    ConnectionStatus::Disconnected => {
+                        self.disconnected_view(message_queue, log_queue, input_message)
+                    }
+                    ConnectionStatus::Connected {
+                        user_id,
+                        all_users,
+                        rooms,
+                        ..
+                    } => self.connected_view(
+                        message_queue,
+                        log_queue,
+                        input_message,
+                        *user_id,
+                        rooms,
+                        all_users.iter(),
+                    ),
+                },
+            },
+        }
+    }
+}
+
+impl ChatRoom {
+    /// send the message if connected, requests are not queued in the outbox
+    fn send_when_connected(
+        &self,
+        message: WebSocketClientToServerMessage,
+        nonce: u64,
+    ) -> iced::Command<Message> {
+        match &self.app_status {
+            AppStatus::SubReady {
+                page:
+                    Page::Main {
+                        connections_status: ConnectionStatus::Connected { connection, .. },
+                        ..
+                    },
+            } => send_to_connection(connection.clone(), message, nonce),
+            _ => iced::Command::none(),
+        }
+    }
+
+    fn markdown_button(&self) -> Element<'_, Message> {
+        let label = if self.markdown {
+            "markdown: on"
+        } else {
+            "markdown: off"
+        };
+        button(label)
+            .padding(5)
+            .on_press(Message::ToggleMarkdown)
+            .into()
+    }
+
+    /// the file waiting to be uploaded with buttons to send or discard it
+    fn staged_view(&self) -> Element<'_, Message> {
+        let Some(staged) = &self.staged else {
+            return column(vec![]).into();
+        };
+        let confirm = row(vec![
+            text(format!(
+                "upload {} ({})?",
+                staged.file_name,
+                transfers::format_size(staged.content.len() as u64)
+            ))
+            .size(20)
+            .into(),
+            button("send file")
+                .padding(5)
+                .on_press(Message::ConfirmUpload)
+                .into(),
+            button("cancel")
+                .padding(5)
+                .on_press(Message::CancelUpload)
+                .into(),
+        ])
+        .spacing(5)
+        .align_items(Alignment::Center);
+        match &staged.preview {
+            Some(preview) => column(vec![
+                image(preview.clone()).width(THUMBNAIL_WIDTH).into(),
+                confirm.into(),
+            ])
+            .spacing(5)
+            .align_items(Alignment::Center)
+            .into(),
+            None => confirm.into(),
+        }
+    }
+
+    fn welcome_view(&self) -> Element<'_, Message> {
+        let user_name = text_input("user name", &self.user_name, Message::UserNameChange);
+        let url = text_input("url", &self.url, Message::UrlChange);
+        let start_bt = button("start").padding(5).on_press(Message::EnterMain);
+        let mut items = vec![user_name.into(), url.into(), start_bt.into()];
+        if let Some(reason) = &self.closed {
+            items.push(
+                text(format!("the server closed the connection: {reason}"))
+                    .style(Color::from_rgb8(204, 0, 0))
+                    .into(),
+            );
+        }
+        let col = column(items)
+            .align_items(Alignment::Center)
+            .padding(10)
+            .width(Length::Fill)
+            .height(Length::Fill);
+        col.into()
+    }
+
+    fn disconnected_view(
+        &self,
+        message_queue: &VecDeque<ChatEntry>,
+        log_queue: &VecDeque<String>,
+        input_message: &str,
+    ) -> Element<'_, Message> {
+        let text = text("Disconnected, messages are queued until reconnected")
+            .size(20)
+            .style(Color::from_rgb8(102, 102, 153));
+        let send_bt = button("queue").padding(5).on_press(Message::Send);
+        let exit_bt = button("exit").padding(5).on_press(Message::Exit);
+        let bt_row = row(vec![send_bt.into(), exit_bt.into(), self.markdown_button()])
+            .padding(10)
+            .spacing(3)
+            .align_items(Alignment::Center);
+        let input_message = text_input("input here", input_message, Message::InputChange);
+        let msg_log_row = build_msg_and_log(message_queue, log_queue, &self.transfers);
+        let col = column(vec![
+            text.into(),
+            bt_row.into(),
+            self.staged_view(),
+            input_message.into(),
+            msg_log_row,
+        ])
+        .align_items(Alignment::Center)
+        .padding(10)
+        .width(Length::Fill)
+        .height(Length::Fill);
+        col.into()
+    }
+
+    fn connected_view<'a>(
+        &self,
+        message_queue: &VecDeque<ChatEntry>,
+        log_queue: &VecDeque<String>,
+        input_message: &str,
+        user_id: u32,
+        rooms: &RoomState,
+        all_users: impl IntoIterator<Item = &'a (u32, String)>,
+    ) -> Element<'_, Message> {
+        let status = format!("Connected: id: {user_id}, name: {}", self.user_name);
+        let status_text = text(status).size(20).style(Color::from_rgb8(102, 102, 153));
+
+        let send_bt = button("send").padding(5).on_press(Message::Send);
+        let exit_bt = button("exit").padding(5).on_press(Message::Exit);
+        let clear_bt = button("clear").padding(5).on_press(Message::Clear);
+        let attach_bt = button("attach file at path")
+            .padding(5)
+            .on_press(Message::Attach);
+        let paste_bt = button("paste file").padding(5).on_press(Message::Paste);
+        let topic_bt = button("set topic").padding(5).on_press(Message::SetTopic);
+        let rooms_bt = button("rooms").padding(5).on_press(Message::ListRooms);
+        let mut buttons = vec![
+            send_bt.into(),
+            attach_bt.into(),
+            paste_bt.into(),
+            topic_bt.into(),
+            rooms_bt.into(),
+        ];
+        if rooms
+            .roles
+            .get(&user_id)
+            .is_some_and(|role| *role >= Role::Moderator)
+        {
+            buttons.push(
+                button("reports")
+                    .padding(5)
+                    .on_press(Message::ListReports)
+                    .into(),
+            );
+        }
+        buttons.extend([exit_bt.into(), clear_bt.into(), self.markdown_button()]);
+        let bt_row = row(buttons)
+            .padding(10)
+            .spacing(3)
+            .align_items(Alignment::Center);
+        let input_message = text_input("input here", input_message, Message::InputChange);
+
+        let msg_log_row = build_msg_and_log(message_queue, log_queue, &self.transfers);
+        let all_connected_users: String = all_users
+            .into_iter()
+            .map(|user| {
+                let badge = rooms
+                    .roles
+                    .get(&user.0)
+                    .map_or("", |role| role_badge(*role));
+                format!("{}{}-{}", badge, user.0, user.1)
+            })
+            .fold(String::new(), |mut f, s| {
+                f.push_str(&s);
+                f.push(' ');
+                f
+            });
+
+        info!("all users: {:?}", all_connected_users.len());
+
+        let col = column(vec![
+            status_text.into(),
+            text("all users:").into(),
+            text(all_connected_users).into(),
+            bt_row.into(),
+            self.staged_view(),
+            input_message.into(),
+            rooms_view(rooms),
+            reports_view(&rooms.reports),
+            msg_log_row,
+        ])
+        .align_items(Alignment::Center)
+        .padding(10)
+        .width(Length::Fill)
+        .height(Length::Fill);
+        col.into()
+    }
+}
+
+/// hand the queued messages to the connection one after another, stopping at the first failure
+fn flush_outbox(
+    mut connection: Connection,
+    messages: Vec<(u64, WebSocketClientToServerMessage)>,
+) -> iced::Command<Message> {
+    iced::Command::perform(
+        async move {
+            let count = messages.len();
+            for (nonce, message) in messages {
+                connection
+                    .send(message)
+                    .await
+                    .map_err(|_| (nonce, "cannot send to sub"))?;
+            }
+            Ok(count)
+        },
+        |result: Result<_, (u64, &str)>| match result {
+            Ok(count) => Message::Flushed(count),
+            Err((nonce, e)) => Message::SendFailed(nonce, e.to_string()),
+        },
+    )
+}
+
+/// hand the message to the connection, reporting the result under the message nonce
+fn send_to_connection(
+    mut connection: Connection,
+    message: WebSocketClientToServerMessage,
+    nonce: u64,
+) -> iced::Command<Message> {
+    iced::Command::perform(
+        async move {
+            connection
+                .send(message)
+                .await
+                .map_err(|_| "cannot send to sub")?;
+            Ok(())
+        },
+        move |result: Result<_, &str>| match result {
+            Ok(()) => Message::Sent(nonce),
+            Err(e) => Message::SendFailed(nonce, e.to_string()),
+        },
+    )
+}
+
+fn build_msg_and_log(
+    message_queue: &VecDeque<ChatEntry>,
+    log_queue: &VecDeque<String>,
+    transfers: &Transfers,
+) -> Element<'static, Message> {
+    let chat_messages = message_queue
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let data = &entry.data;
+            let name = match &data.recipient {
+                Some(_) => format!("{} (private)", data.name),
+                None => data.name.clone(),
+            };
+            let color = if entry.own {
+                Color::from_rgb8(204, 51, 0)
+            } else {
+                Color::from_rgb8(0, 51, 102)
+            };
+            let msg_body = match (&entry.blocks, &data.attachment) {
+                _ if data.kind == MessageKind::System => text(&data.data)
+                    .size(16)
+                    .style(Color::from_rgb8(153, 153, 153))
+                    .into(),
+                // there is no italic font, actions are drawn lighter like italic markdown
+                _ if data.kind == MessageKind::Action => text(format!("* {} {}", name, data.data))
+                    .size(20)
+                    .style(Color { a: 0.7, ..color })
+                    .into(),
+                (_, Some(attachment)) => attachment_view(&name, attachment, color, transfers),
+                (None, None) => text(format!("{}: {}", name, data.data))
+                    .size(20)
+                    .style(color)
+                    .into(),
+                (Some(blocks), None) => {
+                    let mut code = CodeContext {
+                        entry: index,
+                        code_blocks: &entry.code_blocks,
+                        expanded: &entry.expanded,
+                        next: 0,
+                    };
+                    row(vec![
+                        text(format!("{name}:")).size(20).style(color).into(),
+                        markdown_view(blocks, color, &mut code),
+                    ])
+                    .spacing(5)
+                    .into()
+                }
+            };
+            let copy_bt = button("copy").on_press(Message::Copy(data.data.clone()));
+            let mut items = vec![msg_body, copy_bt.into()];
+            if !entry.own && data.kind != MessageKind::System && data.message_id != 0 {
+                items.push(
+                    button("report")
+                        .on_press(Message::Report(data.message_id))
+                        .into(),
+                );
+            }
+            if entry.own {
+                match &entry.state {
+                    DeliveryState::Queued => items.push(
+                        text("queued")
+                            .size(14)
+                            .style(Color::from_rgb8(153, 153, 153))
+                            .into(),
+                    ),
+                    DeliveryState::Pending => items.push(
+                        text("sending...")
+                            .size(14)
+                            .style(Color::from_rgb8(153, 153, 153))
+                            .into(),
+                    ),
+                    DeliveryState::Sent => items.push(
+                        text("sent")
+                            .size(14)
+                            .style(Color::from_rgb8(0, 153, 51))
+                            .into(),
+                    ),
+                    DeliveryState::Failed(reason) => {
+                        items.push(
+                            text(format!("failed: {reason}"))
+                                .size(14)
+                                .style(Color::from_rgb8(204, 0, 0))
+                                .into(),
+                        );
+                        items.push(button("retry").on_press(Message::Retry(data.nonce)).into());
+                    }
+                }
+            }
+            row(items)
+                .spacing(5)
+                .align_items(Alignment::Center)
+                .padding(5)
+                .into()
+        })
+        .collect();
+    let logs = log_queue
+        .iter()
+        .map(|msg| {
+            text(msg)
+                .size(20)
+                .style(Color::from_rgb8(0, 51, 102))
+                .into()
+        })
+        .collect();
+    let msg_col = scrollable(
+        column(chat_messages)
+            .spacing(15)
+            .align_items(Alignment::Start)
+            .width(Length::FillPortion(8))
+            .padding(15),
+    )
+    .height(Length::Fill);
+    let log_col = scrollable(
+        column(logs)
+            .spacing(15)
+            .align_items(Alignment::End)
+            .width(Length::FillPortion(2))
+            .padding(15),
+    )
+    .height(Length::Fill);
+    let msg_log_row = row(vec![msg_col.into(), log_col.into()])
+        .spacing(15)
+        .align_items(Alignment::Start)
+        .width(Length::Fill);
+    msg_log_row.into()
+}
+
+fn spans_view(spans: &[Span], size: u16, color: Color) -> Element<'static, Message> {
+    let items = spans
+        .iter()
+        .map(|span| {
+            let style = &span.style;
+            let mut span_text = text(&span.text).size(if style.bold { size + 2 } else { size });
+            span_text = if style.strike {
+                span_text.style(Color::from_rgb8(153, 153, 153))
+            } else if style.italic {
+                span_text.style(Color { a: 0.7, ..color })
+            } else {
+                span_text.style(color)
+            };
+            if style.code {
+                span_text = span_text.font(MONOSPACE);
+            }
+            match &style.link {
+                Some(link) => button(span_text)
+                    .padding(0)
+                    .style(theme::Button::Text)
+                    .on_press(Message::OpenLink(link.clone()))
+                    .into(),
+                None if style.code => container(span_text)
+                    .padding([0, 3])
+                    .style(theme::Container::Box)
+                    .into(),
+                None => span_text.into(),
+            }
+        })
+        .collect();
+    row(items).align_items(Alignment::Center).into()
+}
+
+/// the room header with the pending invitations and the listed rooms
+/// the moderation queue, the input is used as the reason when reporting a message
+fn reports_view(reports: &[MessageReport]) -> Element<'static, Message> {
+    let items = reports
+        .iter()
+        .map(|report| {
+            let mut lines = vec![text(format!(
+                "#{}: {} reported {}: {}",
+                report.room, report.reporter, report.message.name, report.reason
+            ))
+            .size(18)
+            .into()];
+            for message in &report.context {
+                let color = if message.message_id == report.message.message_id {
+                    Color::from_rgb8(204, 0, 0)
+                } else {
+                    Color::from_rgb8(153, 153, 153)
+                };
+                lines.push(
+                    text(format!("{}: {}", message.name, message.plain_text()))
+                        .size(16)
+                        .style(color)
+                        .into(),
+                );
+            }
+            lines.push(
+                row(vec![
+                    button("delete")
+                        .padding(3)
+                        .on_press(Message::ResolveReport(report.id, ReportAction::Delete))
+                        .into(),
+                    button("mute 10m")
+                        .padding(3)
+                        .on_press(Message::ResolveReport(
+                            report.id,
+                            ReportAction::Mute(10 * 60),
+                        ))
+                        .into(),
+                    button("dismiss")
+                        .padding(3)
+                        .on_press(Message::ResolveReport(report.id, ReportAction::Dismiss))
+                        .into(),
+                ])
+                .spacing(5)
+                .into(),
+            );
+            column(lines).spacing(3).into()
+        })
+        .collect();
+    column(items).spacing(10).padding(5).into()
+}
+
+/// the prefix of a user in the user list showing their role in the current room
+fn role_badge(role: Role) -> &'static str {
+    match role {
+        Role::Owner => "~",
+        Role::Moderator => "@",
+        Role::Member => "",
+        Role::Guest => "?",
+    }
+}
+
+fn rooms_view(rooms: &RoomState) -> Element<'static, Message> {
+    let mut items = Vec::new();
+    for (room, from) in &rooms.invitations {
+        items.push(
+            row(vec![
+                text(format!("{from} invited you to #{room}"))
+                    .size(18)
+                    .into(),
+                button("accept")
+                    .padding(5)
+                    .on_press(Message::AnswerInvite(room.clone(), true))
+                    .into(),
+                button("decline")
+                    .padding(5)
+                    .on_press(Message::AnswerInvite(room.clone(), false))
+                    .into(),
+            ])
+            .spacing(5)
+            .align_items(Alignment::Center)
+            .into(),
+        );
+    }
+    if !rooms.listed.is_empty() {
+        let listed = rooms
+            .listed
+            .iter()
+            .map(|room| {
+                let label = match room.visibility {
+                    RoomVisibility::Public => format!("#{}", room.name),
+                    RoomVisibility::Private => format!("#{} (private)", room.name),
+                    RoomVisibility::Password => format!("#{} (password)", room.name),
+                };
+                button(text(label).size(16))
+                    .padding(3)
+                    .on_press(Message::JoinRoom(room.name.clone()))
+                    .into()
+            })
+            .collect();
+        items.push(row(listed).spacing(3).into());
+    }
+    items.push(room_header(&rooms.current));
+    column(items).spacing(5).into()
+}
+
+/// the name, topic and description of the room above the messages
+fn room_header(room: &RoomInfo) -> Element<'static, Message> {
+    let grey = Color::from_rgb8(102, 102, 102);
+    let mut title = vec![text(format!("#{}", room.name)).size(24).into()];
+    if !room.topic.is_empty() {
+        title.push(text(&room.topic).size(20).into());
+    }
+    let mut lines = vec![row(title).spacing(10).align_items(Alignment::Center).into()];
+    if !room.description.is_empty() {
+        lines.push(text(&room.description).size(16).style(grey).into());
+    }
+    if !room.created_by.is_empty() {
+        lines.push(
+            text(format!("created by {}", room.created_by))
+                .size(14)
+                .style(grey)
+                .into(),
+        );
+    }
+    container(column(lines).spacing(3))
+        .padding(8)
+        .width(Length::Fill)
+        .style(theme::Container::Box)
+        .into()
+}
+
+/// the file name and size with a button to download the attachment or to open it once saved
+fn attachment_view(
+    sender: &str,
+    attachment: &Attachment,
+    color: Color,
+    transfers: &Transfers,
+) -> Element<'static, Message> {
+    let action: Element<'static, Message> = match (
+        transfers.saved(&attachment.sha256),
+        transfers.progress(&attachment.sha256),
+    ) {
+        (Some(path), _) => button("open")
+            .on_press(Message::OpenFile(path.clone()))
+            .into(),
+        (None, Some(progress)) => text(format!("downloading {progress}%"))
+            .size(14)
+            .style(Color::from_rgb8(153, 153, 153))
+            .into(),
+        (None, None) => button("download")
+            .on_press(Message::Download(attachment.clone()))
+            .into(),
+    };
+    let header = row(vec![
+        text(format!(
+            "{}: [file] {} ({})",
+            sender,
+            attachment.file_name,
+            transfers::format_size(attachment.size)
+        ))
+        .size(20)
+        .style(color)
+        .into(),
+        action,
+    ])
+    .spacing(5)
+    .align_items(Alignment::Center);
+    match image_preview(attachment, transfers) {
+        Some(preview) => column(vec![header.into(), preview]).spacing(5).into(),
+        None => header.into(),
+    }
+}
+
+/// the thumbnail of an image attachment, or the full image once expanded,
+/// clicking it switches between the two
+fn image_preview(
+    attachment: &Attachment,
+    transfers: &Transfers,
+) -> Option<Element<'static, Message>> {
+    let full = transfers.image(&attachment.sha256);
+    let (handle, width) = match (transfers.is_expanded(&attachment.sha256), full) {
+        (true, Some(full)) => (full, Length::Shrink),
+        _ => match &attachment.thumbnail {
+            Some(thumbnail) => match transfers.image(&thumbnail.sha256) {
+                Some(handle) => (handle, Length::Fixed(thumbnail.width as f32)),
+                None => (full?, Length::Fixed(THUMBNAIL_WIDTH)),
+            },
+            // own uploads are shown from the local copy
+            None => (full?, Length::Fixed(THUMBNAIL_WIDTH)),
+        },
+    };
+    Some(
+        button(image(handle.clone()).width(width))
+            .padding(0)
+            .style(theme::Button::Text)
+            .on_press(Message::ToggleImage(attachment.clone()))
+            .into(),
+    )
+}
+
+/// highlight the code blocks in document order, the order [`markdown_view`] visits them
+fn highlight_code_blocks(blocks: &[Block], code_blocks: &mut Vec<Vec<Vec<HighlightedSpan>>>) {
+    for block in blocks {
+        match block {
+            Block::Code { lang, code } => {
+                code_blocks.push(highlight::highlight(code, lang.as_deref()))
+            }
+            Block::Quote(blocks) => highlight_code_blocks(blocks, code_blocks),
+            Block::List { items, .. } => {
+                for item in items {
+                    highlight_code_blocks(item, code_blocks);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// the code blocks of the entry being rendered
+struct CodeContext<'a> {
+    /// the index of the entry in the message queue
+    entry: usize,
+    code_blocks: &'a [Vec<Vec<HighlightedSpan>>],
+    expanded: &'a HashSet<usize>,
+    /// the index of the next code block to render
+    next: usize,
+}
+
+fn code_block_view(
+    lang: Option<&str>,
+    code: &str,
+    context: &mut CodeContext,
+) -> Element<'static, Message> {
+    let index = context.next;
+    context.next += 1;
+    let lines = &context.code_blocks[index];
+    let collapsible = lines.len() > COLLAPSED_LINES;
+    let expanded = context.expanded.contains(&index);
+
+    let mut header = vec![
+        text(lang.unwrap_or("code"))
+            .size(14)
+            .style(Color::from_rgb8(153, 153, 153))
+            .into(),
+        button(text("copy").size(14))
+            .padding([0, 5])
+            .on_press(Message::Copy(code.to_string()))
+            .into(),
+    ];
+    if collapsible {
+        let label = if expanded {
+            "collapse".to_string()
+        } else {
+            format!("expand {} lines", lines.len())
+        };
+        header.push(
+            button(text(label).size(14))
+                .padding([0, 5])
+                .on_press(Message::ToggleCode(context.entry, index))
+                .into(),
+        );
+    }
+    let visible = if collapsible && !expanded {
+        COLLAPSED_LINES
+    } else {
+        lines.len()
+    };
+    let mut rows = vec![row(header).spacing(5).align_items(Alignment::Center).into()];
+    rows.extend(lines.iter().take(visible).map(|line| {
+        row(line
+            .iter()
+            .map(|span| {
+                let (r, g, b) = span.color;
+                text(&span.text)
+                    .size(16)
+                    .font(MONOSPACE)
+                    .style(Color::from_rgb8(r, g, b))
+                    .into()
+            })
+            .collect())
+        .into()
+    }));
+    if collapsible && !expanded {
+        rows.push(
+            text("...")
+                .size(16)
+                .font(MONOSPACE)
+                .style(Color::from_rgb8(153, 153, 153))
+                .into(),
+        );
+    }
+    container(column(rows))
+        .padding(5)
+        .style(theme::Container::Box)
+        .into()
+}
+
+/// render parsed markdown, the text is drawn in `color`
+fn markdown_view(
+    blocks: &[Block],
+    color: Color,
+    code: &mut CodeContext,
+) -> Element<'static, Message> {
+    let items = blocks
+        .iter()
+        .map(|block| match block {
+            Block::Paragraph(spans) => spans_view(spans, 20, color),
+            Block::Heading(level, spans) => spans_view(spans, 32 - 2 * *level as u16, color),
+            Block::Quote(blocks) => row(vec![
+                text("|")
+                    .size(20)
+                    .style(Color::from_rgb8(153, 153, 153))
+                    .into(),
+                markdown_view(blocks, Color { a: 0.8, ..color }, code),
+            ])
+            .spacing(5)
+            .into(),
+            Block::List { start, items } => column(
+                items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| {
+                        let marker = match start {
+                            Some(start) => format!("{}.", start + index as u64),
+                            None => "-".to_string(),
+                        };
+                        row(vec![
+                            text(marker).size(20).style(color).into(),
+                            markdown_view(item, color, code),
+                        ])
+                        .spacing(5)
+                        .into()
+                    })
+                    .collect(),
+            )
+            .spacing(2)
+            .into(),
+            Block::Code { lang, code: source } => code_block_view(lang.as_deref(), source, code),
+            Block::Rule => horizontal_rule(1).into(),
+        })
+        .collect();
+    column(items).spacing(5).into()
+}
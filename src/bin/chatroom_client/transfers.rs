@@ -0,0 +1,357 @@
+//! Uploads and downloads of attachments.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use iced::widget::image;
+use websocket_chatroom::{transfer, Attachment, Connection, WebSocketClientToServerMessage};
+
+use crate::Message;
+
+/// what happens to a finished download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    /// save it in the download directory
+    Save,
+    /// keep the image in memory to show it in the chat
+    Show,
+}
+
+/// a file waiting for the user to confirm the upload
+pub struct Staged {
+    pub file_name: String,
+    pub content: Arc<Vec<u8>>,
+    /// set for images
+    pub preview: Option<image::Handle>,
+}
+
+impl Staged {
+    pub fn new(file_name: String, content: Arc<Vec<u8>>) -> Self {
+        let preview = transfer::guess_mime(&file_name)
+            .starts_with("image/")
+            .then(|| image::Handle::from_memory(content.to_vec()));
+        Self {
+            file_name,
+            content,
+            preview,
+        }
+    }
+}
+
+struct Download {
+    sha256: String,
+    file_name: String,
+    purpose: Purpose,
+    /// set once the server started the download
+    size: Option<u64>,
+    content: Vec<u8>,
+}
+
+pub struct Transfers {
+    /// where downloads are saved
+    dir: PathBuf,
+    /// downloads in progress by transfer id
+    downloads: HashMap<u64, Download>,
+    /// saved files by sha-256
+    saved: HashMap<String, PathBuf>,
+    /// the content of own uploads by upload id, kept until the server acks them
+    uploads: HashMap<u64, Arc<Vec<u8>>>,
+    /// images and thumbnails shown in the chat by sha-256
+    images: HashMap<String, image::Handle>,
+    /// image attachments shown in full size instead of the thumbnail
+    expanded: HashSet<String>,
+}
+
+impl Transfers {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            downloads: HashMap::new(),
+            saved: HashMap::new(),
+            uploads: HashMap::new(),
+            images: HashMap::new(),
+            expanded: HashSet::new(),
+        }
+    }
+
+    pub fn start_download(
+        &mut self,
+        transfer_id: u64,
+        sha256: &str,
+        file_name: &str,
+        purpose: Purpose,
+    ) {
+        self.downloads.insert(
+            transfer_id,
+            Download {
+                sha256: sha256.to_string(),
+                file_name: file_name.to_string(),
+                purpose,
+                size: None,
+                content: Vec::new(),
+            },
+        );
+    }
+
+    pub fn download_started(&mut self, transfer_id: u64, size: u64) {
+        if let Some(download) = self.downloads.get_mut(&transfer_id) {
+            download.size = Some(size);
+            download.content.reserve(size as usize);
+        }
+    }
+
+    pub fn chunk(&mut self, transfer_id: u64, data: &[u8]) {
+        if let Some(download) = self.downloads.get_mut(&transfer_id) {
+            download.content.extend_from_slice(data);
+        }
+    }
+
+    /// verify the content of the finished download and save or show it,
+    /// returning where it is saved
+    pub fn download_finished(
+        &mut self,
+        transfer_id: u64,
+    ) -> Option<Result<Option<PathBuf>, String>> {
+        let download = self.downloads.remove(&transfer_id)?;
+        if transfer::sha256_hex(&download.content) != download.sha256 {
+            return Some(Err(format!("{} is corrupted", download.file_name)));
+        }
+        if download.purpose == Purpose::Show {
+            self.show_image(&download.sha256, download.content);
+            return Some(Ok(None));
+        }
+        let result = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| {
+                let path = unique_path(&self.dir, &download.file_name);
+                std::fs::write(&path, &download.content).map(|_| path)
+            })
+            .map_err(|e| format!("cannot save {}: {}", download.file_name, e));
+        if let Ok(path) = &result {
+            self.saved.insert(download.sha256, path.clone());
+        }
+        Some(result.map(Some))
+    }
+
+    pub fn download_failed(&mut self, transfer_id: u64) {
+        self.downloads.remove(&transfer_id);
+    }
+
+    /// the progress in percent if the attachment is being downloaded
+    pub fn progress(&self, sha256: &str) -> Option<u64> {
+        self.downloads
+            .values()
+            .find(|download| download.sha256 == sha256)
+            .map(|download| match download.size {
+                Some(size) if size > 0 => download.content.len() as u64 * 100 / size,
+                _ => 0,
+            })
+    }
+
+    pub fn saved(&self, sha256: &str) -> Option<&PathBuf> {
+        self.saved.get(sha256)
+    }
+
+    pub fn show_image(&mut self, sha256: &str, content: Vec<u8>) {
+        self.images
+            .insert(sha256.to_string(), image::Handle::from_memory(content));
+    }
+
+    pub fn image(&self, sha256: &str) -> Option<&image::Handle> {
+        self.images.get(sha256)
+    }
+
+    /// switch between the thumbnail and the full image, returns whether it is expanded now
+    pub fn toggle_expanded(&mut self, sha256: &str) -> bool {
+        if self.expanded.remove(sha256) {
+            false
+        } else {
+            self.expanded.insert(sha256.to_string());
+            true
+        }
+    }
+
+    pub fn is_expanded(&self, sha256: &str) -> bool {
+        self.expanded.contains(sha256)
+    }
+
+    pub fn keep_upload(&mut self, upload_id: u64, content: Arc<Vec<u8>>) {
+        self.uploads.insert(upload_id, content);
+    }
+
+    pub fn upload_content(&self, upload_id: u64) -> Option<Arc<Vec<u8>>> {
+        self.uploads.get(&upload_id).cloned()
+    }
+
+    /// the server acked or rejected the upload
+    pub fn upload_done(&mut self, upload_id: u64) {
+        self.uploads.remove(&upload_id);
+    }
+}
+
+/// the file name and content of the file at the path
+pub async fn read_file(path: PathBuf) -> Result<(String, Arc<Vec<u8>>), String> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    tokio::fs::read(&path)
+        .await
+        .map(|content| (file_name, Arc::new(content)))
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))
+}
+
+/// an image in the clipboard as png, or the file whose path is copied
+pub async fn read_clipboard() -> Result<(String, Arc<Vec<u8>>), String> {
+    enum Content {
+        Image(Vec<u8>),
+        Text(String),
+    }
+    let content = tokio::task::spawn_blocking(|| {
+        let mut clipboard = arboard::Clipboard::new().map_err(|e| e.to_string())?;
+        match clipboard.get_image() {
+            Ok(image) => image_to_png(image).map(Content::Image),
+            Err(_) => clipboard
+                .get_text()
+                .map(Content::Text)
+                .map_err(|_| "the clipboard holds no image or file".to_string()),
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    match content {
+        Content::Image(png) => Ok(("pasted.png".to_string(), Arc::new(png))),
+        Content::Text(text) => {
+            // file managers copy files as a list of paths or file urls
+            let line = text.lines().next().unwrap_or_default().trim();
+            let path = PathBuf::from(line.strip_prefix("file://").unwrap_or(line));
+            if !path.is_file() {
+                return Err("the clipboard holds no image or file".to_string());
+            }
+            read_file(path).await
+        }
+    }
+}
+
+fn image_to_png(image: arboard::ImageData) -> Result<Vec<u8>, String> {
+    let buffer = ::image::RgbaImage::from_raw(
+        image.width as u32,
+        image.height as u32,
+        image.bytes.into_owned(),
+    )
+    .ok_or("the clipboard image is malformed")?;
+    let mut png = Vec::new();
+    buffer
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            ::image::ImageOutputFormat::Png,
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(png)
+}
+
+/// a path in `dir` for the file name that does not overwrite an existing file
+fn unique_path(dir: &Path, file_name: &str) -> PathBuf {
+    let file_name = transfer::sanitize_file_name(file_name);
+    let path = dir.join(&file_name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) => (stem.to_string(), format!(".{extension}")),
+        None => (file_name.clone(), String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{stem} ({n}){extension}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// describe the content as an attachment
+pub fn attachment_for(file_name: &str, content: &[u8]) -> Attachment {
+    let file_name = transfer::sanitize_file_name(file_name);
+    Attachment {
+        sha256: transfer::sha256_hex(content),
+        mime: transfer::guess_mime(&file_name).to_string(),
+        file_name,
+        size: content.len() as u64,
+        thumbnail: None,
+    }
+}
+
+/// ask the server for the content of an attachment
+pub fn download(
+    mut connection: Connection,
+    transfer_id: u64,
+    sha256: String,
+) -> iced::Command<Message> {
+    iced::Command::perform(
+        async move {
+            connection
+                .send(WebSocketClientToServerMessage::Download {
+                    transfer_id,
+                    sha256,
+                })
+                .await
+                .is_ok()
+        },
+        move |sent| {
+            if sent {
+                Message::Sent(transfer_id)
+            } else {
+                Message::SendFailed(transfer_id, "cannot send to sub".to_string())
+            }
+        },
+    )
+}
+
+/// send the content in chunks, the server acks the upload id once it stored the file
+pub fn upload(
+    mut connection: Connection,
+    upload_id: u64,
+    attachment: Attachment,
+    content: Arc<Vec<u8>>,
+) -> iced::Command<Message> {
+    iced::Command::perform(
+        async move {
+            connection
+                .send(WebSocketClientToServerMessage::BeginUpload {
+                    upload_id,
+                    file_name: attachment.file_name,
+                    size: attachment.size,
+                    mime: attachment.mime,
+                })
+                .await
+                .map_err(|_| "cannot send to sub")?;
+            for chunk in content.chunks(transfer::CHUNK_SIZE) {
+                connection
+                    .send_chunk(upload_id, chunk)
+                    .await
+                    .map_err(|_| "cannot send to sub")?;
+            }
+            connection
+                .send(WebSocketClientToServerMessage::FinishUpload {
+                    upload_id,
+                    sha256: attachment.sha256,
+                })
+                .await
+                .map_err(|_| "cannot send to sub")?;
+            Ok(())
+        },
+        move |result: Result<_, &str>| match result {
+            Ok(()) => Message::Sent(upload_id),
+            Err(e) => Message::SendFailed(upload_id, e.to_string()),
+        },
+    )
+}
+
+/// a human readable size
+pub fn format_size(size: u64) -> String {
+    match size {
+        0..=1023 => format!("{size} B"),
+        1024..=1048575 => format!("{:.1} KiB", size as f64 / 1024.0),
+        _ => format!("{:.1} MiB", size as f64 / 1048576.0),
+    }
+}
@@ -0,0 +1,149 @@
+//! A chat client for shell pipelines and CI jobs.
+//!
+//!     tail -f build.log | cargo run --bin chatroom_pipe -- --name ci --room builds
+//!
+//! Every line read from stdin is sent to the room, lines starting with `/` are
+//! run as slash commands. With `--input json` every line is a
+//! [`WebSocketClientToServerMessage`] in the JSON of the protocol instead.
+//! Everything the server sends is written to stdout as one
+//! [`WebSocketServerToClientMessage`] in JSON per line, the end of the
+//! connection as `{"Closed":"<reason>"}` or `"Disconnected"`. The connection is
+//! closed once stdin ends.
+
+use std::{
+    io::{self, Write},
+    process::ExitCode,
+};
+
+use clap::{Parser, ValueEnum};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use websocket_chatroom::{
+    client::{ChatClient, ClientEvent},
+    command, WebSocketClientToServerMessage, WebSocketServerToClientMessage,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Input {
+    /// every line is a message or a slash command
+    Raw,
+    /// every line is a message of the protocol in JSON
+    Json,
+}
+
+#[derive(Parser)]
+struct Cli {
+    /// the server to connect to
+    #[clap(default_value = "ws://127.0.0.1:2233")]
+    url: String,
+    /// the name to chat under
+    #[clap(long)]
+    name: String,
+    /// the token of the name if it is registered, as sent in a `Token` message
+    #[clap(long, env = "CHATROOM_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// the room to join before the first line is sent
+    #[clap(long)]
+    room: Option<String>,
+    /// how the lines of stdin are read
+    #[clap(long, value_enum, default_value_t = Input::Raw)]
+    input: Input,
+}
+
+/// write the value as a line to stdout, false once stdout is closed
+fn output(value: &impl serde::Serialize) -> bool {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, value).unwrap();
+    writeln!(stdout).and_then(|_| stdout.flush()).is_ok()
+}
+
+/// send a line of stdin
+async fn send(client: &mut ChatClient, input: Input, line: &str) -> Result<(), String> {
+    match input {
+        Input::Raw => match command::parse(line) {
+            Some((name, args)) => client.command(name, args).await,
+            None => client.say(command::unescape(line)).await.map(|_| ()),
+        },
+        Input::Json => {
+            let message: WebSocketClientToServerMessage =
+                serde_json::from_str(line).map_err(|e| format!("not a message: {e}"))?;
+            client.send(message).await
+        }
+    }
+}
+
+async fn run(cli: Cli) -> ExitCode {
+    let connected = match &cli.token {
+        Some(token) => ChatClient::login(&cli.url, &cli.name, token).await,
+        None => ChatClient::connect(&cli.url, &cli.name).await,
+    };
+    let mut client = match connected {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // the handshake is read by the client, the consumers see it all the same
+    output(&WebSocketServerToClientMessage::Connected(
+        client.id(),
+        client.name().to_string(),
+    ));
+    output(&WebSocketServerToClientMessage::AllUsers(
+        client.users().to_vec(),
+    ));
+    if let Some(room) = &cli.room {
+        if let Err(e) = client.command("join", room).await {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) => {
+                    if let Err(e) = send(&mut client, cli.input, &line).await {
+                        eprintln!("{e}");
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("cannot read stdin: {e}");
+                    break;
+                }
+            },
+            event = client.next_event() => match event {
+                Some(ClientEvent::Message(message)) => {
+                    if !output(&message) {
+                        break;
+                    }
+                }
+                Some(ClientEvent::Chunk(..)) => {}
+                Some(ClientEvent::Closed(reason)) => {
+                    output(&serde_json::json!({ "Closed": reason }));
+                    return ExitCode::FAILURE;
+                }
+                Some(ClientEvent::Disconnected) | None => {
+                    output(&"Disconnected");
+                    return ExitCode::FAILURE;
+                }
+            },
+        }
+    }
+    client.close().await;
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let code = runtime.block_on(run(cli));
+    // a read of stdin cannot be cancelled, do not wait for it
+    runtime.shutdown_background();
+    code
+}
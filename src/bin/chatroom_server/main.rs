@@ -0,0 +1,99 @@
+//! A chat server that broadcasts a message to all connections.
+//!
+//! You can test this out by running:
+//!
+//!     cargo run --bin chatroom_server 127.0.0.1:2233
+//!
+//! And then in another window run:
+//!
+//!     cargo run --bin chatroom_client
+//!
+//! You can run the second command in multiple windows and then chat between the
+//! two, seeing the messages from the other client as they're received. The
+//! server itself lives in [`websocket_chatroom::server`].
+
+use std::{io::Error as IoError, path::PathBuf};
+
+use clap::Parser;
+use websocket_chatroom::{server::ChatServer, Role};
+
+#[derive(Parser)]
+struct Cli {
+    /// the address to listen on
+    #[clap(default_value = "127.0.0.1:2233")]
+    addr: String,
+    /// where the attachments are stored
+    #[clap(long, default_value = "chatroom_data")]
+    data_dir: PathBuf,
+    /// the largest file a user may upload, in bytes
+    #[clap(long, default_value_t = 20 * 1024 * 1024)]
+    max_upload_size: u64,
+    /// the total size of the files a single user may upload, in bytes
+    #[clap(long, default_value_t = 200 * 1024 * 1024)]
+    upload_quota: u64,
+    /// the rules of the word filter, read again when the file changes,
+    /// `filter.json` in the data directory by default
+    #[clap(long)]
+    filter: Option<PathBuf>,
+    /// the directory of the `*.rhai` scripts, read again when a script changes,
+    /// `scripts` in the data directory by default
+    #[clap(long)]
+    scripts: Option<PathBuf>,
+    /// the keys of the REST API, read again when the file changes,
+    /// `api_keys.json` in the data directory by default
+    #[clap(long)]
+    api_keys: Option<PathBuf>,
+    /// a user that owns every room, may be given more than once
+    #[clap(long = "owner")]
+    owners: Vec<String>,
+    /// a user that moderates every room, may be given more than once
+    #[clap(long = "moderator")]
+    moderators: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), IoError> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                tracing_subscriber::EnvFilter::new(
+                    "chatroom_client=info,websocket_chatroom=info,chatroom_server=info",
+                )
+            }),
+        )
+        .with_ansi(true)
+        .try_init()
+        .unwrap_or_else(|e| {
+            eprintln!("failed to init logger: {}", e);
+        });
+    let cli = Cli::parse();
+
+    let mut builder = ChatServer::builder()
+        .data_dir(cli.data_dir)
+        .max_upload_size(cli.max_upload_size)
+        .upload_quota(cli.upload_quota);
+    if let Some(filter) = cli.filter {
+        builder = builder.filter_rules(filter);
+    }
+    if let Some(scripts) = cli.scripts {
+        builder = builder.scripts(scripts);
+    }
+    if let Some(api_keys) = cli.api_keys {
+        builder = builder.api_keys(api_keys);
+    }
+    // an owner given as a moderator as well stays an owner
+    for moderator in &cli.moderators {
+        builder = builder.role(moderator, Role::Moderator);
+    }
+    for owner in &cli.owners {
+        builder = builder.role(owner, Role::Owner);
+    }
+    let server = builder.bind(&cli.addr).await?;
+    println!("Listening on: {}", server.local_addr()?);
+    // only shown once, the accounts keep the hashes of the tokens
+    for (name, token) in server.tokens() {
+        println!("{name} is registered, log in with the token {token}");
+    }
+
+    server.run().await
+}
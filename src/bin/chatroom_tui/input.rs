@@ -0,0 +1,142 @@
+//! The input line with a history of the lines sent before.
+
+/// how many sent lines are remembered
+const HISTORY_SIZE: usize = 100;
+
+#[derive(Default)]
+pub struct InputLine {
+    text: Vec<char>,
+    /// the character the cursor is in front of
+    cursor: usize,
+    /// oldest first
+    history: Vec<String>,
+    /// the entry of the history shown, `None` while editing a new line
+    browsing: Option<usize>,
+    /// the new line while the history is browsed
+    draft: String,
+}
+
+impl InputLine {
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    /// the cursor, in characters from the start
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    /// show the line sent before the one shown
+    pub fn previous(&mut self) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.text();
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.set(&self.history[index].clone());
+    }
+
+    /// show the line sent after the one shown, or the new line again
+    pub fn next(&mut self) {
+        let Some(index) = self.browsing else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.browsing = Some(index + 1);
+            self.set(&self.history[index + 1].clone());
+        } else {
+            self.browsing = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set(&draft);
+        }
+    }
+
+    /// take the line to send it, remembering it in the history
+    pub fn submit(&mut self) -> String {
+        let line = self.text();
+        self.set("");
+        self.browsing = None;
+        self.draft.clear();
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.remove(0);
+            }
+            self.history.push(line.clone());
+        }
+        line
+    }
+
+    fn set(&mut self, text: &str) {
+        self.text = text.chars().collect();
+        self.cursor = self.text.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let mut input = InputLine::default();
+        for c in "hello".chars() {
+            input.insert(c);
+        }
+        assert_eq!(input.submit(), "hello");
+        "/who".chars().for_each(|c| input.insert(c));
+        input.submit();
+        "dra".chars().for_each(|c| input.insert(c));
+        input.left();
+        input.insert('f');
+        assert_eq!(input.text(), "drfa");
+
+        input.previous();
+        assert_eq!(input.text(), "/who");
+        input.previous();
+        input.previous();
+        assert_eq!(input.text(), "hello");
+        input.next();
+        input.next();
+        assert_eq!(input.text(), "drfa");
+        assert_eq!(input.cursor(), 4);
+    }
+}
@@ -0,0 +1,636 @@
+//! A chat client for the terminal, e.g. in an SSH session.
+//!
+//!     cargo run --bin chatroom_tui -- --name alice ws://127.0.0.1:2233
+//!
+//! The messages of the room are shown next to the users online, lines typed
+//! into the input are sent to the room or run as slash commands like in the
+//! graphical client. Up and Down browse the lines sent before, PageUp and
+//! PageDown scroll, `/quit`, Esc or Ctrl-C leave. A lost connection is made
+//! again every second, like [`websocket_chatroom::connect`] does. Messages of
+//! self are marked as sending until the server acknowledges them, and as not
+//! sent if it refuses them or the connection is lost; the last
+//! [`MAX_LINES`] lines are kept.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Stdout},
+    time::Duration,
+};
+
+use clap::Parser;
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures_util::StreamExt;
+use input::InputLine;
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
+    Frame, Terminal,
+};
+use tokio::task::JoinHandle;
+use websocket_chatroom::{
+    client::{ChatClient, ClientEvent, ConnectError},
+    command::{self, LOBBY},
+    tokens::Tokens,
+    MessageData, MessageKind, WebSocketServerToClientMessage,
+};
+
+mod input;
+
+#[derive(Parser)]
+struct Cli {
+    /// the server to connect to
+    #[clap(default_value = "ws://127.0.0.1:2233")]
+    url: String,
+    /// the name to chat under
+    #[clap(long)]
+    name: String,
+}
+
+/// how far PageUp and PageDown scroll, in rows
+const PAGE: usize = 10;
+/// how many lines are kept, the oldest are dropped
+const MAX_LINES: usize = 1000;
+
+/// the colours the names of other users are shown in
+const NAME_COLORS: [Color; 6] = [
+    Color::Yellow,
+    Color::Green,
+    Color::Blue,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::LightYellow,
+];
+
+/// how far a message of self got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    /// relayed by the server, or sent by someone else
+    Delivered,
+    /// waiting for the `Ack` of the nonce
+    Pending(u64),
+    /// refused by the server or lost with the connection
+    Failed,
+}
+
+enum Line {
+    Message {
+        /// 0 until the message of self is acknowledged
+        message_id: u64,
+        name: String,
+        text: String,
+        /// sent by self
+        own: bool,
+        delivery: Delivery,
+        private: bool,
+        /// the user id the colour of the name is picked by
+        color: u32,
+    },
+    System(String),
+    Error(String),
+}
+
+impl Line {
+    fn spans(&self) -> Spans<'static> {
+        match self {
+            Line::Message {
+                name,
+                text,
+                own,
+                delivery,
+                private,
+                color,
+                ..
+            } => {
+                let name_style = if *own {
+                    Style::default().fg(Color::Cyan)
+                } else {
+                    Style::default().fg(NAME_COLORS[*color as usize % NAME_COLORS.len()])
+                };
+                let text_style = match (*private, *own, delivery) {
+                    (_, _, Delivery::Pending(_)) => Style::default().fg(Color::DarkGray),
+                    (_, _, Delivery::Failed) => Style::default().fg(Color::Red),
+                    (true, _, _) => Style::default().fg(Color::Magenta),
+                    (false, true, _) => Style::default().fg(Color::Cyan),
+                    (false, false, _) => Style::default(),
+                };
+                let prefix = if *private { "(private) " } else { "" };
+                let mut spans = vec![
+                    Span::styled(
+                        format!("{prefix}{name}: "),
+                        name_style.add_modifier(Modifier::BOLD),
+                    ),
+                    Span::styled(text.clone(), text_style),
+                ];
+                let note = match delivery {
+                    Delivery::Delivered => None,
+                    Delivery::Pending(_) => Some(" (sending)"),
+                    Delivery::Failed => Some(" (not sent)"),
+                };
+                if let Some(note) = note {
+                    spans.push(Span::styled(
+                        note,
+                        text_style.add_modifier(Modifier::ITALIC),
+                    ));
+                }
+                Spans::from(spans)
+            }
+            Line::System(text) => Spans::from(Span::styled(
+                text.clone(),
+                Style::default()
+                    .fg(Color::DarkGray)
+                    .add_modifier(Modifier::ITALIC),
+            )),
+            Line::Error(text) => {
+                Spans::from(Span::styled(text.clone(), Style::default().fg(Color::Red)))
+            }
+        }
+    }
+}
+
+/// the connection to the server
+enum Status {
+    /// a connection is being made, after a delay if the last attempt failed
+    Connecting(JoinHandle<Result<ChatClient, ConnectError>>),
+    Connected(ChatClient),
+    /// the server closed the connection or refused it, it is not made again
+    Closed(String),
+}
+
+struct App {
+    url: String,
+    name: String,
+    /// the id of self while connected
+    me: Option<u32>,
+    room: String,
+    topic: String,
+    users: BTreeMap<u32, String>,
+    lines: VecDeque<Line>,
+    input: InputLine,
+    /// how many rows the messages are scrolled up from the bottom
+    scroll: usize,
+    status: Status,
+}
+
+impl App {
+    fn new(url: String, name: String) -> Self {
+        let status = Status::Connecting(connect(&url, &name, false));
+        Self {
+            url,
+            name,
+            me: None,
+            room: LOBBY.to_string(),
+            topic: String::new(),
+            users: BTreeMap::new(),
+            lines: VecDeque::new(),
+            input: InputLine::default(),
+            scroll: 0,
+            status,
+        }
+    }
+
+    /// handle a key, false to quit
+    async fn on_key(&mut self, key: KeyEvent) -> bool {
+        if key.kind == KeyEventKind::Release {
+            return true;
+        }
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char(c) => self.input.insert(c),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_add(PAGE),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+            KeyCode::Enter => return self.submit().await,
+            _ => {}
+        }
+        true
+    }
+
+    /// send the input line, false to quit
+    async fn submit(&mut self) -> bool {
+        let line = self.input.submit();
+        if line.trim().is_empty() {
+            return true;
+        }
+        self.scroll = 0;
+        let command = command::parse(&line);
+        if matches!(command, Some(("quit", _))) {
+            return false;
+        }
+        let Status::Connected(client) = &mut self.status else {
+            self.push(Line::Error("not connected, try again later".to_string()));
+            return true;
+        };
+        let result = match command {
+            Some((name, args)) => client.command(name, args).await,
+            None => {
+                let text = command::unescape(&line);
+                let result = client.say(text).await;
+                // shown as pending until the server acknowledges it
+                let line = Line::Message {
+                    message_id: 0,
+                    name: self.users.get(&client.id()).unwrap_or(&self.name).clone(),
+                    text: text.to_string(),
+                    own: true,
+                    delivery: match result {
+                        Ok(nonce) => Delivery::Pending(nonce),
+                        Err(_) => Delivery::Failed,
+                    },
+                    private: false,
+                    color: client.id(),
+                };
+                self.push(line);
+                result.map(|_| ())
+            }
+        };
+        if let Err(e) = result {
+            self.push(Line::Error(e));
+        }
+        true
+    }
+
+    /// add a line below the others, dropping the oldest beyond `MAX_LINES`
+    fn push(&mut self, line: Line) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// the pending message of self with the nonce
+    fn pending(&mut self, nonce: u64) -> Option<(&mut u64, &mut Delivery)> {
+        self.lines.iter_mut().rev().find_map(|line| match line {
+            Line::Message {
+                message_id,
+                delivery,
+                ..
+            } if *delivery == Delivery::Pending(nonce) => Some((message_id, delivery)),
+            _ => None,
+        })
+    }
+
+    /// the messages waiting for an ack are lost with the connection
+    fn fail_pending(&mut self) {
+        for line in &mut self.lines {
+            if let Line::Message {
+                delivery: delivery @ Delivery::Pending(_),
+                ..
+            } = line
+            {
+                *delivery = Delivery::Failed;
+            }
+        }
+    }
+
+    fn on_connect(&mut self, result: Result<ChatClient, ConnectError>) {
+        match result {
+            Ok(client) => {
+                self.me = Some(client.id());
+                self.users = client.users().iter().cloned().collect();
+                self.push(Line::System(format!(
+                    "connected to {} as {}",
+                    self.url,
+                    client.name()
+                )));
+                self.status = Status::Connected(client);
+            }
+            Err(ConnectError::Refused(reason)) => {
+                self.push(Line::Error(format!("refused: {reason}")));
+                self.status = Status::Closed(reason);
+            }
+            Err(ConnectError::Failed(_)) => {
+                self.status = Status::Connecting(connect(&self.url, &self.name, true));
+            }
+        }
+    }
+
+    fn on_event(&mut self, event: ClientEvent) {
+        match event {
+            ClientEvent::Message(message) => self.on_message(message),
+            ClientEvent::Chunk(..) => {}
+            ClientEvent::Closed(reason) => {
+                self.fail_pending();
+                self.push(Line::Error(format!("closed: {reason}")));
+                self.me = None;
+                self.status = Status::Closed(reason);
+            }
+            ClientEvent::Disconnected => {
+                self.fail_pending();
+                self.push(Line::Error("disconnected, reconnecting".to_string()));
+                self.me = None;
+                self.status = Status::Connecting(connect(&self.url, &self.name, true));
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: WebSocketServerToClientMessage) {
+        match message {
+            WebSocketServerToClientMessage::UserMessage(message) => self.push(line(message)),
+            WebSocketServerToClientMessage::NewUserAdded(id, name) => {
+                self.users.insert(id, name);
+            }
+            WebSocketServerToClientMessage::Disconnected(id, _) => {
+                self.users.remove(&id);
+            }
+            WebSocketServerToClientMessage::AllUsers(users) => {
+                self.users = users.into_iter().collect();
+            }
+            WebSocketServerToClientMessage::Renamed(id, name) => {
+                if Some(id) == self.me {
+                    self.name = name.clone();
+                }
+                self.users.insert(id, name);
+            }
+            WebSocketServerToClientMessage::Joined { room } => {
+                self.room = room.name;
+                self.topic = room.topic;
+            }
+            WebSocketServerToClientMessage::RoomUpdated(room) => self.topic = room.topic,
+            WebSocketServerToClientMessage::CommandOutput { text, .. } => {
+                for line in text.lines() {
+                    self.push(Line::System(line.to_string()));
+                }
+            }
+            WebSocketServerToClientMessage::Notice(text) => self.push(Line::System(text)),
+            WebSocketServerToClientMessage::Token(token) => {
+                Tokens::load(Tokens::default_path()).insert(&self.url, &self.name, token);
+                self.push(Line::System(format!(
+                    "{} is registered now, its token is kept in {}",
+                    self.name,
+                    Tokens::default_path().display()
+                )));
+            }
+            WebSocketServerToClientMessage::Ack { nonce, message_id } => {
+                if let Some((id, delivery)) = self.pending(nonce) {
+                    *id = message_id;
+                    *delivery = Delivery::Delivered;
+                }
+            }
+            WebSocketServerToClientMessage::Rejected { nonce, reason } => {
+                if let Some((_, delivery)) = self.pending(nonce) {
+                    *delivery = Delivery::Failed;
+                }
+                self.push(Line::Error(reason))
+            }
+            WebSocketServerToClientMessage::Invited { room, from } => self.push(Line::System(
+                format!("{from} invited you to {room}, /join {room} to enter"),
+            )),
+            WebSocketServerToClientMessage::MessageDeleted(message_id) => {
+                self.lines.retain(|line| {
+                    !matches!(line, Line::Message { message_id: id, .. } if *id == message_id)
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)])
+            .split(f.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(20), Constraint::Length(24)])
+            .split(rows[0]);
+
+        // messages, the newest at the bottom
+        let width = columns[0].width.saturating_sub(2).max(1) as usize;
+        let height = columns[0].height.saturating_sub(2) as usize;
+        let lines: Vec<Spans> = self.lines.iter().map(Line::spans).collect();
+        let rows_used: usize = lines
+            .iter()
+            .map(|line| line.width().max(1).div_ceil(width))
+            .sum();
+        let bottom = rows_used.saturating_sub(height);
+        self.scroll = self.scroll.min(bottom);
+        let title = match self.topic.as_str() {
+            "" => self.room.clone(),
+            topic => format!("{} - {}", self.room, topic),
+        };
+        let messages = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .wrap(Wrap { trim: false })
+            .scroll((
+                u16::try_from(bottom.saturating_sub(self.scroll)).unwrap_or(u16::MAX),
+                0,
+            ));
+        f.render_widget(messages, columns[0]);
+
+        let users: Vec<ListItem> = self
+            .users
+            .iter()
+            .map(|(id, name)| {
+                let style = if Some(*id) == self.me {
+                    Style::default().fg(Color::Cyan)
+                } else {
+                    Style::default()
+                };
+                ListItem::new(Span::styled(name.clone(), style))
+            })
+            .collect();
+        let users = List::new(users).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("online ({})", self.users.len())),
+        );
+        f.render_widget(users, columns[1]);
+
+        // the input, scrolled sideways to keep the cursor in view
+        let status = match &self.status {
+            Status::Connecting(_) => "connecting...".to_string(),
+            Status::Connected(_) => format!("{} (Esc to quit)", self.name),
+            Status::Closed(reason) => format!("closed: {reason}"),
+        };
+        let width = rows[1].width.saturating_sub(2).max(1) as usize;
+        let start = (self.input.cursor() + 1).saturating_sub(width);
+        let text: String = self.input.text().chars().skip(start).collect();
+        let input =
+            Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(status));
+        f.render_widget(input, rows[1]);
+        f.set_cursor(
+            rows[1].x + 1 + (self.input.cursor() - start) as u16,
+            rows[1].y + 1,
+        );
+    }
+}
+
+/// make a connection in the background, after a second if `delay`
+fn connect(url: &str, name: &str, delay: bool) -> JoinHandle<Result<ChatClient, ConnectError>> {
+    let (url, name) = (url.to_string(), name.to_string());
+    tokio::spawn(async move {
+        if delay {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        match Tokens::load(Tokens::default_path()).get(&url, &name) {
+            Some(token) => ChatClient::login(&url, &name, token).await,
+            None => ChatClient::connect(&url, &name).await,
+        }
+    })
+}
+
+/// the line of a message relayed to self
+fn line(message: MessageData) -> Line {
+    if message.kind == MessageKind::System {
+        return Line::System(message.data);
+    }
+    let text = match &message.attachment {
+        Some(attachment) => format!(
+            "[file {} ({} bytes)]",
+            attachment.file_name, attachment.size
+        ),
+        None => message.data,
+    };
+    Line::Message {
+        message_id: message.message_id,
+        name: message.name,
+        text,
+        own: false,
+        delivery: Delivery::Delivered,
+        private: message.recipient.is_some(),
+        color: message.id,
+    }
+}
+
+/// what the main loop waits for
+enum Update {
+    Key(Option<io::Result<Event>>),
+    Connected(Result<ChatClient, ConnectError>),
+    Client(ClientEvent),
+}
+
+/// the next thing the connection brings, never if it is closed
+async fn next_update(status: &mut Status) -> Update {
+    match status {
+        Status::Connecting(task) => Update::Connected(
+            task.await
+                .unwrap_or_else(|e| Err(ConnectError::Failed(e.to_string()))),
+        ),
+        Status::Connected(client) => Update::Client(
+            client
+                .next_event()
+                .await
+                .unwrap_or(ClientEvent::Disconnected),
+        ),
+        Status::Closed(_) => std::future::pending().await,
+    }
+}
+
+async fn run(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) -> io::Result<()> {
+    let mut keys = EventStream::new();
+    loop {
+        terminal.draw(|f| app.draw(f))?;
+        let update = tokio::select! {
+            key = keys.next() => Update::Key(key),
+            update = next_update(&mut app.status) => update,
+        };
+        match update {
+            Update::Key(Some(Ok(Event::Key(key)))) => {
+                if !app.on_key(key).await {
+                    return Ok(());
+                }
+            }
+            Update::Key(Some(Ok(_))) => {}
+            Update::Key(Some(Err(e))) => return Err(e),
+            Update::Key(None) => return Ok(()),
+            Update::Connected(result) => app.on_connect(result),
+            Update::Client(event) => app.on_event(event),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+    let mut app = App::new(cli.url, cli.name);
+
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    // leave the alternate screen before a panic is printed
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        default_hook(info);
+    }));
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = run(&mut terminal, &mut app).await;
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    if let Status::Connected(client) = app.status {
+        let _ = tokio::time::timeout(Duration::from_secs(1), client.close()).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn own(nonce: u64) -> Line {
+        Line::Message {
+            message_id: 0,
+            name: "alice".to_string(),
+            text: "hi".to_string(),
+            own: true,
+            delivery: Delivery::Pending(nonce),
+            private: false,
+            color: 0,
+        }
+    }
+
+    fn delivery(line: &Line) -> Option<(u64, Delivery)> {
+        match line {
+            Line::Message {
+                message_id,
+                delivery,
+                ..
+            } => Some((*message_id, *delivery)),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lines() {
+        let mut app = App::new("ws://127.0.0.1:0".to_string(), "alice".to_string());
+        app.push(own(1));
+        app.push(own(2));
+        app.on_message(WebSocketServerToClientMessage::Ack {
+            nonce: 1,
+            message_id: 7,
+        });
+        app.on_message(WebSocketServerToClientMessage::Rejected {
+            nonce: 2,
+            reason: "muted".to_string(),
+        });
+        assert_eq!(delivery(&app.lines[0]), Some((7, Delivery::Delivered)));
+        assert_eq!(delivery(&app.lines[1]), Some((0, Delivery::Failed)));
+        app.push(own(3));
+        app.fail_pending();
+        assert_eq!(delivery(&app.lines[3]), Some((0, Delivery::Failed)));
+
+        for i in 0..MAX_LINES {
+            app.push(Line::System(i.to_string()));
+        }
+        assert_eq!(app.lines.len(), MAX_LINES);
+        assert!(matches!(&app.lines[0], Line::System(text) if text == "0"));
+    }
+}
@@ -0,0 +1,443 @@
+//! A headless client for tokio code, e.g. bots.
+//!
+//! [`ChatClient::connect`] joins the server under a name, or
+//! [`ChatClient::login`] under a registered one, the messages of the
+//! server are read with [`ChatClient::next_event`] and requests are sent with
+//! [`ChatClient::send`]. A bot implements [`Bot`] and is driven by
+//! [`ChatClient::run`]; [`CommandBot`] answers `!name args` messages with
+//! handlers:
+//!
+//! ```no_run
+//! use websocket_chatroom::client::{ChatClient, CommandBot};
+//!
+//! # async fn run() -> Result<(), String> {
+//! let mut client = ChatClient::connect("ws://127.0.0.1:2233", "dice")
+//!     .await
+//!     .map_err(|e| e.to_string())?;
+//! let mut bot = CommandBot::new("!").on("hello", |message, _args| {
+//!     Some(format!("hello {}", message.name))
+//! });
+//! let reason = client.run(&mut bot).await;
+//! println!("the server closed the connection: {reason:?}");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The iced subscription [`crate::connect`] is built on this client.
+
+use std::{collections::BTreeMap, fmt};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{info, warn};
+
+use crate::{
+    transfer, Connection, MessageData, WebSocketClientToServerMessage,
+    WebSocketServerToClientMessage,
+};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// how many events or requests may wait before the other side is slowed down
+const QUEUE_SIZE: usize = 64;
+
+/// something the server sent
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Message(WebSocketServerToClientMessage),
+    /// a binary frame: the transfer id and the payload
+    Chunk(u64, Vec<u8>),
+    /// the server closed the connection with a reason, e.g. a kick or a ban
+    Closed(String),
+    /// the connection ended without a reason, the last event
+    Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// the server could not be reached or did not answer as expected
+    Failed(String),
+    /// the server refused the user with the reason, e.g. because of a ban
+    Refused(String),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Failed(reason) => write!(f, "cannot connect: {reason}"),
+            Self::Refused(reason) => write!(f, "the server refused the connection: {reason}"),
+        }
+    }
+}
+
+/// a connection to the server, the websocket is served by a task of its own
+#[derive(Debug)]
+pub struct ChatClient {
+    id: u32,
+    name: String,
+    users: Vec<(u32, String)>,
+    outgoing: Sender<Message>,
+    events: Receiver<ClientEvent>,
+    task: JoinHandle<()>,
+    next_nonce: u64,
+}
+
+impl ChatClient {
+    /// connect as the user, the server may give the user another name
+    pub async fn connect(url: &str, name: &str) -> Result<Self, ConnectError> {
+        Self::open(
+            url,
+            WebSocketClientToServerMessage::Connect(name.to_string()),
+        )
+        .await
+    }
+
+    /// connect under a registered name with the token the server sent for it
+    /// in a `Token` message
+    pub async fn login(url: &str, name: &str, token: &str) -> Result<Self, ConnectError> {
+        let message = WebSocketClientToServerMessage::Login {
+            name: name.to_string(),
+            token: token.to_string(),
+        };
+        Self::open(url, message).await
+    }
+
+    async fn open(
+        url: &str,
+        message: WebSocketClientToServerMessage,
+    ) -> Result<Self, ConnectError> {
+        let (mut websocket, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .map_err(|e| ConnectError::Failed(e.to_string()))?;
+        websocket
+            .send(Message::Text(serde_json::to_string(&message).unwrap()))
+            .await
+            .map_err(|e| ConnectError::Failed(e.to_string()))?;
+        let (id, name) = match receive(&mut websocket).await? {
+            WebSocketServerToClientMessage::Connected(id, name) => (id, name),
+            message => return Err(unexpected(message)),
+        };
+        info!("Connected to server with id: {}", id);
+        let users = match receive(&mut websocket).await? {
+            WebSocketServerToClientMessage::AllUsers(users) => users,
+            message => return Err(unexpected(message)),
+        };
+        let (outgoing, requests) = mpsc::channel(QUEUE_SIZE);
+        let (event_sender, events) = mpsc::channel(QUEUE_SIZE);
+        let task = tokio::spawn(serve(websocket, requests, event_sender));
+        Ok(Self {
+            id,
+            name,
+            users,
+            outgoing,
+            events,
+            task,
+            next_nonce: 1,
+        })
+    }
+
+    /// the id the server gave this connection
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// the name the server accepted, or the one of the last `/nick`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// the users online when the connection was made
+    pub fn users(&self) -> &[(u32, String)] {
+        &self.users
+    }
+
+    /// a handle to send requests from elsewhere, e.g. from a ui
+    pub fn connection(&self) -> Connection {
+        Connection(self.outgoing.clone())
+    }
+
+    /// a nonce that was not used by this client before
+    pub fn next_nonce(&mut self) -> u64 {
+        self.next_nonce += 1;
+        self.next_nonce - 1
+    }
+
+    pub async fn send(&self, message: WebSocketClientToServerMessage) -> Result<(), String> {
+        let message = Message::Text(serde_json::to_string(&message).unwrap());
+        self.outgoing
+            .send(message)
+            .await
+            .map_err(|_| "the connection is closed".to_string())
+    }
+
+    /// send a message to the room the client is in, returning the nonce its
+    /// `Ack` or `Rejected` carries
+    pub async fn say(&mut self, text: &str) -> Result<u64, String> {
+        let nonce = self.next_nonce();
+        let message = MessageData {
+            id: self.id,
+            name: self.name.clone(),
+            data: text.to_string(),
+            nonce,
+            ..Default::default()
+        };
+        self.send(WebSocketClientToServerMessage::UserMessage(message))
+            .await
+            .map(|_| nonce)
+    }
+
+    /// run a slash command, the server answers with a `CommandOutput` or `Rejected`
+    pub async fn command(&mut self, name: &str, args: &str) -> Result<(), String> {
+        let message = WebSocketClientToServerMessage::Command {
+            nonce: self.next_nonce(),
+            name: name.to_string(),
+            args: args.to_string(),
+        };
+        self.send(message).await
+    }
+
+    /// the next thing the server sent, `None` once the connection ended
+    pub async fn next_event(&mut self) -> Option<ClientEvent> {
+        let event = self.events.recv().await;
+        if let Some(ClientEvent::Message(WebSocketServerToClientMessage::Renamed(id, name))) =
+            &event
+        {
+            if *id == self.id {
+                self.name = name.clone();
+            }
+        }
+        event
+    }
+
+    /// close the connection and wait for the server to acknowledge it
+    pub async fn close(mut self) {
+        let _ = self.outgoing.send(Message::Close(None)).await;
+        while self.events.recv().await.is_some() {}
+        let _ = self.task.await;
+    }
+
+    /// let the bot answer everything the server sends until the connection
+    /// ends, the reason if the server closed it
+    pub async fn run(&mut self, bot: &mut impl Bot) -> Option<String> {
+        while let Some(event) = self.next_event().await {
+            let mut replies = Replies::default();
+            match event {
+                // system lines have the id 0 as well
+                ClientEvent::Message(WebSocketServerToClientMessage::UserMessage(message))
+                    if message.id != self.id || message.name != self.name =>
+                {
+                    bot.on_message(&message, &mut replies)
+                }
+                ClientEvent::Message(message) => bot.on_event(&message, &mut replies),
+                ClientEvent::Chunk(..) => {}
+                ClientEvent::Closed(reason) => return Some(reason),
+                ClientEvent::Disconnected => return None,
+            }
+            for reply in replies.replies {
+                let result = match reply {
+                    Reply::Say(text) => self.say(&text).await.map(|_| ()),
+                    Reply::Command(name, args) => self.command(&name, &args).await,
+                };
+                if let Err(e) = result {
+                    warn!("cannot send the reply of the bot: {}", e);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// the next text message of the server during the handshake
+async fn receive(
+    websocket: &mut WebSocket,
+) -> Result<WebSocketServerToClientMessage, ConnectError> {
+    match websocket.next().await {
+        Some(Ok(Message::Text(message))) => serde_json::from_str(&message)
+            .map_err(|e| ConnectError::Failed(format!("cannot read the message: {e}"))),
+        // refused, e.g. because the user is banned
+        Some(Ok(Message::Close(frame))) => Err(ConnectError::Refused(
+            frame
+                .map(|frame| frame.reason.into_owned())
+                .unwrap_or_default(),
+        )),
+        Some(Ok(message)) => Err(ConnectError::Failed(format!(
+            "unexpected message {message:?}"
+        ))),
+        Some(Err(e)) => Err(ConnectError::Failed(e.to_string())),
+        None => Err(ConnectError::Failed(
+            "the server closed the connection".to_string(),
+        )),
+    }
+}
+
+fn unexpected(message: WebSocketServerToClientMessage) -> ConnectError {
+    ConnectError::Failed(format!("unexpected message {message:?}"))
+}
+
+/// pass the requests to the websocket and what it receives to the events
+/// until the connection ends or the client is dropped
+async fn serve(
+    mut websocket: WebSocket,
+    mut requests: Receiver<Message>,
+    events: Sender<ClientEvent>,
+) {
+    loop {
+        let event = tokio::select! {
+            received = websocket.next() => match received {
+                Some(Ok(Message::Text(message))) => {
+                    match serde_json::from_str(&message) {
+                        Ok(message) => ClientEvent::Message(message),
+                        Err(e) => {
+                            warn!("cannot read the message of the server: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                Some(Ok(Message::Binary(frame))) => match transfer::decode_chunk(&frame) {
+                    Some((transfer_id, data)) => ClientEvent::Chunk(transfer_id, data.to_vec()),
+                    None => continue,
+                },
+                Some(Ok(Message::Close(Some(frame)))) if !frame.reason.is_empty() => {
+                    let _ = events.send(ClientEvent::Closed(frame.reason.into_owned())).await;
+                    return;
+                }
+                // a close without a reason ends the stream with the next read
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => ClientEvent::Disconnected,
+            },
+            request = requests.recv() => match request {
+                Some(request) => match websocket.send(request).await {
+                    Ok(()) => continue,
+                    Err(_) => ClientEvent::Disconnected,
+                },
+                // every sender is gone, nobody is left to send requests
+                None => {
+                    let _ = websocket.close(None).await;
+                    ClientEvent::Disconnected
+                }
+            },
+        };
+        let last = matches!(event, ClientEvent::Disconnected);
+        if events.send(event).await.is_err() || last {
+            return;
+        }
+    }
+}
+
+enum Reply {
+    Say(String),
+    Command(String, String),
+}
+
+/// the answers of a bot, sent once the handler returns
+#[derive(Default)]
+pub struct Replies {
+    replies: Vec<Reply>,
+}
+
+impl Replies {
+    /// send a message to the room of the bot
+    pub fn say(&mut self, text: impl Into<String>) {
+        self.replies.push(Reply::Say(text.into()));
+    }
+
+    /// send a private message to the user
+    pub fn reply(&mut self, user: &str, text: &str) {
+        self.command("msg", &format!("{user} {text}"));
+    }
+
+    /// run a slash command
+    pub fn command(&mut self, name: &str, args: &str) {
+        self.replies
+            .push(Reply::Command(name.to_string(), args.to_string()));
+    }
+}
+
+/// a bot driven by [`ChatClient::run`]
+pub trait Bot {
+    /// a message of another user, or of the server, relayed to the bot
+    fn on_message(&mut self, _message: &MessageData, _replies: &mut Replies) {}
+
+    /// anything else the server sent
+    fn on_event(&mut self, _event: &WebSocketServerToClientMessage, _replies: &mut Replies) {}
+}
+
+type Handler = Box<dyn FnMut(&MessageData, &str) -> Option<String> + Send>;
+
+/// a bot answering messages like `!roll 20` with the handler of the command,
+/// the text a handler returns is said in the room
+pub struct CommandBot {
+    prefix: String,
+    handlers: BTreeMap<String, Handler>,
+}
+
+impl CommandBot {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// add the handler of a command, replacing a handler with the same name
+    pub fn on(
+        mut self,
+        name: &str,
+        handler: impl FnMut(&MessageData, &str) -> Option<String> + Send + 'static,
+    ) -> Self {
+        self.handlers.insert(name.to_string(), Box::new(handler));
+        self
+    }
+
+    /// the text of the handler of the message, `None` if it is not a command
+    pub fn handle(&mut self, message: &MessageData) -> Option<String> {
+        let line = message.data.trim().strip_prefix(&self.prefix)?;
+        let (name, args) = line
+            .split_once(char::is_whitespace)
+            .map(|(name, args)| (name, args.trim()))
+            .unwrap_or((line, ""));
+        let handler = self.handlers.get_mut(name)?;
+        handler(message, args)
+    }
+}
+
+impl Bot for CommandBot {
+    fn on_message(&mut self, message: &MessageData, replies: &mut Replies) {
+        if let Some(text) = self.handle(message) {
+            replies.say(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_bot() {
+        let mut bot = CommandBot::new("!").on("echo", |message, args| {
+            Some(format!("{} said {}", message.name, args))
+        });
+        let message = |data: &str| MessageData {
+            name: "bob".to_string(),
+            data: data.to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            bot.handle(&message("!echo  hi there")),
+            Some("bob said hi there".to_string())
+        );
+        assert_eq!(bot.handle(&message("echo hi")), None);
+        assert_eq!(bot.handle(&message("!unknown")), None);
+
+        let mut replies = Replies::default();
+        bot.on_message(&message("!echo"), &mut replies);
+        assert!(matches!(&replies.replies[..], [Reply::Say(text)] if text == "bob said "));
+    }
+}
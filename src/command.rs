@@ -0,0 +1,66 @@
+//! Slash commands typed into the message input.
+//!
+//! An input starting with `/` is a command: the word after the slash is the
+//! name of the command, the rest of the line its arguments. Clients send it as
+//! [`crate::WebSocketClientToServerMessage::Command`] and the server runs it.
+//! Messages that should start with a slash are written with two of them.
+
+/// the name of the default room every user joins after connecting
+pub const LOBBY: &str = "lobby";
+
+/// split a command into its name and arguments, `None` for a normal message
+pub fn parse(input: &str) -> Option<(&str, &str)> {
+    let line = input.trim();
+    let command = line.strip_prefix('/')?;
+    if command.is_empty() || command.starts_with('/') {
+        return None;
+    }
+    match command.split_once(char::is_whitespace) {
+        Some((name, args)) => Some((name, args.trim())),
+        None => Some((command, "")),
+    }
+}
+
+/// the text of a normal message, undoing the escape of a leading slash
+pub fn unescape(input: &str) -> &str {
+    match input.strip_prefix('/') {
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => input,
+    }
+}
+
+/// whether the name may be used for a user or a room
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("/join rust"), Some(("join", "rust")));
+        assert_eq!(
+            parse("  /msg bob  hello there "),
+            Some(("msg", "bob  hello there"))
+        );
+        assert_eq!(parse("/who"), Some(("who", "")));
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("//not a command"), None);
+        assert_eq!(unescape("//not a command"), "/not a command");
+        assert_eq!(unescape("hello"), "hello");
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(is_valid_name("rust-lang"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("two words"));
+    }
+}
@@ -0,0 +1,80 @@
+//! Syntax highlighting for the code blocks of markdown messages.
+
+use std::sync::OnceLock;
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+/// a piece of a highlighted line drawn in a single color
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HighlightedSpan {
+    /// rgb
+    pub color: (u8, u8, u8),
+    pub text: String,
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults();
+        themes.themes.remove("InspiredGitHub").unwrap()
+    })
+}
+
+/// split the code into lines of colored spans, `lang` is the info string of the
+/// fenced block (a name like `rust` or an extension like `rs`), unknown
+/// languages are returned in the default color
+pub fn highlight(code: &str, lang: Option<&str>) -> Vec<Vec<HighlightedSpan>> {
+    let syntax_set = syntax_set();
+    let syntax = lang
+        .and_then(|lang| syntax_set.find_syntax_by_token(lang))
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, theme());
+    LinesWithEndings::from(code)
+        .map(|line| match highlighter.highlight_line(line, syntax_set) {
+            Ok(ranges) => ranges
+                .into_iter()
+                .map(|(style, text)| HighlightedSpan {
+                    color: (style.foreground.r, style.foreground.g, style.foreground.b),
+                    text: text.trim_end_matches(['\n', '\r']).to_string(),
+                })
+                .filter(|span| !span.text.is_empty())
+                .collect(),
+            Err(_) => vec![HighlightedSpan {
+                color: (0, 0, 0),
+                text: line.trim_end_matches(['\n', '\r']).to_string(),
+            }],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_rust() {
+        let lines = highlight("fn main() {\n    let a = 1;\n}\n", Some("rust"));
+        assert_eq!(lines.len(), 3);
+        let first: String = lines[0].iter().map(|span| span.text.as_str()).collect();
+        assert_eq!(first, "fn main() {");
+        // the keyword and the function name are colored differently
+        assert_ne!(lines[0][0].color, lines[0][1].color);
+    }
+
+    #[test]
+    fn test_highlight_unknown_language() {
+        let lines = highlight("just text", Some("no-such-language"));
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].len(), 1);
+    }
+}
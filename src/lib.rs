@@ -1,28 +1,276 @@
-use iced::{
-    futures::{SinkExt, StreamExt},
-    subscription, Subscription,
-};
+use client::{ChatClient, ClientEvent, ConnectError};
+use iced::{subscription, Subscription};
 use serde::{Deserialize, Serialize};
-use tokio::{
-    net::TcpStream,
-    sync::mpsc::{Receiver, Sender},
-};
-use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tokens::Tokens;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_tungstenite::tungstenite::Message;
 use tracing::info;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+pub mod client;
+pub mod command;
+pub mod highlight;
+pub mod markdown;
+pub mod outbox;
+pub mod plugin;
+pub mod server;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod tokens;
+pub mod transfer;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct MessageData {
     pub id: u32,
     pub name: String,
     pub data: String,
+    /// generated by the sending client, echoed back in the [`WebSocketServerToClientMessage::Ack`]
+    #[serde(default)]
+    pub nonce: u64,
+    /// assigned by the server when the message is relayed, 0 before that
+    #[serde(default)]
+    pub message_id: u64,
+    /// how `data` should be rendered
+    #[serde(default)]
+    pub format: MessageFormat,
+    #[serde(default)]
+    pub kind: MessageKind,
+    /// a file shared with the message, `data` holds the file name for clients that ignore it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    /// the user a private message is sent to, `None` for messages to the room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+}
+
+/// a file stored on the server, addressed by the sha-256 of its content
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Attachment {
+    pub sha256: String,
+    pub file_name: String,
+    pub size: u64,
+    pub mime: String,
+    /// a small png preview generated by the server for images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// a preview of an image attachment, stored and downloaded like any other attachment
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Thumbnail {
+    pub sha256: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MessageData {
+    /// the message as plain text, for clients that do not render markdown
+    pub fn plain_text(&self) -> String {
+        match self.format {
+            MessageFormat::Plain => self.data.clone(),
+            MessageFormat::Markdown => markdown::to_plain_text(&self.data),
+        }
+    }
+}
+
+/// the format of [`MessageData::data`], markdown messages are sent as
+/// [`MessageData::plain_text`] to clients that did not ask for them with `Formats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    /// see [`markdown`] for the supported subset
+    Markdown,
+}
+
+/// the metadata of a room, sent when joining it and whenever it changes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: String,
+    pub description: String,
+    /// the name of the user that created the room, empty for the lobby
+    pub created_by: String,
+    /// seconds since the unix epoch
+    pub created_at: u64,
+    #[serde(default)]
+    pub visibility: RoomVisibility,
+}
+
+/// who can see and join a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RoomVisibility {
+    #[default]
+    Public,
+    /// only listed for and joinable by members, users become members by accepting an invitation
+    Private,
+    /// listed for everyone, joining needs the password
+    Password,
+}
+
+/// what a user may do in a room, ordered from the least to the most allowed
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+pub enum Role {
+    /// may read and chat but not upload or invite
+    Guest,
+    #[default]
+    Member,
+    /// may set the topic and moderate the room
+    Moderator,
+    /// may change who may join the room and the roles of others
+    Owner,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Guest => "guest",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Role::Guest, Role::Member, Role::Moderator, Role::Owner]
+            .into_iter()
+            .find(|role| role.name() == name)
+    }
+}
+
+/// who a message comes from and how it is shown in the timeline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MessageKind {
+    #[default]
+    Normal,
+    /// sent with `/me`, `data` is the action without the name of the user
+    Action,
+    /// a notice of the server about joins, leaves, renames or topic changes
+    System,
+}
+
+/// a message reported by a user, waiting for a moderator
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MessageReport {
+    pub id: u64,
+    /// the name of the reporting user
+    pub reporter: String,
+    pub reason: String,
+    pub room: String,
+    pub message: MessageData,
+    /// the messages around the reported one in the room, in order, including it
+    pub context: Vec<MessageData>,
+}
+
+/// what a moderator does about a report, every action resolves it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ReportAction {
+    /// the message is fine
+    Dismiss,
+    /// remove the message for everyone in the room
+    Delete,
+    /// mute the author for this many seconds
+    Mute(u64),
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum WebSocketClientToServerMessage {
     UserMessage(MessageData),
     Connect(String),
+    /// connect under a registered name with the token the server gave it (see `Token`)
+    Login {
+        name: String,
+        token: String,
+    },
+    /// the formats the client renders, sent after connecting; markdown
+    /// messages reach clients that did not send `Markdown` as plain text
+    Formats(Vec<MessageFormat>),
+    /// announce an upload, the content follows in binary frames (see [`transfer`])
+    BeginUpload {
+        upload_id: u64,
+        file_name: String,
+        size: u64,
+        mime: String,
+    },
+    /// all chunks are sent, the server answers with an `Ack` or `Rejected` for the upload id
+    FinishUpload {
+        upload_id: u64,
+        sha256: String,
+    },
+    /// ask for the content of an attachment
+    Download {
+        transfer_id: u64,
+        sha256: String,
+    },
+    /// run a slash command (see [`command`]), answered with a `CommandOutput` or `Rejected`
+    Command {
+        nonce: u64,
+        name: String,
+        args: String,
+    },
+    /// change the topic of a room, answered with a `Rejected` if that is not allowed
+    SetTopic {
+        nonce: u64,
+        room: String,
+        topic: String,
+    },
+    /// invite a user into a room self is a member of
+    Invite {
+        room: String,
+        user_id: u32,
+    },
+    /// accept a pending invitation and join the room
+    AcceptInvite {
+        room: String,
+    },
+    DeclineInvite {
+        room: String,
+    },
+    /// ask for the rooms self can see, answered with a `RoomList`
+    ListRooms,
+    /// change the role of a user in a room, only owners may do that
+    SetRole {
+        room: String,
+        user_id: u32,
+        role: Role,
+    },
+    /// disconnect a user in the room self moderates
+    Kick {
+        user_id: u32,
+        reason: String,
+    },
+    /// disconnect a user and refuse their account for `duration` seconds, or
+    /// for good if it is `None`, and with `address` the address they connect from
+    Ban {
+        user_id: u32,
+        duration: Option<u64>,
+        reason: String,
+        #[serde(default)]
+        address: bool,
+    },
+    /// drop the messages of a user for `duration` seconds, 0 lifts the mute
+    Mute {
+        user_id: u32,
+        duration: u64,
+    },
+    /// lift the bans of an account name or an address, e.g. `10.0.0.0/8`
+    Unban {
+        target: String,
+    },
+    /// report a message to the moderators of its room
+    Report {
+        message_id: u64,
+        reason: String,
+    },
+    /// ask for the reports of the rooms self moderates, answered with `Reports`
+    ListReports,
+    ResolveReport {
+        report_id: u64,
+        action: ReportAction,
+    },
 }
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum WebSocketServerToClientMessage {
     UserMessage(MessageData),
     /// self connect success
@@ -33,6 +281,59 @@ pub enum WebSocketServerToClientMessage {
     Disconnected(u32, String),
     /// all users
     AllUsers(Vec<(u32, String)>),
+    /// the message with this nonce was relayed by the server
+    Ack {
+        nonce: u64,
+        message_id: u64,
+    },
+    /// the message with this nonce was not relayed by the server
+    Rejected {
+        nonce: u64,
+        reason: String,
+    },
+    /// the content of the attachment follows in binary frames
+    DownloadStarted {
+        transfer_id: u64,
+        size: u64,
+    },
+    DownloadFinished {
+        transfer_id: u64,
+    },
+    DownloadFailed {
+        transfer_id: u64,
+        reason: String,
+    },
+    /// the text the command with this nonce printed, only shown to the user running it
+    CommandOutput {
+        nonce: u64,
+        text: String,
+    },
+    /// self entered the room, messages are only relayed within a room
+    Joined {
+        room: RoomInfo,
+    },
+    /// the topic or description of the room self is in changed
+    RoomUpdated(RoomInfo),
+    /// a user changed their name
+    Renamed(u32, String),
+    /// an invitation into the room from the user
+    Invited {
+        room: String,
+        from: String,
+    },
+    /// the rooms visible to self, private rooms are only listed for their members
+    RoomList(Vec<RoomInfo>),
+    /// a note for this user only, e.g. why a request without a nonce failed
+    Notice(String),
+    /// the roles of the users in the room self is in, sent whenever they change
+    RoomRoles(Vec<(u32, Role)>),
+    /// the open reports of the rooms self moderates
+    Reports(Vec<MessageReport>),
+    /// a moderator removed the message with this id
+    MessageDeleted(u64),
+    /// the name of self was registered because it was given a role, only a
+    /// `Login` with this token connects under it from now on
+    Token(String),
 }
 
 pub fn connect() -> Subscription<Event> {
@@ -40,137 +341,80 @@ pub fn connect() -> Subscription<Event> {
     subscription::unfold(
         std::any::TypeId::of::<Connect>(),
         State::WaitingUrl,
-        move |state| {
-            async move {
-                match state {
-                    State::Stoped(mut receiver) => {
-                        let (url, user_name) = receiver.recv().await.unwrap();
-                        (None, State::Disconnected(url, user_name))
-                    }
-                    State::WaitingUrl => {
-                        let (sender, receiver) = tokio::sync::mpsc::channel(10);
-                        (Some(Event::ReadyToConnect(sender)), State::Stoped(receiver))
-                    }
-                    State::Disconnected(url, user_name) => {
-                        match tokio_tungstenite::connect_async(&url).await {
-                            Ok((mut websocket, _)) => {
-                                let (sender, receiver) = tokio::sync::mpsc::channel(10);
-                                // send the connect message to server
-                                let message = WebSocketClientToServerMessage::Connect(user_name);
-                                let message = serde_json::to_string(&message).unwrap();
-                                websocket.send(Message::Text(message)).await.unwrap();
-                                // receive the id from server
-                                let (id, user_name) = match websocket.next().await {
-                                    Some(Ok(Message::Text(message))) => {
-                                        let message: WebSocketServerToClientMessage =
-                                            serde_json::from_str(&message).unwrap();
-                                        match message {
-                                            WebSocketServerToClientMessage::Connected(
-                                                id,
-                                                user_name,
-                                            ) => (id, user_name),
-                                            _ => panic!("Unexpected message"),
-                                        }
-                                    }
-                                    _ => panic!("Unexpected message"),
-                                };
-                                info!("Connected to server with id: {}", id);
-                                let all_users = match websocket.next().await {
-                                    Some(Ok(Message::Text(message))) => {
-                                        let message: WebSocketServerToClientMessage =
-                                            serde_json::from_str(&message).unwrap();
-                                        match message {
-                                            WebSocketServerToClientMessage::AllUsers(all_users) => {
-                                                all_users
-                                            }
-                                            _ => panic!("Unexpected message"),
-                                        }
-                                    }
-                                    _ => panic!("Unexpected message"),
-                                };
-                                info!("All users: {:?}", all_users);
-                                (
-                                    Some(Event::Connected(Connection(sender), id, all_users)),
-                                    State::Connected(websocket, receiver, url, user_name),
-                                )
-                            }
-                            Err(_) => {
-                                // Wait for 1 second before retrying
-                                println!("Connection failed... Retrying...");
-                                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-
-                                (
-                                    Some(Event::Disconnected),
-                                    State::Disconnected(url, user_name),
-                                )
-                            }
+        move |state| async move {
+            match state {
+                State::Stoped(mut receiver) => {
+                    let (url, user_name) = receiver.recv().await.unwrap();
+                    (None, State::Disconnected(url, user_name))
+                }
+                State::WaitingUrl => {
+                    let (sender, receiver) = tokio::sync::mpsc::channel(10);
+                    (Some(Event::ReadyToConnect(sender)), State::Stoped(receiver))
+                }
+                State::Disconnected(url, user_name) => {
+                    let tokens = Tokens::load(Tokens::default_path());
+                    let connected = match tokens.get(&url, &user_name) {
+                        Some(token) => ChatClient::login(&url, &user_name, token).await,
+                        None => ChatClient::connect(&url, &user_name).await,
+                    };
+                    match connected {
+                        Ok(client) => {
+                            info!("All users: {:?}", client.users());
+                            let formats = vec![MessageFormat::Markdown];
+                            let _ = client
+                                .send(WebSocketClientToServerMessage::Formats(formats))
+                                .await;
+                            (
+                                Some(Event::Connected(
+                                    client.connection(),
+                                    client.id(),
+                                    client.users().to_vec(),
+                                )),
+                                State::Connected(client, url, user_name),
+                            )
                         }
-                    }
-                    State::Connected(mut websocket, mut input, url, user_name) => {
-                        let mut fused_websocket = websocket.by_ref().fuse();
-                        let on_receive_remote =
-                            |received,
-                             websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
-                             input: Receiver<WebSocketClientToServerMessage>,
-                             url,
-                             user_name| {
-                                match received {
-                                    Ok(Message::Text(message)) => {
-                                        let message: WebSocketServerToClientMessage =
-                                            serde_json::from_str(&message).unwrap();
-                                        (
-                                            Some(Event::MessageReceived(message)),
-                                            State::Connected(websocket, input, url, user_name),
-                                        )
-                                    }
-                                    Ok(_) => {
-                                        (None, State::Connected(websocket, input, url, user_name))
-                                    }
-                                    Err(_) => (
-                                        Some(Event::Disconnected),
-                                        State::Disconnected(url, user_name),
-                                    ),
-                                }
-                            };
-                        let on_received_user_input =
-                            |message,
-                             mut websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
-                             input,
-                             url,
-                             user_name| async move {
-                                let message = match message {
-                                    Some(message) => message,
-                                    None => {
-                                        return (
-                                            Some(Event::Disconnected),
-                                            State::Disconnected(url, user_name),
-                                        );
-                                    }
-                                };
-                                let message = serde_json::to_string(&message).unwrap();
-                                let result = websocket.send(Message::Text(message)).await;
-
-                                if result.is_ok() {
-                                    (None, State::Connected(websocket, input, url, user_name))
-                                } else {
-                                    (
-                                        Some(Event::Disconnected),
-                                        State::Disconnected(url, user_name),
-                                    )
-                                }
-                            };
-                        tokio::select! {
-                            received = fused_websocket.select_next_some() => {
-                                on_receive_remote(received,websocket,input,url,user_name)
-                            }
-
-                            message = input.recv() => {
-                                on_received_user_input(message,websocket,input,url,user_name).await
-
-                            }
+                        // refused, e.g. because the user is banned
+                        Err(ConnectError::Refused(reason)) => {
+                            (Some(Event::Closed(reason)), State::WaitingUrl)
+                        }
+                        Err(ConnectError::Failed(_)) => {
+                            // Wait for 1 second before retrying
+                            println!("Connection failed... Retrying...");
+                            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+                            (
+                                Some(Event::Disconnected),
+                                State::Disconnected(url, user_name),
+                            )
                         }
                     }
                 }
+                State::Connected(mut client, url, user_name) => match client.next_event().await {
+                    Some(ClientEvent::Message(message)) => {
+                        if let WebSocketServerToClientMessage::Token(token) = &message {
+                            Tokens::load(Tokens::default_path()).insert(
+                                &url,
+                                client.name(),
+                                token.clone(),
+                            );
+                        }
+                        (
+                            Some(Event::MessageReceived(message)),
+                            State::Connected(client, url, user_name),
+                        )
+                    }
+                    Some(ClientEvent::Chunk(transfer_id, data)) => (
+                        Some(Event::ChunkReceived(transfer_id, data)),
+                        State::Connected(client, url, user_name),
+                    ),
+                    Some(ClientEvent::Closed(reason)) => {
+                        (Some(Event::Closed(reason)), State::WaitingUrl)
+                    }
+                    Some(ClientEvent::Disconnected) | None => (
+                        Some(Event::Disconnected),
+                        State::Disconnected(url, user_name),
+                    ),
+                },
             }
         },
     )
@@ -180,12 +424,7 @@ enum State {
     WaitingUrl,
     Stoped(Receiver<(String, String)>),
     Disconnected(String, String),
-    Connected(
-        WebSocketStream<MaybeTlsStream<TcpStream>>,
-        Receiver<WebSocketClientToServerMessage>,
-        String,
-        String,
-    ),
+    Connected(ChatClient, String, String),
 }
 
 #[derive(Debug, Clone)]
@@ -194,42 +433,34 @@ pub enum Event {
     Connected(Connection, u32, Vec<(u32, String)>),
     Disconnected,
     MessageReceived(WebSocketServerToClientMessage),
+    /// a binary frame: the transfer id and the payload
+    ChunkReceived(u64, Vec<u8>),
+    /// the server closed the connection with a reason, e.g. a kick or a ban,
+    /// and is not connected to again until a new url is sent
+    Closed(String),
 }
 
 #[derive(Debug, Clone)]
-pub struct Connection(Sender<WebSocketClientToServerMessage>);
+pub struct Connection(Sender<Message>);
 
 impl Connection {
+    /// hand the message to the websocket task, waiting while the queue is full
     pub async fn send(
         &mut self,
         message: WebSocketClientToServerMessage,
-    ) -> Result<(), tokio::sync::mpsc::error::TrySendError<WebSocketClientToServerMessage>> {
-        self.0.try_send(message)
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    enum E {
-        A(String),
-        B(String),
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<Message>> {
+        let message = serde_json::to_string(&message).unwrap();
+        self.0.send(Message::Text(message)).await
     }
-    #[test]
-    fn test_enum() {
-        let mut e = E::A("a".to_string());
 
-        change_e(&mut e);
-    }
-
-    fn change_e(e: &mut E) {
-        // swap a and b
-        match e {
-            E::A(a) => {
-                *e = E::B(a.clone());
-            }
-            E::B(b) => {
-                *e = E::A(b.clone());
-            }
-        }
+    /// send a chunk of the transfer as a binary frame
+    pub async fn send_chunk(
+        &mut self,
+        transfer_id: u64,
+        data: &[u8],
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<Message>> {
+        self.0
+            .send(Message::Binary(transfer::encode_chunk(transfer_id, data)))
+            .await
     }
 }
@@ -0,0 +1,534 @@
+//! The markdown subset understood by the chatroom.
+//!
+//! Markdown messages are parsed into a small document model made of [`Block`]s
+//! and [`Span`]s that the clients render, the server runs [`sanitize`] on every
+//! markdown message before relaying it, and [`to_plain_text`] gives the
+//! readable fallback it sends to clients that cannot render markdown.
+
+use std::ops::Range;
+
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag};
+
+/// link schemes that are kept by [`sanitize`], links without a scheme are kept too
+const SAFE_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block {
+    Paragraph(Vec<Span>),
+    /// the level goes from 1 to 6
+    Heading(u8, Vec<Span>),
+    Quote(Vec<Block>),
+    List {
+        /// the number of the first item for ordered lists
+        start: Option<u64>,
+        items: Vec<Vec<Block>>,
+    },
+    /// a fenced or indented code block
+    Code {
+        lang: Option<String>,
+        code: String,
+    },
+    Rule,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: SpanStyle,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpanStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub strike: bool,
+    /// inline code
+    pub code: bool,
+    /// the target if the span is part of a link
+    pub link: Option<String>,
+}
+
+enum Frame {
+    /// a container of blocks: the document, a quote or a list item
+    Blocks(Vec<Block>),
+    List {
+        start: Option<u64>,
+        items: Vec<Vec<Block>>,
+    },
+    /// a paragraph or a heading, the paragraphs of tight list items are implicit
+    Inline {
+        heading: Option<u8>,
+        explicit: bool,
+        spans: Vec<Span>,
+    },
+    Code {
+        lang: Option<String>,
+        code: String,
+    },
+}
+
+struct Builder {
+    stack: Vec<Frame>,
+    bold: usize,
+    italic: usize,
+    strike: usize,
+    links: Vec<String>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Self {
+            stack: vec![Frame::Blocks(Vec::new())],
+            bold: 0,
+            italic: 0,
+            strike: 0,
+            links: Vec::new(),
+        }
+    }
+
+    fn style(&self) -> SpanStyle {
+        SpanStyle {
+            bold: self.bold > 0,
+            italic: self.italic > 0,
+            strike: self.strike > 0,
+            code: false,
+            link: self.links.last().cloned(),
+        }
+    }
+
+    fn push_block(&mut self, block: Block) {
+        self.close_implicit_inline();
+        match self.stack.last_mut() {
+            Some(Frame::Blocks(blocks)) => blocks.push(block),
+            Some(Frame::List { items, .. }) => items.push(vec![block]),
+            // a block where only text is expected is kept as its plain text
+            Some(Frame::Inline { .. } | Frame::Code { .. }) => {
+                let mut text = String::new();
+                blocks_to_plain_text(&[block], "", &mut text);
+                self.text(text.trim_end());
+            }
+            None => {}
+        }
+    }
+
+    fn push_span(&mut self, text: &str, style: SpanStyle) {
+        if !matches!(self.stack.last(), Some(Frame::Inline { .. })) {
+            self.stack.push(Frame::Inline {
+                heading: None,
+                explicit: false,
+                spans: Vec::new(),
+            });
+        }
+        let Some(Frame::Inline { spans, .. }) = self.stack.last_mut() else {
+            unreachable!()
+        };
+        // merge with the previous span to keep the number of widgets low
+        match spans.last_mut() {
+            Some(last) if last.style == style => last.text.push_str(text),
+            _ => spans.push(Span {
+                text: text.to_string(),
+                style,
+            }),
+        }
+    }
+
+    fn close_implicit_inline(&mut self) {
+        if let Some(Frame::Inline {
+            explicit: false, ..
+        }) = self.stack.last()
+        {
+            let Some(Frame::Inline { spans, .. }) = self.stack.pop() else {
+                unreachable!()
+            };
+            self.push_block(Block::Paragraph(spans));
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        match self.stack.last_mut() {
+            Some(Frame::Code { code, .. }) => code.push_str(text),
+            _ => {
+                let style = self.style();
+                self.push_span(text, style);
+            }
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => {
+                self.close_implicit_inline();
+                self.stack.push(Frame::Inline {
+                    heading: None,
+                    explicit: true,
+                    spans: Vec::new(),
+                });
+            }
+            Tag::Heading(level, ..) => {
+                self.close_implicit_inline();
+                self.stack.push(Frame::Inline {
+                    heading: Some(heading_level(level)),
+                    explicit: true,
+                    spans: Vec::new(),
+                });
+            }
+            Tag::BlockQuote | Tag::Item | Tag::FootnoteDefinition(_) => {
+                self.close_implicit_inline();
+                self.stack.push(Frame::Blocks(Vec::new()));
+            }
+            Tag::List(start) => {
+                self.close_implicit_inline();
+                self.stack.push(Frame::List {
+                    start,
+                    items: Vec::new(),
+                });
+            }
+            Tag::CodeBlock(kind) => {
+                self.close_implicit_inline();
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().map(|lang| lang.to_string())
+                    }
+                    CodeBlockKind::Indented => None,
+                };
+                self.stack.push(Frame::Code {
+                    lang,
+                    code: String::new(),
+                });
+            }
+            Tag::Emphasis => self.italic += 1,
+            Tag::Strong => self.bold += 1,
+            Tag::Strikethrough => self.strike += 1,
+            Tag::Link(_, dest, _) | Tag::Image(_, dest, _) => self.links.push(dest.to_string()),
+            Tag::Table(_) | Tag::TableHead | Tag::TableRow | Tag::TableCell => {}
+        }
+    }
+
+    fn end(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph | Tag::Heading(..) => {
+                if let Some(Frame::Inline { heading, spans, .. }) = self.stack.pop() {
+                    self.push_block(match heading {
+                        Some(level) => Block::Heading(level, spans),
+                        None => Block::Paragraph(spans),
+                    });
+                }
+            }
+            Tag::BlockQuote | Tag::FootnoteDefinition(_) => {
+                self.close_implicit_inline();
+                if let Some(Frame::Blocks(blocks)) = self.stack.pop() {
+                    self.push_block(Block::Quote(blocks));
+                }
+            }
+            Tag::Item => {
+                self.close_implicit_inline();
+                if let Some(Frame::Blocks(blocks)) = self.stack.pop() {
+                    if let Some(Frame::List { items, .. }) = self.stack.last_mut() {
+                        items.push(blocks);
+                    }
+                }
+            }
+            Tag::List(_) => {
+                if let Some(Frame::List { start, items }) = self.stack.pop() {
+                    self.push_block(Block::List { start, items });
+                }
+            }
+            Tag::CodeBlock(_) => {
+                if let Some(Frame::Code { lang, code }) = self.stack.pop() {
+                    self.push_block(Block::Code { lang, code });
+                }
+            }
+            Tag::Emphasis => self.italic -= 1,
+            Tag::Strong => self.bold -= 1,
+            Tag::Strikethrough => self.strike -= 1,
+            Tag::Link(..) | Tag::Image(..) => {
+                self.links.pop();
+            }
+            Tag::Table(_) | Tag::TableHead | Tag::TableRow | Tag::TableCell => {}
+        }
+    }
+}
+
+fn heading_level(level: HeadingLevel) -> u8 {
+    match level {
+        HeadingLevel::H1 => 1,
+        HeadingLevel::H2 => 2,
+        HeadingLevel::H3 => 3,
+        HeadingLevel::H4 => 4,
+        HeadingLevel::H5 => 5,
+        HeadingLevel::H6 => 6,
+    }
+}
+
+fn options() -> Options {
+    Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS
+}
+
+/// parse the markdown source into blocks
+pub fn parse(source: &str) -> Vec<Block> {
+    let mut builder = Builder::new();
+    for event in Parser::new_ext(source, options()) {
+        match event {
+            Event::Start(tag) => builder.start(tag),
+            Event::End(tag) => builder.end(tag),
+            Event::Text(text) | Event::Html(text) => builder.text(&text),
+            Event::Code(code) => {
+                let style = SpanStyle {
+                    code: true,
+                    ..builder.style()
+                };
+                builder.push_span(&code, style);
+            }
+            Event::SoftBreak => builder.text(" "),
+            Event::HardBreak => builder.text("\n"),
+            Event::Rule => builder.push_block(Block::Rule),
+            Event::TaskListMarker(checked) => builder.text(if checked { "[x] " } else { "[ ] " }),
+            Event::FootnoteReference(name) => builder.text(&format!("[{name}]")),
+        }
+    }
+    builder.close_implicit_inline();
+    match builder.stack.pop() {
+        Some(Frame::Blocks(blocks)) => blocks,
+        _ => Vec::new(),
+    }
+}
+
+fn is_safe_link(dest: &str) -> bool {
+    let dest = dest.trim();
+    match dest.find(':') {
+        // a colon after a path, query or fragment character is not a scheme separator
+        Some(colon) if !dest[..colon].contains(['/', '?', '#']) => {
+            let scheme = dest[..colon].to_ascii_lowercase();
+            SAFE_SCHEMES.contains(&scheme.as_str())
+        }
+        _ => true,
+    }
+}
+
+/// escape the characters that have a meaning in markdown
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_{}[]()<>#+-.!|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// make a markdown message safe to relay: control characters are dropped, raw
+/// html is escaped so it shows up as text and links with a scheme other than
+/// http, https or mailto are replaced by their text
+pub fn sanitize(source: &str) -> String {
+    let source: String = source
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect();
+    let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+    // the range and the collected text of an unsafe link
+    let mut unsafe_link: Option<(Range<usize>, String)> = None;
+    for (event, range) in Parser::new_ext(&source, options()).into_offset_iter() {
+        if let Some((link_range, link_text)) = &mut unsafe_link {
+            match event {
+                Event::End(Tag::Link(..) | Tag::Image(..)) if range == *link_range => {
+                    let (link_range, link_text) = unsafe_link.take().unwrap();
+                    edits.push((link_range, escape(&link_text)));
+                }
+                Event::Text(text) | Event::Code(text) | Event::Html(text) => {
+                    link_text.push_str(&text)
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            Event::Start(Tag::Link(_, dest, _) | Tag::Image(_, dest, _))
+                if !is_safe_link(&dest) =>
+            {
+                unsafe_link = Some((range, String::new()));
+            }
+            Event::Html(html) => edits.push((range, html.replace('<', "\\<"))),
+            _ => {}
+        }
+    }
+    let mut sanitized = String::with_capacity(source.len());
+    let mut last = 0;
+    for (range, replacement) in edits {
+        if range.start < last {
+            continue;
+        }
+        sanitized.push_str(&source[last..range.start]);
+        sanitized.push_str(&replacement);
+        last = range.end;
+    }
+    sanitized.push_str(&source[last..]);
+    sanitized
+}
+
+fn spans_to_plain_text(spans: &[Span], output: &mut String) {
+    let mut current_link: Option<&str> = None;
+    for span in spans {
+        if let Some(link) = current_link {
+            if Some(link) != span.style.link.as_deref() {
+                output.push_str(&format!(" ({link})"));
+            }
+        }
+        output.push_str(&span.text);
+        current_link = span.style.link.as_deref();
+    }
+    if let Some(link) = current_link {
+        output.push_str(&format!(" ({link})"));
+    }
+}
+
+fn blocks_to_plain_text(blocks: &[Block], indent: &str, output: &mut String) {
+    for block in blocks {
+        match block {
+            Block::Paragraph(spans) | Block::Heading(_, spans) => {
+                output.push_str(indent);
+                spans_to_plain_text(spans, output);
+                output.push('\n');
+            }
+            Block::Quote(blocks) => blocks_to_plain_text(blocks, &format!("{indent}> "), output),
+            Block::List { start, items } => {
+                for (index, item) in items.iter().enumerate() {
+                    let marker = match start {
+                        Some(start) => format!("{}. ", start + index as u64),
+                        None => "- ".to_string(),
+                    };
+                    let mut item_text = String::new();
+                    blocks_to_plain_text(item, "", &mut item_text);
+                    for (line_number, line) in item_text.lines().enumerate() {
+                        output.push_str(indent);
+                        if line_number == 0 {
+                            output.push_str(&marker);
+                        } else {
+                            output.push_str(&" ".repeat(marker.len()));
+                        }
+                        output.push_str(line);
+                        output.push('\n');
+                    }
+                }
+            }
+            Block::Code { code, .. } => {
+                for line in code.lines() {
+                    output.push_str(indent);
+                    output.push_str(line);
+                    output.push('\n');
+                }
+            }
+            Block::Rule => {
+                output.push_str(indent);
+                output.push_str("---\n");
+            }
+        }
+    }
+}
+
+/// render the markdown source as plain text, keeping link targets in brackets
+pub fn to_plain_text(source: &str) -> String {
+    let mut output = String::new();
+    blocks_to_plain_text(&parse(source), "", &mut output);
+    output.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_inline_styles() {
+        let blocks = parse("a **b** *c* `d` [e](https://e.com)");
+        let Block::Paragraph(spans) = &blocks[0] else {
+            panic!("expected a paragraph, got {blocks:?}");
+        };
+        let styled: Vec<_> = spans
+            .iter()
+            .map(|span| (span.text.as_str(), span.style.clone()))
+            .collect();
+        assert_eq!(styled[1].0, "b");
+        assert!(styled[1].1.bold);
+        assert_eq!(styled[3].0, "c");
+        assert!(styled[3].1.italic);
+        assert_eq!(styled[5].0, "d");
+        assert!(styled[5].1.code);
+        assert_eq!(styled[7].1.link.as_deref(), Some("https://e.com"));
+    }
+
+    #[test]
+    fn test_parse_blocks() {
+        let blocks = parse("- one\n- two\n\n```rust\nfn main() {}\n```\n");
+        assert_eq!(
+            blocks,
+            vec![
+                Block::List {
+                    start: None,
+                    items: vec![
+                        vec![Block::Paragraph(vec![Span {
+                            text: "one".to_string(),
+                            style: SpanStyle::default(),
+                        }])],
+                        vec![Block::Paragraph(vec![Span {
+                            text: "two".to_string(),
+                            style: SpanStyle::default(),
+                        }])],
+                    ],
+                },
+                Block::Code {
+                    lang: Some("rust".to_string()),
+                    code: "fn main() {}\n".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blocks_out_of_place() {
+        let mut builder = Builder::new();
+        builder.start(Tag::List(None));
+        builder.push_block(Block::Rule);
+        builder.end(Tag::List(None));
+        builder.start(Tag::Paragraph);
+        builder.text("a ");
+        builder.push_block(Block::Code {
+            lang: None,
+            code: "b\n".to_string(),
+        });
+        builder.end(Tag::Paragraph);
+        let Some(Frame::Blocks(blocks)) = builder.stack.pop() else {
+            panic!("expected the document");
+        };
+        assert_eq!(
+            blocks,
+            vec![
+                Block::List {
+                    start: None,
+                    items: vec![vec![Block::Rule]],
+                },
+                Block::Paragraph(vec![Span {
+                    text: "a b".to_string(),
+                    style: SpanStyle::default(),
+                }]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(
+            sanitize("[click](javascript:alert(1)) and [ok](https://ok.com)"),
+            "click and [ok](https://ok.com)"
+        );
+        assert_eq!(sanitize("a <b>bold</b>\u{7}"), "a \\<b>bold\\</b>");
+        assert!(!to_plain_text(&sanitize("<script>x</script>")).is_empty());
+    }
+
+    #[test]
+    fn test_to_plain_text() {
+        assert_eq!(
+            to_plain_text("# title\n\n1. **a**\n2. [b](https://b.com)"),
+            "title\n1. a\n2. b (https://b.com)"
+        );
+    }
+}
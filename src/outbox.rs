@@ -0,0 +1,163 @@
+//! Messages written by the user that have not been acknowledged by the server yet.
+//!
+//! The outbox is mirrored to a json file, so messages typed while disconnected
+//! survive a restart of the client and are sent once the connection is back.
+//! The file keeps the messages of every name on every server apart, only the
+//! ones of the name and server the client connects with are sent.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::MessageData;
+use tracing::warn;
+
+pub struct Outbox {
+    path: PathBuf,
+    /// keyed by `<name>@<url>`
+    messages: BTreeMap<String, Vec<MessageData>>,
+    /// the key of the name and server in use
+    current: String,
+}
+
+impl Outbox {
+    /// load the outbox stored at `path`, a missing or broken file gives an empty outbox
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let messages = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("ignoring broken outbox {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                warn!("cannot read outbox {}: {}", path.display(), e);
+                BTreeMap::new()
+            }
+        };
+        Self {
+            path,
+            messages,
+            current: String::new(),
+        }
+    }
+
+    /// use the messages of the name on the server at `url` from now on
+    pub fn select(&mut self, url: &str, name: &str) {
+        self.current = key(url, name);
+    }
+
+    /// the default location of the outbox file
+    pub fn default_path() -> PathBuf {
+        dirs::data_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("websocket_chatroom")
+            .join("outbox.json")
+    }
+
+    pub fn messages(&self) -> &[MessageData] {
+        self.messages
+            .get(&self.current)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages().is_empty()
+    }
+
+    /// queue the message, a message with the same nonce is only stored once
+    pub fn push(&mut self, message: MessageData) {
+        let messages = self.messages.entry(self.current.clone()).or_default();
+        if messages.iter().all(|m| m.nonce != message.nonce) {
+            messages.push(message);
+            self.save();
+        }
+    }
+
+    /// drop the message after the server acked or rejected it
+    pub fn remove(&mut self, nonce: u64) {
+        let Some(messages) = self.messages.get_mut(&self.current) else {
+            return;
+        };
+        let len = messages.len();
+        messages.retain(|m| m.nonce != nonce);
+        if messages.len() != len {
+            if messages.is_empty() {
+                self.messages.remove(&self.current);
+            }
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = write_messages(&self.path, &self.messages) {
+            warn!("cannot write outbox {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn key(url: &str, name: &str) -> String {
+    format!("{name}@{url}")
+}
+
+fn write_messages(
+    path: &Path,
+    messages: &BTreeMap<String, Vec<MessageData>>,
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // write to a temporary file first so a crash never leaves a truncated outbox
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(messages)?)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(nonce: u64) -> MessageData {
+        MessageData {
+            id: 0,
+            name: "tester".to_string(),
+            data: format!("message {nonce}"),
+            nonce,
+            message_id: 0,
+            format: Default::default(),
+            kind: Default::default(),
+            attachment: None,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn test_outbox_survives_reload() {
+        let path = std::env::temp_dir().join(format!("outbox-test-{}.json", std::process::id()));
+        let mut outbox = Outbox::load(&path);
+        outbox.select("ws://localhost:8080", "tester");
+        outbox.push(message(1));
+        outbox.push(message(2));
+        outbox.push(message(1));
+        outbox.remove(2);
+        outbox.push(message(3));
+        outbox.select("ws://example.com", "tester");
+        outbox.push(message(4));
+
+        let mut reloaded = Outbox::load(&path);
+        assert!(reloaded.is_empty());
+        reloaded.select("ws://localhost:8080", "tester");
+        let nonces: Vec<_> = reloaded.messages().iter().map(|m| m.nonce).collect();
+        assert_eq!(nonces, vec![1, 3]);
+        reloaded.select("ws://localhost:8080", "other");
+        assert!(reloaded.is_empty());
+        reloaded.select("ws://example.com", "tester");
+        reloaded.remove(4);
+        assert_eq!(Outbox::load(&path).messages.len(), 1);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+//! In-process bots running inside the chat server.
+//!
+//! A [`ChatPlugin`] is told about users joining and leaving rooms and about
+//! every message, may veto messages before they are relayed, may offer slash
+//! commands and answers with [`PluginAction`]s the server carries out.
+//! Built-in commands take precedence over the ones of plugins. Plugins are
+//! registered in
+//! [`Plugins`] when the server starts. A plugin that panics is dropped, the
+//! connection that triggered it carries on.
+//!
+//! A plugin reminding a room of the stand-up once a day:
+//!
+//! ```
+//! use std::time::{Duration, SystemTime};
+//! use websocket_chatroom::plugin::{ChatPlugin, PluginActions};
+//!
+//! struct StandUp {
+//!     next: SystemTime,
+//! }
+//!
+//! impl ChatPlugin for StandUp {
+//!     fn name(&self) -> &str {
+//!         "standup"
+//!     }
+//!
+//!     fn tick(&mut self, now: SystemTime, actions: &mut PluginActions) {
+//!         if now >= self.next {
+//!             self.next += Duration::from_secs(24 * 60 * 60);
+//!             actions.say("team", "stand-up in 5 minutes");
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    time::SystemTime,
+};
+
+use tracing::error;
+
+use crate::MessageData;
+
+/// something that happened in a room
+#[derive(Debug, Clone, Copy)]
+pub enum ChatEvent<'a> {
+    Joined {
+        room: &'a str,
+        user: &'a str,
+    },
+    Left {
+        room: &'a str,
+        user: &'a str,
+    },
+    /// a user in the room changed their name
+    Renamed {
+        room: &'a str,
+        old: &'a str,
+        new: &'a str,
+    },
+    /// a message relayed to the room
+    Message {
+        room: &'a str,
+        message: &'a MessageData,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginAction {
+    /// send a message to everyone in the room
+    Say { room: String, text: String },
+    /// send a private message to the user
+    Reply { user: String, text: String },
+}
+
+/// actions paired with the name of the plugin that took them
+pub type NamedActions = Vec<(String, PluginAction)>;
+
+/// a slash command offered by a plugin, listed by `/help`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginCommand {
+    /// the name without the slash
+    pub name: String,
+    pub usage: String,
+    pub help: String,
+}
+
+/// the actions of a plugin, carried out by the server once the plugin returns
+#[derive(Debug, Default)]
+pub struct PluginActions {
+    actions: Vec<PluginAction>,
+}
+
+impl PluginActions {
+    pub fn say(&mut self, room: &str, text: impl Into<String>) {
+        self.actions.push(PluginAction::Say {
+            room: room.to_string(),
+            text: text.into(),
+        });
+    }
+
+    pub fn reply(&mut self, user: &str, text: impl Into<String>) {
+        self.actions.push(PluginAction::Reply {
+            user: user.to_string(),
+            text: text.into(),
+        });
+    }
+}
+
+pub trait ChatPlugin: Send {
+    /// the name the messages of the plugin are sent with, as `bot:<name>`
+    fn name(&self) -> &str;
+
+    /// called before a message is relayed, `Err` refuses it with the reason
+    fn check(&mut self, _room: &str, _message: &MessageData) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, _event: ChatEvent, _actions: &mut PluginActions) {}
+
+    fn commands(&self) -> Vec<PluginCommand> {
+        Vec::new()
+    }
+
+    /// run one of the [`ChatPlugin::commands`] for the user in the room, the
+    /// text is shown to the user only, `None` if the plugin has no such command
+    fn on_command(
+        &mut self,
+        _room: &str,
+        _user: &str,
+        _name: &str,
+        _args: &str,
+        _actions: &mut PluginActions,
+    ) -> Option<Result<String, String>> {
+        None
+    }
+
+    /// called about once a second, e.g. for reminders
+    fn tick(&mut self, _now: SystemTime, _actions: &mut PluginActions) {}
+}
+
+/// the plugins of the server
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Box<dyn ChatPlugin>>,
+}
+
+impl Plugins {
+    pub fn register(&mut self, plugin: impl ChatPlugin + 'static) {
+        self.plugins.push(Box::new(plugin));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// ask every plugin whether the message may be relayed
+    pub fn check(&mut self, room: &str, message: &MessageData) -> Result<(), String> {
+        let mut verdict = Ok(());
+        self.run(|plugin| {
+            if verdict.is_ok() {
+                verdict = plugin
+                    .check(room, message)
+                    .map_err(|reason| format!("{}: {}", plugin.name(), reason));
+            }
+        });
+        verdict
+    }
+
+    /// tell every plugin about the event
+    pub fn on_event(&mut self, event: ChatEvent) -> NamedActions {
+        self.collect(|plugin, actions| plugin.on_event(event, actions))
+    }
+
+    /// the commands of all plugins
+    pub fn commands(&mut self) -> Vec<PluginCommand> {
+        let mut commands = Vec::new();
+        self.run(|plugin| commands.extend(plugin.commands()));
+        commands
+    }
+
+    pub fn has_command(&mut self, name: &str) -> bool {
+        self.commands().iter().any(|command| command.name == name)
+    }
+
+    /// run the command with the first plugin that knows it, `None` if none does
+    pub fn command(
+        &mut self,
+        room: &str,
+        user: &str,
+        name: &str,
+        args: &str,
+    ) -> Option<(Result<String, String>, NamedActions)> {
+        let mut result = None;
+        let actions = self.collect(|plugin, actions| {
+            if result.is_none() {
+                result = plugin.on_command(room, user, name, args, actions);
+            }
+        });
+        result.map(|result| (result, actions))
+    }
+
+    pub fn tick(&mut self, now: SystemTime) -> NamedActions {
+        self.collect(|plugin, actions| plugin.tick(now, actions))
+    }
+
+    fn collect(
+        &mut self,
+        mut f: impl FnMut(&mut dyn ChatPlugin, &mut PluginActions),
+    ) -> NamedActions {
+        let mut all = Vec::new();
+        self.run(|plugin| {
+            let mut actions = PluginActions::default();
+            f(plugin, &mut actions);
+            let name = plugin.name().to_string();
+            all.extend(
+                actions
+                    .actions
+                    .into_iter()
+                    .map(|action| (name.clone(), action)),
+            );
+        });
+        all
+    }
+
+    /// run `f` for every plugin, dropping the plugins that panic
+    fn run(&mut self, mut f: impl FnMut(&mut dyn ChatPlugin)) {
+        self.plugins.retain_mut(|plugin| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(plugin.as_mut())));
+            if result.is_err() {
+                error!("the plugin {} panicked and is disabled", plugin.name());
+            }
+            result.is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl ChatPlugin for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn check(&mut self, _room: &str, message: &MessageData) -> Result<(), String> {
+            match message.data.as_str() {
+                "boom" => panic!("boom"),
+                "veto" => Err("no".to_string()),
+                _ => Ok(()),
+            }
+        }
+
+        fn on_event(&mut self, event: ChatEvent, actions: &mut PluginActions) {
+            match event {
+                ChatEvent::Joined { room, user } => actions.say(room, format!("hi {user}")),
+                ChatEvent::Message { message, .. } => actions.reply(&message.name, &message.data),
+                ChatEvent::Left { .. } | ChatEvent::Renamed { .. } => {}
+            }
+        }
+
+        fn commands(&self) -> Vec<PluginCommand> {
+            vec![PluginCommand {
+                name: "echo".to_string(),
+                usage: "/echo <text>".to_string(),
+                help: "repeat the text".to_string(),
+            }]
+        }
+
+        fn on_command(
+            &mut self,
+            _room: &str,
+            _user: &str,
+            name: &str,
+            args: &str,
+            _actions: &mut PluginActions,
+        ) -> Option<Result<String, String>> {
+            (name == "echo").then(|| Ok(args.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_plugins() {
+        let mut plugins = Plugins::default();
+        plugins.register(Echo);
+        let message = |data: &str| MessageData {
+            name: "bob".to_string(),
+            data: data.to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            plugins.on_event(ChatEvent::Joined {
+                room: "lobby",
+                user: "bob"
+            }),
+            [(
+                "echo".to_string(),
+                PluginAction::Say {
+                    room: "lobby".to_string(),
+                    text: "hi bob".to_string()
+                }
+            )]
+        );
+        assert!(plugins.check("lobby", &message("hello")).is_ok());
+        assert_eq!(plugins.commands().len(), 1);
+        assert_eq!(
+            plugins.command("lobby", "bob", "echo", "hi").unwrap().0,
+            Ok("hi".to_string())
+        );
+        assert!(plugins.command("lobby", "bob", "nope", "").is_none());
+        assert_eq!(
+            plugins.check("lobby", &message("veto")).unwrap_err(),
+            "echo: no"
+        );
+        // the panic is caught and the plugin dropped
+        assert!(plugins.check("lobby", &message("boom")).is_ok());
+        assert!(plugins.is_empty());
+    }
+}
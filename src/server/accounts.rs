@@ -0,0 +1,98 @@
+//! Names that only the holder of their token may connect under.
+//!
+//! Roles are given by name, so a name is registered as soon as it holds a role
+//! above member: when the server starts for the names given a role on the
+//! command line, otherwise when its user creates a room or is given a role.
+//! The random token of the name is sent to the user once with
+//! `WebSocketServerToClientMessage::Token`, and from then on the name is only
+//! accepted with a `Login` carrying that token. Only the sha-256 of the tokens
+//! is written to a json file.
+
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+use tracing::warn;
+
+use crate::transfer;
+
+use super::api::same;
+
+#[derive(Default)]
+pub struct Accounts {
+    /// where the accounts are saved, `None` keeps them in memory only
+    path: Option<PathBuf>,
+    /// the sha-256 of the token, keyed by name
+    hashes: BTreeMap<String, String>,
+}
+
+impl Accounts {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let hashes = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path),
+            hashes,
+        })
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.hashes.contains_key(name)
+    }
+
+    /// register the name with a new token, `None` if it is registered already
+    pub fn register(&mut self, name: &str) -> Option<String> {
+        if self.is_registered(name) {
+            return None;
+        }
+        let token = format!("{:032x}", rand::random::<u128>());
+        self.hashes
+            .insert(name.to_string(), transfer::sha256_hex(token.as_bytes()));
+        self.save();
+        Some(token)
+    }
+
+    /// whether the token is the one of the registered name
+    pub fn verify(&self, name: &str, token: &str) -> bool {
+        self.hashes
+            .get(name)
+            .is_some_and(|hash| same(hash, &transfer::sha256_hex(token.as_bytes())))
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&self.hashes)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp, content))
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(e) = result {
+            warn!("cannot write the accounts: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accounts() {
+        let path = std::env::temp_dir().join(format!("accounts-test-{}.json", std::process::id()));
+        let mut accounts = Accounts::open(&path).unwrap();
+        let token = accounts.register("alice").unwrap();
+        assert!(accounts.register("alice").is_none());
+        assert!(accounts.verify("alice", &token));
+        assert!(!accounts.verify("alice", "0123456789abcdef"));
+        assert!(!accounts.verify("bob", &token));
+
+        let accounts = Accounts::open(&path).unwrap();
+        assert!(accounts.is_registered("alice") && !accounts.is_registered("bob"));
+        assert!(accounts.verify("alice", &token));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! The REST API next to the WebSocket, for scripts and dashboards that should
+//! not hold a connection open.
+//!
+//! Every request is authenticated with a key sent as `Authorization: Bearer
+//! <key>`. The keys are read from `api_keys.json` in the data directory, again
+//! whenever it changes, and every key posts as the bot it is given to, named
+//! `bot:<name>` so it cannot pass for a user:
+//!
+//! ```json
+//! [
+//!     {"name": "ci", "key": "b1946ac92492d2347c6235b4d2611184"}
+//! ]
+//! ```
+//!
+//! - `GET /api/users`: the users online, as in `AllUsers`
+//! - `GET /api/rooms`: the rooms listed for the bot, as in `RoomList`
+//! - `GET /api/rooms/<room>/messages?limit=<n>`: the last messages relayed to
+//!   the room, oldest first, 50 by default
+//! - `POST /api/rooms/<room>/messages` with `{"text": "...", "format":
+//!   "Markdown"}`: post as the bot, answered with `{"message_id": <id>}`
+//!
+//! Bots read and post in public rooms and the rooms they are a member of,
+//! their messages pass the content filter like everyone else's. Failures are
+//! answered with `{"error": "<reason>"}`.
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{command, MessageData, MessageFormat};
+
+use super::{
+    bots::{bot_name, BOT_ID},
+    http::{self, Request, Response},
+    relay, relayed, ServerState,
+};
+
+/// how often the key file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// the shortest key accepted, in characters
+const MIN_KEY_LENGTH: usize = 16;
+/// how many messages of the history are sent without a `limit`
+const DEFAULT_HISTORY: usize = 50;
+
+#[derive(Debug, Clone, Deserialize)]
+struct ApiKey {
+    /// the bot the messages posted with the key are attributed to
+    name: String,
+    key: String,
+}
+
+impl ApiKey {
+    fn check(&self) -> Result<(), String> {
+        if !command::is_valid_name(&self.name) {
+            return Err(format!("{:?} is not a valid name", self.name));
+        }
+        if self.key.chars().count() < MIN_KEY_LENGTH {
+            return Err(format!(
+                "the key of {} is shorter than {MIN_KEY_LENGTH} characters",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct ApiKeys {
+    path: PathBuf,
+    keys: Vec<ApiKey>,
+    /// given when the server was built, not read from the file
+    fixed: Vec<ApiKey>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl ApiKeys {
+    /// read the keys, a missing file is read once it is created
+    pub fn open(path: impl Into<PathBuf>, fixed: Vec<(String, String)>) -> io::Result<Self> {
+        let fixed = fixed
+            .into_iter()
+            .map(|(name, key)| ApiKey { name, key })
+            .collect::<Vec<_>>();
+        for api_key in &fixed {
+            api_key
+                .check()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        let mut keys = Self {
+            path: path.into(),
+            keys: Vec::new(),
+            fixed,
+            modified: None,
+            checked: Instant::now(),
+        };
+        keys.load()?;
+        Ok(keys)
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        let modified = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.keys.clear();
+                self.modified = None;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let keys: Vec<ApiKey> = serde_json::from_str(&fs::read_to_string(&self.path)?)?;
+        for api_key in &keys {
+            api_key
+                .check()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        self.keys = keys;
+        self.modified = modified;
+        info!("read {} api keys", self.keys.len());
+        Ok(())
+    }
+
+    /// read the keys again if the file changed, keeping the old ones if it is broken
+    fn reload(&mut self) {
+        if self.checked.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+        let modified = fs::metadata(&self.path)
+            .ok()
+            .and_then(|metadata| metadata.modified().ok());
+        if modified == self.modified {
+            return;
+        }
+        if let Err(e) = self.load() {
+            warn!("cannot read the api keys: {}", e);
+            self.modified = modified;
+        }
+    }
+
+    /// the name of the bot the key is given to, `bot:<name>`
+    pub fn bot(&mut self, key: &str) -> Option<String> {
+        self.reload();
+        self.fixed
+            .iter()
+            .chain(&self.keys)
+            .find(|api_key| same(&api_key.key, key))
+            .map(|api_key| bot_name(&api_key.name))
+    }
+}
+
+/// compare in constant time, so how long a wrong key takes tells nothing about the right one
+pub fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// the body of a post
+#[derive(Deserialize)]
+struct Post {
+    text: String,
+    #[serde(default)]
+    format: MessageFormat,
+}
+
+/// answer a request to `/api/...`, the body is read already
+pub fn handle(state: &ServerState, request: &Request) -> Response {
+    let Some(key) = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return Response::error(401, "send an api key as `Authorization: Bearer <key>`");
+    };
+    let Some(bot) = state.api_keys.lock().unwrap().bot(key.trim()) else {
+        return Response::error(401, "unknown api key");
+    };
+    let segments: Vec<&str> = request
+        .path
+        .trim_start_matches("/api/")
+        .split('/')
+        .collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["users"]) => users(state),
+        ("GET", ["rooms"]) => Response::json(200, &state.rooms.lock().unwrap().list(&bot)),
+        ("GET", ["rooms", room, "messages"]) => with_room(state, &bot, room, |room| {
+            history(state, room, request.param("limit"))
+        }),
+        ("POST", ["rooms", room, "messages"]) => with_room(state, &bot, room, |room| {
+            post(state, &bot, room, &request.body)
+        }),
+        (_, ["users"] | ["rooms"] | ["rooms", _, "messages"]) => {
+            Response::error(405, "method not allowed")
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn users(state: &ServerState) -> Response {
+    let users: Vec<(u32, String)> = state
+        .peers
+        .lock()
+        .unwrap()
+        .values()
+        .map(|peer| (peer.id, peer.name.clone()))
+        .collect();
+    Response::json(200, &users)
+}
+
+/// answer with `f` if the bot may read and post in the room, the name of the room is percent-encoded
+fn with_room(
+    state: &ServerState,
+    bot: &str,
+    room: &str,
+    f: impl FnOnce(&str) -> Response,
+) -> Response {
+    let room = http::decode(room).unwrap_or_default();
+    let open = state
+        .rooms
+        .lock()
+        .unwrap()
+        .get(&room)
+        .is_some_and(|info| info.is_open_to(bot));
+    if !open {
+        return Response::error(404, format!("no room {room}"));
+    }
+    f(&room)
+}
+
+fn history(state: &ServerState, room: &str, limit: Option<&str>) -> Response {
+    let limit = match limit.map(str::parse) {
+        None => DEFAULT_HISTORY,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return Response::error(400, "the limit is not a number"),
+    };
+    Response::json(200, &state.reports.lock().unwrap().history(room, limit))
+}
+
+/// relay the post in the body as a message of the bot, also used by the
+/// webhooks, `bot` is the name with its `bot:` prefix
+pub fn post(state: &ServerState, bot: &str, room: &str, body: &[u8]) -> Response {
+    let post: Post = match serde_json::from_slice(body) {
+        Ok(post) => post,
+        Err(e) => return Response::error(400, format!("not a post: {e}")),
+    };
+    if post.text.trim().is_empty() {
+        return Response::error(400, "the text is empty");
+    }
+    let peers = state.peers.lock().unwrap();
+    if let Some(reason) = state.bans.lock().unwrap().muted(bot) {
+        return Response::error(403, reason);
+    }
+    let message = MessageData {
+        id: BOT_ID,
+        name: bot.to_string(),
+        data: post.text,
+        format: post.format,
+        ..Default::default()
+    };
+    match relay(state, &peers, room, None, message) {
+        Ok(message) => {
+            relayed(state, &peers, room, &message);
+            Response::json(
+                200,
+                &serde_json::json!({ "message_id": message.message_id }),
+            )
+        }
+        Err(reason) => Response::error(403, reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let path = std::env::temp_dir().join(format!("api-keys-test-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"[{"name": "dashboard", "key": "0123456789abcdef"}]"#,
+        )
+        .unwrap();
+        let fixed = vec![("ci".to_string(), "fedcba9876543210".to_string())];
+        let mut keys = ApiKeys::open(&path, fixed).unwrap();
+        assert_eq!(
+            keys.bot("0123456789abcdef").as_deref(),
+            Some("bot:dashboard")
+        );
+        assert_eq!(keys.bot("fedcba9876543210").as_deref(), Some("bot:ci"));
+        assert_eq!(keys.bot("0123456789abcdeF"), None);
+        assert_eq!(keys.bot(""), None);
+
+        fs::write(&path, r#"[{"name": "dashboard", "key": "short"}]"#).unwrap();
+        assert!(ApiKeys::open(&path, Vec::new()).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,412 @@
+//! Storage of uploaded files.
+//!
+//! Files are stored under their sha-256, so the same content uploaded twice is
+//! only stored once. An index next to the files keeps the name, the mime type
+//! and the uploaders of every file; every uploader is charged for the file in
+//! their quota, and so are the uploads in progress. Images get a png thumbnail
+//! that is stored the same way.
+//!
+//! The index also records the rooms every file was posted to, a file is only
+//! sent to its uploader and the users who may read one of these rooms.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io::{self, Cursor, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{transfer, Attachment, Thumbnail};
+use image::{io::Reader as ImageReader, ImageFormat, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// the largest width and height of a thumbnail
+const THUMBNAIL_SIZE: u32 = 256;
+/// images with a larger width or height are not decoded for a thumbnail
+const MAX_IMAGE_DIMENSION: u32 = 16384;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct StoredAttachment {
+    file_name: String,
+    size: u64,
+    mime: String,
+    /// everyone who uploaded the content, empty for thumbnails, which do not
+    /// count towards any quota
+    #[serde(default)]
+    uploaders: BTreeSet<String>,
+    #[serde(default)]
+    thumbnail: Option<Thumbnail>,
+    /// the rooms the file was posted to, the ones of the image for a thumbnail
+    #[serde(default)]
+    rooms: BTreeSet<String>,
+}
+
+pub struct AttachmentStore {
+    dir: PathBuf,
+    /// the largest file a user may upload
+    max_file_size: u64,
+    /// the total size of the files a single user may upload
+    quota: u64,
+    /// sha-256 to the stored file
+    index: HashMap<String, StoredAttachment>,
+    /// the sizes of the uploads in progress, keyed by user
+    reserved: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+/// an upload in progress, written to a temporary file until it is finished
+pub struct Upload {
+    file_name: String,
+    size: u64,
+    mime: String,
+    received: u64,
+    hasher: Sha256,
+    file: File,
+    tmp_path: PathBuf,
+    /// the uploader, whose quota holds `size` until the upload is dropped
+    user: String,
+    reserved: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Upload {
+    pub fn write_chunk(&mut self, data: &[u8]) -> Result<(), String> {
+        self.received += data.len() as u64;
+        if self.received > self.size {
+            return Err("more data than announced".to_string());
+        }
+        self.hasher.update(data);
+        self.file.write_all(data).map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // finished uploads are renamed away, this only cleans up aborted ones
+        let _ = fs::remove_file(&self.tmp_path);
+        let mut reserved = self.reserved.lock().unwrap();
+        if let Some(size) = reserved.get_mut(&self.user) {
+            *size -= self.size;
+            if *size == 0 {
+                reserved.remove(&self.user);
+            }
+        }
+    }
+}
+
+fn is_sha256(sha256: &str) -> bool {
+    sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl AttachmentStore {
+    pub fn open(dir: impl Into<PathBuf>, max_file_size: u64, quota: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("tmp"))?;
+        let index = match fs::read_to_string(dir.join("index.json")) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            dir,
+            max_file_size,
+            quota,
+            index,
+            reserved: Arc::default(),
+        })
+    }
+
+    /// the size of the files of the user and of their uploads in progress
+    fn used_by(&self, user: &str) -> u64 {
+        let stored: u64 = self
+            .index
+            .values()
+            .filter(|stored| stored.uploaders.contains(user))
+            .map(|stored| stored.size)
+            .sum();
+        stored
+            + self
+                .reserved
+                .lock()
+                .unwrap()
+                .get(user)
+                .copied()
+                .unwrap_or(0)
+    }
+
+    /// check the size limits and create the temporary file of the upload, the
+    /// size is held in the quota of the user until the upload is dropped
+    pub fn begin(
+        &self,
+        user: &str,
+        upload_id: u64,
+        file_name: &str,
+        size: u64,
+        mime: &str,
+    ) -> Result<Upload, String> {
+        if size > self.max_file_size {
+            return Err(format!(
+                "file is larger than the limit of {} bytes",
+                self.max_file_size
+            ));
+        }
+        if self.used_by(user).saturating_add(size) > self.quota {
+            return Err(format!("upload quota of {} bytes is exceeded", self.quota));
+        }
+        let tmp_path = self.dir.join("tmp").join(format!(
+            "{}-{}",
+            transfer::sanitize_file_name(user),
+            upload_id
+        ));
+        let file = File::create(&tmp_path).map_err(|e| e.to_string())?;
+        *self
+            .reserved
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_default() += size;
+        Ok(Upload {
+            file_name: transfer::sanitize_file_name(file_name),
+            size,
+            mime: mime.to_string(),
+            received: 0,
+            hasher: Sha256::new(),
+            file,
+            tmp_path,
+            user: user.to_string(),
+            reserved: self.reserved.clone(),
+        })
+    }
+
+    /// verify the upload against the hash announced by the client and store it,
+    /// content that is stored already is charged to the user as well
+    pub fn finish(
+        &mut self,
+        user: &str,
+        mut upload: Upload,
+        sha256: &str,
+    ) -> Result<Attachment, String> {
+        if upload.received != upload.size {
+            return Err(format!(
+                "received {} of {} bytes",
+                upload.received, upload.size
+            ));
+        }
+        let actual = format!("{:x}", std::mem::take(&mut upload.hasher).finalize());
+        if actual != sha256 {
+            return Err("sha-256 does not match the content".to_string());
+        }
+        upload.file.flush().map_err(|e| e.to_string())?;
+        let path = self.dir.join(&actual);
+        if !self.index.contains_key(&actual) {
+            fs::rename(&upload.tmp_path, &path).map_err(|e| e.to_string())?;
+            // trust the content over the mime type claimed by the client
+            let content = fs::read(&path).map_err(|e| e.to_string())?;
+            let format = image::guess_format(&content).ok().filter(|format| {
+                matches!(
+                    format,
+                    ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP
+                )
+            });
+            let (mime, thumbnail) = match format {
+                Some(format) => (
+                    format.to_mime_type().to_string(),
+                    self.store_thumbnail(&content, format),
+                ),
+                None if upload.mime.starts_with("image/") => {
+                    ("application/octet-stream".to_string(), None)
+                }
+                None => (upload.mime.clone(), None),
+            };
+            self.index.insert(
+                actual.clone(),
+                StoredAttachment {
+                    file_name: upload.file_name.clone(),
+                    size: upload.size,
+                    mime,
+                    uploaders: BTreeSet::new(),
+                    thumbnail,
+                    rooms: BTreeSet::new(),
+                },
+            );
+        }
+        let stored = self.index.get_mut(&actual).unwrap();
+        if stored.uploaders.insert(user.to_string()) {
+            self.save_index();
+        }
+        let stored = &self.index[&actual];
+        Ok(Attachment {
+            sha256: actual.clone(),
+            file_name: upload.file_name.clone(),
+            size: upload.size,
+            mime: stored.mime.clone(),
+            thumbnail: stored.thumbnail.clone(),
+        })
+    }
+
+    /// scale the image down to fit in [`THUMBNAIL_SIZE`] and store it as png,
+    /// images that cannot be decoded simply get no thumbnail
+    fn store_thumbnail(&mut self, content: &[u8], format: ImageFormat) -> Option<Thumbnail> {
+        let mut reader = ImageReader::with_format(Cursor::new(content), format);
+        let mut limits = image::io::Limits::default();
+        limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+        limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+        reader.limits(limits);
+        let image = match reader.decode() {
+            Ok(image) => image,
+            Err(e) => {
+                warn!("cannot decode image for a thumbnail: {}", e);
+                return None;
+            }
+        };
+        let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+        let mut png = Vec::new();
+        thumbnail
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .ok()?;
+        let sha256 = transfer::sha256_hex(&png);
+        if !self.index.contains_key(&sha256) {
+            fs::write(self.dir.join(&sha256), &png).ok()?;
+            self.index.insert(
+                sha256.clone(),
+                StoredAttachment {
+                    file_name: "thumbnail.png".to_string(),
+                    size: png.len() as u64,
+                    mime: "image/png".to_string(),
+                    uploaders: BTreeSet::new(),
+                    thumbnail: None,
+                    rooms: BTreeSet::new(),
+                },
+            );
+        }
+        Some(Thumbnail {
+            sha256,
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+        })
+    }
+
+    /// record that the file was posted to the room, so the users of the room may download it
+    pub fn posted(&mut self, sha256: &str, room: &str) {
+        let Some(stored) = self.index.get_mut(sha256) else {
+            return;
+        };
+        if !stored.rooms.insert(room.to_string()) {
+            return;
+        }
+        let thumbnail = stored
+            .thumbnail
+            .as_ref()
+            .map(|thumbnail| thumbnail.sha256.clone());
+        if let Some(stored) = thumbnail.and_then(|sha256| self.index.get_mut(&sha256)) {
+            stored.rooms.insert(room.to_string());
+        }
+        self.save_index();
+    }
+
+    /// the path and size of a stored file if the user uploaded it or may read
+    /// one of the rooms it was posted to, as told by `may_read`
+    pub fn file(
+        &self,
+        sha256: &str,
+        user: &str,
+        may_read: impl Fn(&str) -> bool,
+    ) -> Result<(PathBuf, u64), String> {
+        let stored = Some(sha256)
+            .filter(|sha256| is_sha256(sha256))
+            .and_then(|sha256| self.index.get(sha256))
+            .filter(|stored| {
+                stored.uploaders.contains(user) || stored.rooms.iter().any(|room| may_read(room))
+            })
+            .ok_or_else(|| "no such attachment".to_string())?;
+        Ok((self.dir.join(sha256), stored.size))
+    }
+
+    fn save_index(&self) {
+        let result = serde_json::to_vec(&self.index)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(self.dir.join("index.json"), content));
+        if let Err(e) = result {
+            warn!("cannot write the attachment index: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_and_quota() {
+        let dir = std::env::temp_dir().join(format!("attachments-test-{}", std::process::id()));
+        let mut store = AttachmentStore::open(&dir, 10, 15).unwrap();
+
+        assert!(store.begin("a", 1, "big.bin", 11, "").is_err());
+        let mut upload = store.begin("a", 1, "../a.txt", 8, "text/plain").unwrap();
+        upload.write_chunk(b"12345").unwrap();
+        upload.write_chunk(b"678").unwrap();
+        let attachment = store
+            .finish("a", upload, &transfer::sha256_hex(b"12345678"))
+            .unwrap();
+        assert_eq!(attachment.file_name, "a.txt");
+        let (path, size) = store.file(&attachment.sha256, "a", |_| false).unwrap();
+        assert_eq!((fs::read(path).unwrap(), size), (b"12345678".to_vec(), 8));
+        // only the uploader may download it until it is posted
+        let open = |room: &str| room == "lobby";
+        assert!(store.file(&attachment.sha256, "b", open).is_err());
+        store.posted(&attachment.sha256, "secret");
+        assert!(store.file(&attachment.sha256, "b", open).is_err());
+        store.posted(&attachment.sha256, "lobby");
+        assert!(store.file(&attachment.sha256, "b", open).is_ok());
+
+        // 8 of the 15 bytes are used
+        assert!(store.begin("a", 2, "b.txt", 8, "").is_err());
+        let mut upload = store.begin("b", 2, "b.txt", 3, "").unwrap();
+        upload.write_chunk(b"abc").unwrap();
+        assert!(store.finish("b", upload, "wrong").is_err());
+        assert!(store.file("../index.json", "a", |_| true).is_err());
+
+        // uploads in progress hold their size until they are dropped
+        let first = store.begin("b", 3, "c.txt", 10, "").unwrap();
+        assert!(store.begin("b", 4, "d.txt", 10, "").is_err());
+        drop(first);
+        // the same content is charged to every uploader
+        let mut upload = store.begin("b", 5, "copy.txt", 8, "").unwrap();
+        upload.write_chunk(b"12345678").unwrap();
+        store
+            .finish("b", upload, &transfer::sha256_hex(b"12345678"))
+            .unwrap();
+        assert!(store.begin("b", 6, "e.txt", 8, "").is_err());
+        assert!(store.begin("b", 6, "e.txt", 7, "").is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_image_thumbnail() {
+        let dir = std::env::temp_dir().join(format!("thumbnail-test-{}", std::process::id()));
+        let mut store = AttachmentStore::open(&dir, 1 << 20, 1 << 20).unwrap();
+        let mut png = Vec::new();
+        image::RgbImage::new(1024, 512)
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+
+        // the mime type claimed by the client is ignored
+        let mut upload = store
+            .begin("a", 1, "picture", png.len() as u64, "text/plain")
+            .unwrap();
+        upload.write_chunk(&png).unwrap();
+        let attachment = store
+            .finish("a", upload, &transfer::sha256_hex(&png))
+            .unwrap();
+        assert_eq!(attachment.mime, "image/png");
+        let thumbnail = attachment.thumbnail.unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (256, 128));
+        assert!(store.file(&thumbnail.sha256, "b", |_| true).is_err());
+        store.posted(&attachment.sha256, "lobby");
+        assert!(store.file(&thumbnail.sha256, "b", |_| true).is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! Carrying out the actions of the plugins (see [`crate::plugin`]).
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    plugin::{NamedActions, PluginAction},
+    MessageData, WebSocketServerToClientMessage,
+};
+
+use super::{reports::Reports, send, Peer};
+
+/// the user id the messages of plugins are sent with, never given to a connection
+pub const BOT_ID: u32 = u32::MAX;
+
+/// the name a bot posts with, `bot:<name>`, which no user can take as `:` is
+/// not allowed in their names
+pub fn bot_name(name: &str) -> String {
+    format!("bot:{name}")
+}
+
+/// send the messages of the plugins, the actions are paired with the name of the plugin
+pub fn perform(
+    peers: &HashMap<SocketAddr, Peer>,
+    message_counter: &AtomicU64,
+    reports: &mut Reports,
+    actions: NamedActions,
+) {
+    for (name, action) in actions {
+        let mut message = MessageData {
+            id: BOT_ID,
+            name: bot_name(&name),
+            message_id: message_counter.fetch_add(1, Ordering::Relaxed),
+            ..Default::default()
+        };
+        match action {
+            PluginAction::Say { room, text } => {
+                message.data = text;
+                reports.record(&room, &message);
+                let message = WebSocketServerToClientMessage::UserMessage(message);
+                for peer in peers.values().filter(|peer| peer.room == room) {
+                    send(&peer.tx, &message);
+                }
+            }
+            PluginAction::Reply { user, text } => {
+                let Some(peer) = peers.values().find(|peer| peer.name == user) else {
+                    continue;
+                };
+                message.data = text;
+                message.recipient = Some(user);
+                send(
+                    &peer.tx,
+                    &WebSocketServerToClientMessage::UserMessage(message),
+                );
+            }
+        }
+    }
+}
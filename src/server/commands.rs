@@ -0,0 +1,1282 @@
+//! Slash commands run by the server.
+//!
+//! Every command is registered in [`Commands`] with its usage and a line of
+//! help, so a new command is added by registering it instead of extending the
+//! message handling of the connections. `/help` is generated from the registry.
+//! A command only everyone may run is restricted to the [`Permission`] it needs.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    command::{self, LOBBY},
+    plugin::{ChatEvent, Plugins},
+    MessageData, MessageKind, MessageReport, ReportAction, Role, RoomVisibility,
+    WebSocketServerToClientMessage,
+};
+
+use super::{
+    accounts::Accounts,
+    bots, close,
+    filter::ContentFilter,
+    flag,
+    moderation::{self, BanTarget, Moderation},
+    permissions::{self, Permission},
+    reports::Reports,
+    rooms::{RoomRegistry, MAX_TOPIC_LENGTH},
+    screen,
+    scripts::ScriptRunner,
+    send, system_message,
+    webhooks::Webhook,
+    Peer, FILTER_REPORTER,
+};
+
+/// the server state a command may look at and change
+pub struct Context<'a> {
+    /// the connection running the command
+    pub addr: SocketAddr,
+    pub peers: &'a mut HashMap<SocketAddr, Peer>,
+    pub rooms: &'a mut RoomRegistry,
+    pub moderation: &'a mut Moderation,
+    pub reports: &'a mut Reports,
+    pub plugins: &'a mut Plugins,
+    pub scripts: &'a ScriptRunner,
+    pub accounts: &'a mut Accounts,
+    pub filter: &'a mut ContentFilter,
+    pub message_counter: &'a AtomicU64,
+    pub commands: &'a Commands,
+}
+
+impl Context<'_> {
+    pub fn peer(&self) -> &Peer {
+        &self.peers[&self.addr]
+    }
+
+    pub fn peer_mut(&mut self) -> &mut Peer {
+        self.peers.get_mut(&self.addr).unwrap()
+    }
+
+    /// the role of the user in the room they are in
+    pub fn role(&self) -> Role {
+        let peer = self.peer();
+        self.rooms.role(&peer.room, &peer.name)
+    }
+
+    /// fail if the user is muted
+    pub fn check_muted(&self) -> Result<(), String> {
+        match self.moderation.muted(&self.peer().name) {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+
+    /// send the message to every user in the room
+    pub fn broadcast(&self, room: &str, message: &WebSocketServerToClientMessage) {
+        for peer in self.peers.values().filter(|peer| peer.room == room) {
+            send(&peer.tx, message);
+        }
+    }
+
+    pub fn next_message_id(&self) -> u64 {
+        self.message_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// show a system line to every user in the room
+    pub fn system(&self, room: &str, text: String) {
+        self.broadcast(room, &system_message(text, self.next_message_id()));
+    }
+
+    /// register the name if it holds a role above member in the room now,
+    /// sending the token to the user if they are online
+    pub fn protect(&mut self, room: &str, user: &str) {
+        if self.rooms.role(room, user) <= Role::Member {
+            return;
+        }
+        let Some(token) = self.accounts.register(user) else {
+            return;
+        };
+        if let Some(peer) = self.peers.values().find(|peer| peer.name == user) {
+            send(&peer.tx, &WebSocketServerToClientMessage::Token(token));
+        }
+    }
+
+    /// tell the plugins about the event and carry out what they do about it,
+    /// the scripts are told to act on their own
+    pub fn plugin_event(&mut self, event: ChatEvent) {
+        let actions = self.plugins.on_event(event);
+        bots::perform(self.peers, self.message_counter, self.reports, actions);
+        self.scripts.event(event);
+    }
+
+    /// run a command of the plugins in the room of the user, `None` if no plugin has it
+    pub fn plugin_command(&mut self, name: &str, args: &str) -> Option<CommandResult> {
+        let peer = self.peer();
+        let (room, user) = (peer.room.clone(), peer.name.clone());
+        let (result, actions) = self.plugins.command(&room, &user, name, args)?;
+        bots::perform(self.peers, self.message_counter, self.reports, actions);
+        Some(result)
+    }
+}
+
+/// the text shown to the user running the command, or why it failed
+pub type CommandResult = Result<String, String>;
+
+type Handler = Box<dyn Fn(&mut Context, &str) -> CommandResult + Send + Sync>;
+
+struct Command {
+    usage: String,
+    help: String,
+    permission: Permission,
+    handler: Handler,
+}
+
+#[derive(Default)]
+pub struct Commands {
+    commands: BTreeMap<String, Command>,
+}
+
+impl Commands {
+    /// the registry with all commands of the server
+    pub fn with_builtins() -> Self {
+        let mut commands = Self::default();
+        commands.register("nick", "/nick <name>", "change your name", nick);
+        commands.register(
+            "join",
+            "/join <room> [password]",
+            "switch to the room",
+            join,
+        );
+        commands.register("leave", "/leave", "go back to the lobby", leave);
+        commands.register(
+            "msg",
+            "/msg <user> <text>",
+            "send a private message",
+            private_message,
+        );
+        commands.register("me", "/me <action>", "describe what you are doing", me);
+        commands.register(
+            "topic",
+            "/topic [text]",
+            "show or set the topic of the room",
+            topic,
+        );
+        commands.register(
+            "describe",
+            "/describe <text>",
+            "set the description of a room you own",
+            describe,
+        );
+        commands.restrict("describe", Permission::ManageRoom);
+        commands.register("who", "/who", "list the users in the room", who);
+        commands.register("rooms", "/rooms", "list the rooms you can join", rooms);
+        commands.register(
+            "invite",
+            "/invite <user>",
+            "invite the user into the room",
+            invite_command,
+        );
+        commands.restrict("invite", Permission::Invite);
+        commands.register(
+            "accept",
+            "/accept <room>",
+            "accept the invitation to the room",
+            |context, args| answer_invite(context, args, true),
+        );
+        commands.register(
+            "decline",
+            "/decline <room>",
+            "decline the invitation to the room",
+            |context, args| answer_invite(context, args, false),
+        );
+        commands.register(
+            "visibility",
+            "/visibility public|private|password <password>",
+            "change who may join a room you own",
+            visibility,
+        );
+        commands.restrict("visibility", Permission::ManageRoom);
+        commands.register(
+            "webhook",
+            "/webhook add <name> [signed]|remove <name>|list",
+            "manage the webhooks posting into a room you own",
+            webhook,
+        );
+        commands.restrict("webhook", Permission::ManageRoom);
+        commands.register(
+            "role",
+            "/role <user> guest|member|moderator|owner",
+            "change the role of the user in a room you own",
+            role,
+        );
+        commands.restrict("role", Permission::SetRole);
+        commands.register(
+            "kick",
+            "/kick <user> [reason]",
+            "disconnect the user",
+            kick_command,
+        );
+        commands.register(
+            "ban",
+            "/ban [--ip] <user|address[/prefix]> [duration] [reason]",
+            "disconnect and refuse the user or network, e.g. for 10m, 2h or 7d, --ip refuses the address of the user too",
+            ban_command,
+        );
+        commands.register(
+            "unban",
+            "/unban <user|address[/prefix]>",
+            "lift a ban",
+            unban,
+        );
+        commands.register(
+            "mute",
+            "/mute <user> <duration>",
+            "drop the messages of the user for a while, e.g. 10m",
+            mute_command,
+        );
+        commands.register(
+            "unmute",
+            "/unmute <user>",
+            "lift a mute",
+            |context, args| {
+                let user_id = online(context, args)?;
+                mute(context, user_id, 0)
+            },
+        );
+        for name in ["kick", "ban", "unban", "mute", "unmute"] {
+            commands.restrict(name, Permission::Moderate);
+        }
+        commands.register("help", "/help", "list the commands", help);
+        commands
+    }
+
+    /// add a command, replacing a command with the same name
+    pub fn register(
+        &mut self,
+        name: &str,
+        usage: &str,
+        help: &str,
+        handler: impl Fn(&mut Context, &str) -> CommandResult + Send + Sync + 'static,
+    ) {
+        self.commands.insert(
+            name.to_string(),
+            Command {
+                usage: usage.to_string(),
+                help: help.to_string(),
+                permission: Permission::Chat,
+                handler: Box::new(handler),
+            },
+        );
+    }
+
+    /// only let users with the permission run the registered command
+    pub fn restrict(&mut self, name: &str, permission: Permission) {
+        if let Some(command) = self.commands.get_mut(name) {
+            command.permission = permission;
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    /// the permission needed to run the command, unknown commands need none
+    pub fn permission(&self, name: &str) -> Permission {
+        self.commands
+            .get(name)
+            .map_or(Permission::Chat, |command| command.permission)
+    }
+
+    pub fn run(&self, context: &mut Context, name: &str, args: &str) -> CommandResult {
+        match self.commands.get(name) {
+            Some(command) => (command.handler)(context, args),
+            None => context
+                .plugin_command(name, args)
+                .unwrap_or_else(|| Err(format!("unknown command /{name}, see /help"))),
+        }
+    }
+
+    /// one line with the usage and help of every command
+    pub fn help(&self) -> String {
+        self.commands
+            .values()
+            .map(|command| format!("{} - {}", command.usage, command.help))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn nick(context: &mut Context, args: &str) -> CommandResult {
+    // mutes are kept by name, a new one would shake them off
+    context.check_muted()?;
+    if !command::is_valid_name(args) {
+        return Err("usage: /nick <name>, names are letters, digits, '-', '_' or '.'".to_string());
+    }
+    if context.peers.values().any(|peer| peer.name == args) {
+        return Err(format!("{args} is already taken"));
+    }
+    if context.accounts.is_registered(args) {
+        return Err(format!(
+            "{args} is registered, connect with its token to use it"
+        ));
+    }
+    let peer = context.peer_mut();
+    let old = std::mem::replace(&mut peer.name, args.to_string());
+    let renamed = WebSocketServerToClientMessage::Renamed(peer.id, peer.name.clone());
+    let room = peer.room.clone();
+    for peer in context.peers.values() {
+        send(&peer.tx, &renamed);
+    }
+    context.system(&room, format!("{old} is now known as {args}"));
+    // roles are given by name
+    send_roles(context, &room);
+    context.plugin_event(ChatEvent::Renamed {
+        room: &room,
+        old: &old,
+        new: args,
+    });
+    Ok(String::new())
+}
+
+fn join(context: &mut Context, args: &str) -> CommandResult {
+    let (room, password) = args
+        .split_once(char::is_whitespace)
+        .map(|(room, password)| (room, password.trim()))
+        .unwrap_or((args, ""));
+    if !command::is_valid_name(room) {
+        return Err("usage: /join <room> [password]".to_string());
+    }
+    join_room(context, room, password)?;
+    Ok(String::new())
+}
+
+/// switch to the room if the user may enter it, creating it if it does not exist
+pub fn join_room(context: &mut Context, room: &str, password: &str) -> Result<(), String> {
+    if context.peer().room == room {
+        return Err(format!("you are already in {room}"));
+    }
+    let name = context.peer().name.clone();
+    if context.rooms.get(room).is_some() {
+        context.rooms.enter(room, &name, password)?;
+    } else {
+        context.rooms.get_or_create(room, &name);
+        context.protect(room, &name);
+    }
+    let info = context.rooms.info(room).unwrap();
+    let peer = context.peer_mut();
+    let left = std::mem::replace(&mut peer.room, room.to_string());
+    send(
+        &peer.tx,
+        &WebSocketServerToClientMessage::Joined { room: info },
+    );
+    context.system(&left, format!("{name} left"));
+    context.system(room, format!("{name} joined"));
+    send_roles(context, room);
+    context.plugin_event(ChatEvent::Left {
+        room: &left,
+        user: &name,
+    });
+    context.plugin_event(ChatEvent::Joined { room, user: &name });
+    Ok(())
+}
+
+fn leave(context: &mut Context, _args: &str) -> CommandResult {
+    if context.peer().room == LOBBY {
+        return Err("you are in the lobby".to_string());
+    }
+    join_room(context, LOBBY, "")?;
+    Ok(String::new())
+}
+
+/// the id of the online user with the name
+fn online(context: &Context, name: &str) -> Result<u32, String> {
+    context
+        .peers
+        .values()
+        .find(|peer| peer.name == name)
+        .map(|peer| peer.id)
+        .ok_or_else(|| format!("{name} is not online"))
+}
+
+fn invite_command(context: &mut Context, args: &str) -> CommandResult {
+    let user_id = online(context, args)?;
+    let room = context.peer().room.clone();
+    invite(context, &room, user_id)
+}
+
+/// invite the user into a room the inviting user is a member of
+pub fn invite(context: &mut Context, room: &str, user_id: u32) -> CommandResult {
+    let from = context.peer().name.clone();
+    let is_member = context
+        .rooms
+        .get(room)
+        .is_some_and(|info| info.members.contains(&from));
+    if !is_member && context.peer().room != room {
+        return Err(format!("you are not a member of {room}"));
+    }
+    let Some(invited) = context.peers.values().find(|peer| peer.id == user_id) else {
+        return Err("the user is not online".to_string());
+    };
+    context.rooms.invite(room, &invited.name)?;
+    send(
+        &invited.tx,
+        &WebSocketServerToClientMessage::Invited {
+            room: room.to_string(),
+            from,
+        },
+    );
+    Ok(format!("invited {} to {}", invited.name, room))
+}
+
+/// accept or decline a pending invitation, joining the room if it is accepted
+pub fn answer_invite(context: &mut Context, room: &str, accept: bool) -> CommandResult {
+    let name = context.peer().name.clone();
+    context.rooms.answer(room, &name, accept)?;
+    if !accept {
+        return Ok(format!("declined the invitation to {room}"));
+    }
+    if context.peer().room != room {
+        join_room(context, room, "")?;
+    }
+    Ok(String::new())
+}
+
+fn rooms(context: &mut Context, _args: &str) -> CommandResult {
+    let rooms = context.rooms.list(&context.peer().name);
+    Ok(rooms
+        .iter()
+        .map(|room| {
+            let visibility = match room.visibility {
+                RoomVisibility::Public => "",
+                RoomVisibility::Private => " (private)",
+                RoomVisibility::Password => " (password)",
+            };
+            format!("#{}{} {}", room.name, visibility, room.topic)
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+fn visibility(context: &mut Context, args: &str) -> CommandResult {
+    let (mode, password) = args
+        .split_once(char::is_whitespace)
+        .map(|(mode, password)| (mode, password.trim()))
+        .unwrap_or((args, ""));
+    let visibility = match mode {
+        "public" => RoomVisibility::Public,
+        "private" => RoomVisibility::Private,
+        "password" => RoomVisibility::Password,
+        _ => return Err("usage: /visibility public|private|password <password>".to_string()),
+    };
+    let room = context.peer().room.clone();
+    let info = context.rooms.set_visibility(&room, visibility, password)?;
+    context.broadcast(&room, &WebSocketServerToClientMessage::RoomUpdated(info));
+    let name = &context.peer().name;
+    let change = match visibility {
+        RoomVisibility::Public => "made the room public",
+        RoomVisibility::Private => "made the room invite only",
+        RoomVisibility::Password => "protected the room with a password",
+    };
+    context.system(&room, format!("{name} {change}"));
+    Ok(String::new())
+}
+
+fn webhook(context: &mut Context, args: &str) -> CommandResult {
+    let room = context.peer().room.clone();
+    let webhooks = &context.rooms.get(&room).unwrap().webhooks;
+    let words: Vec<&str> = args.split_whitespace().collect();
+    match words.as_slice() {
+        ["list"] if webhooks.is_empty() => Ok(format!("#{room} has no webhooks")),
+        ["list"] => Ok(webhooks
+            .iter()
+            .map(|webhook| {
+                let signed = if webhook.secret.is_some() {
+                    " (signed)"
+                } else {
+                    ""
+                };
+                format!("{}: POST {}{signed}", webhook.name, webhook.path())
+            })
+            .collect::<Vec<_>>()
+            .join("\n")),
+        ["add", name] | ["add", name, "signed"] => {
+            if !command::is_valid_name(name) {
+                return Err("names are letters, digits, '-', '_' or '.'".to_string());
+            }
+            if webhooks.iter().any(|webhook| webhook.name == *name) {
+                return Err(format!("#{room} already has a webhook {name}"));
+            }
+            let webhook = Webhook::new(name, words.len() == 3);
+            let mut text = format!(
+                "webhook {name} posts to #{room} with POST {}",
+                webhook.path()
+            );
+            if let Some(secret) = &webhook.secret {
+                text.push_str(&format!(", signed with the secret {secret}"));
+            }
+            context
+                .rooms
+                .update(&room, |room| room.webhooks.push(webhook));
+            Ok(text)
+        }
+        ["remove", name] => {
+            if !webhooks.iter().any(|webhook| webhook.name == *name) {
+                return Err(format!("#{room} has no webhook {name}"));
+            }
+            context.rooms.update(&room, |room| {
+                room.webhooks.retain(|webhook| webhook.name != *name)
+            });
+            Ok(format!("removed the webhook {name}"))
+        }
+        _ => Err("usage: /webhook add <name> [signed]|remove <name>|list".to_string()),
+    }
+}
+
+fn private_message(context: &mut Context, args: &str) -> CommandResult {
+    let Some((user, text)) = args
+        .split_once(char::is_whitespace)
+        .map(|(user, text)| (user, text.trim()))
+        .filter(|(_, text)| !text.is_empty())
+    else {
+        return Err("usage: /msg <user> <text>".to_string());
+    };
+    context.check_muted()?;
+    let Some(recipient) = context.peers.values().find(|peer| peer.name == user) else {
+        return Err(format!("{user} is not online"));
+    };
+    let sender = context.peer();
+    let mut message = MessageData {
+        id: sender.id,
+        name: sender.name.clone(),
+        data: text.to_string(),
+        recipient: Some(recipient.name.clone()),
+        ..Default::default()
+    };
+    let room = sender.room.clone();
+    let tx = recipient.tx.clone();
+    let flags = screen(context.filter, &mut message)?;
+    message.message_id = context.next_message_id();
+    if !flags.is_empty() {
+        context
+            .reports
+            .report_private(FILTER_REPORTER, &room, &message, &flags.join("; "));
+    }
+    let line = format!("-> {user}: {}", message.data);
+    send(&tx, &WebSocketServerToClientMessage::UserMessage(message));
+    Ok(line)
+}
+
+fn me(context: &mut Context, args: &str) -> CommandResult {
+    if args.is_empty() {
+        return Err("usage: /me <action>".to_string());
+    }
+    context.check_muted()?;
+    let peer = context.peer();
+    let mut message = MessageData {
+        id: peer.id,
+        name: peer.name.clone(),
+        data: args.to_string(),
+        kind: MessageKind::Action,
+        ..Default::default()
+    };
+    let room = peer.room.clone();
+    let flags = screen(context.filter, &mut message)?;
+    context.plugins.check(&room, &message)?;
+    message.message_id = context.next_message_id();
+    context.reports.record(&room, &message);
+    flag(context.reports, &message, &flags);
+    context.broadcast(
+        &room,
+        &WebSocketServerToClientMessage::UserMessage(message.clone()),
+    );
+    context.plugin_event(ChatEvent::Message {
+        room: &room,
+        message: &message,
+    });
+    Ok(String::new())
+}
+
+fn topic(context: &mut Context, args: &str) -> CommandResult {
+    let room = context.peer().room.clone();
+    if args.is_empty() {
+        let topic = &context.rooms.get(&room).unwrap().topic;
+        return Ok(match topic.as_str() {
+            "" => format!("{room} has no topic"),
+            topic => format!("topic of {room}: {topic}"),
+        });
+    }
+    permissions::check(context.role(), Permission::SetTopic)?;
+    set_topic(context, &room, args)?;
+    Ok(String::new())
+}
+
+/// change the topic if the user is in the room, used by `/topic` and `SetTopic`
+pub fn set_topic(context: &mut Context, room: &str, topic: &str) -> Result<(), String> {
+    if context.peer().room != room {
+        return Err(format!("you are not in {room}"));
+    }
+    if topic.chars().count() > MAX_TOPIC_LENGTH {
+        return Err(format!(
+            "the topic is longer than {MAX_TOPIC_LENGTH} characters"
+        ));
+    }
+    let info = context
+        .rooms
+        .update(room, |room| room.topic = topic.to_string())
+        .ok_or_else(|| format!("{room} does not exist"))?;
+    context.broadcast(room, &WebSocketServerToClientMessage::RoomUpdated(info));
+    let name = &context.peer().name;
+    context.system(room, format!("{name} set the topic: {topic}"));
+    Ok(())
+}
+
+fn describe(context: &mut Context, args: &str) -> CommandResult {
+    let room = context.peer().room.clone();
+    if args.chars().count() > MAX_TOPIC_LENGTH {
+        return Err(format!(
+            "the description is longer than {MAX_TOPIC_LENGTH} characters"
+        ));
+    }
+    let info = context
+        .rooms
+        .update(&room, |room| room.description = args.to_string())
+        .unwrap();
+    context.broadcast(&room, &WebSocketServerToClientMessage::RoomUpdated(info));
+    Ok(String::new())
+}
+
+fn role(context: &mut Context, args: &str) -> CommandResult {
+    let Some((user, role)) = args
+        .split_once(char::is_whitespace)
+        .and_then(|(user, role)| Some((user, Role::from_name(role.trim())?)))
+    else {
+        return Err("usage: /role <user> guest|member|moderator|owner".to_string());
+    };
+    let user_id = online(context, user)?;
+    let room = context.peer().room.clone();
+    set_role(context, &room, user_id, role)
+}
+
+/// give the user a role in the room, used by `/role` and `SetRole`
+pub fn set_role(context: &mut Context, room: &str, user_id: u32, role: Role) -> CommandResult {
+    let Some(user) = context
+        .peers
+        .values()
+        .find(|peer| peer.id == user_id)
+        .map(|peer| peer.name.clone())
+    else {
+        return Err("the user is not online".to_string());
+    };
+    context.rooms.set_role(room, &user, role)?;
+    context.protect(room, &user);
+    let name = &context.peer().name;
+    context.system(room, format!("{name} made {user} a {}", role.name()));
+    send_roles(context, room);
+    Ok(String::new())
+}
+
+/// tell every user in the room the roles of the users in it
+pub fn send_roles(context: &Context, room: &str) {
+    let roles = context
+        .peers
+        .values()
+        .filter(|peer| peer.room == room)
+        .map(|peer| (peer.id, context.rooms.role(room, &peer.name)))
+        .collect();
+    context.broadcast(room, &WebSocketServerToClientMessage::RoomRoles(roles));
+}
+
+/// split `<user> [rest]` into the user and the rest
+fn split_user(args: &str) -> (&str, &str) {
+    args.split_once(char::is_whitespace)
+        .map(|(user, rest)| (user, rest.trim()))
+        .unwrap_or((args, ""))
+}
+
+/// the address of a user in the room of the moderator with a lower role than theirs
+fn moderated(context: &Context, user_id: u32) -> Result<SocketAddr, String> {
+    let room = &context.peer().room;
+    let Some((addr, target)) = context
+        .peers
+        .iter()
+        .find(|(_, peer)| peer.id == user_id && &peer.room == room)
+    else {
+        return Err(format!("the user is not in {room}"));
+    };
+    if context.rooms.role(room, &target.name) >= context.role() {
+        return Err(format!("{} is not below you in {room}", target.name));
+    }
+    Ok(*addr)
+}
+
+/// close the connection of the user with the reason and tell the room why
+fn disconnect(context: &mut Context, addr: SocketAddr, reason: String, line: String) {
+    let peer = context.peers.remove(&addr).unwrap();
+    close(&peer.tx, reason);
+    let disconnected = WebSocketServerToClientMessage::Disconnected(peer.id, peer.name.clone());
+    for other in context.peers.values() {
+        send(&other.tx, &disconnected);
+    }
+    context.system(&peer.room, line);
+    context.plugin_event(ChatEvent::Left {
+        room: &peer.room,
+        user: &peer.name,
+    });
+}
+
+fn kick_command(context: &mut Context, args: &str) -> CommandResult {
+    let (user, reason) = split_user(args);
+    if user.is_empty() {
+        return Err("usage: /kick <user> [reason]".to_string());
+    }
+    let user_id = online(context, user)?;
+    kick(context, user_id, reason)
+}
+
+/// disconnect the user, used by `/kick` and `Kick`
+pub fn kick(context: &mut Context, user_id: u32, reason: &str) -> CommandResult {
+    let addr = moderated(context, user_id)?;
+    let by = context.peer().name.clone();
+    let name = context.peers[&addr].name.clone();
+    let (reason, line) = match reason {
+        "" => (format!("kicked by {by}"), format!("{by} kicked {name}")),
+        reason => (
+            format!("kicked by {by}: {reason}"),
+            format!("{by} kicked {name}: {reason}"),
+        ),
+    };
+    disconnect(context, addr, reason, line);
+    Ok(String::new())
+}
+
+fn ban_command(context: &mut Context, args: &str) -> CommandResult {
+    let (address, args) = match args.strip_prefix("--ip") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+            (true, rest.trim_start())
+        }
+        _ => (false, args),
+    };
+    let (target, rest) = split_user(args);
+    if target.is_empty() {
+        return Err("usage: /ban [--ip] <user|address[/prefix]> [duration] [reason]".to_string());
+    }
+    let (duration, reason) = match split_user(rest) {
+        (duration, reason) if moderation::parse_duration(duration).is_some() => {
+            (moderation::parse_duration(duration), reason)
+        }
+        _ => (None, rest),
+    };
+    match BanTarget::parse(target) {
+        BanTarget::Account(name) if context.peers.values().any(|peer| peer.name == name) => {
+            let user_id = online(context, &name)?;
+            ban(context, user_id, duration, reason, address)
+        }
+        BanTarget::Account(_) if address => {
+            Err("--ip only bans the address of a user who is online".to_string())
+        }
+        _ if duration == Some(0) => Err("a ban needs a duration above 0".to_string()),
+        network if network.too_wide() => Err(format!("{target} is too wide to ban")),
+        target => {
+            let role = server_moderator(context)?;
+            if let BanTarget::Account(name) = &target {
+                if context.rooms.server_role(name) >= role {
+                    return Err(format!("{name} is not below you on the server"));
+                }
+            }
+            let by = context.peer().name.clone();
+            context.moderation.ban(target, &by, reason, duration);
+            Ok(format!("banned {args}"))
+        }
+    }
+}
+
+/// the role of the moderator on the whole server, bans outside the room need one
+fn server_moderator(context: &Context) -> Result<Role, String> {
+    match context.rooms.server_role(&context.peer().name) {
+        role if role >= Role::Moderator => Ok(role),
+        _ => Err("only a moderator of the server can do that".to_string()),
+    }
+}
+
+/// disconnect the user and ban their account, and with `address` the address
+/// they connect from, used by `/ban` and `Ban`
+///
+/// the address is only banned on request, behind a proxy it is the one of everyone
+pub fn ban(
+    context: &mut Context,
+    user_id: u32,
+    duration: Option<u64>,
+    reason: &str,
+    address: bool,
+) -> CommandResult {
+    if duration == Some(0) {
+        return Err("a ban needs a duration above 0".to_string());
+    }
+    let addr = moderated(context, user_id)?;
+    let by = context.peer().name.clone();
+    let name = context.peers[&addr].name.clone();
+    let target = BanTarget::Account(name.clone());
+    context.moderation.ban(target, &by, reason, duration);
+    if address {
+        context
+            .moderation
+            .ban(BanTarget::Network(addr.ip().into()), &by, reason, duration);
+    }
+    let Some(ban) = context
+        .moderation
+        .banned(&name, addr.ip())
+        .map(|ban| ban.describe())
+    else {
+        return Err(format!("cannot ban {name}"));
+    };
+    let how_long = duration
+        .map(|duration| format!(" for {}", moderation::format_duration(duration)))
+        .unwrap_or_default();
+    let line = match reason {
+        "" => format!("{by} banned {name}{how_long}"),
+        reason => format!("{by} banned {name}{how_long}: {reason}"),
+    };
+    disconnect(context, addr, ban, line);
+    Ok(String::new())
+}
+
+/// lift the bans of an account or network, used by `/unban` and `Unban`
+pub fn unban(context: &mut Context, target: &str) -> CommandResult {
+    if target.is_empty() {
+        return Err("usage: /unban <user|address[/prefix]>".to_string());
+    }
+    server_moderator(context)?;
+    match context.moderation.unban(&BanTarget::parse(target)) {
+        0 => Err(format!("{target} is not banned")),
+        _ => Ok(format!("unbanned {target}")),
+    }
+}
+
+fn mute_command(context: &mut Context, args: &str) -> CommandResult {
+    let (user, duration) = split_user(args);
+    let Some(duration) = moderation::parse_duration(duration) else {
+        return Err("usage: /mute <user> <duration>".to_string());
+    };
+    let user_id = online(context, user)?;
+    mute(context, user_id, duration)
+}
+
+/// drop the messages of the user for `duration` seconds, 0 lifts the mute,
+/// used by `/mute`, `/unmute` and `Mute`
+pub fn mute(context: &mut Context, user_id: u32, duration: u64) -> CommandResult {
+    let addr = moderated(context, user_id)?;
+    let by = context.peer().name.clone();
+    let name = context.peers[&addr].name.clone();
+    context.moderation.mute(&name, duration);
+    let line = match duration {
+        0 => format!("{by} unmuted {name}"),
+        duration => format!(
+            "{by} muted {name} for {}",
+            moderation::format_duration(duration)
+        ),
+    };
+    let room = context.peer().room.clone();
+    context.system(&room, line);
+    Ok(String::new())
+}
+
+/// report a message of the room the user is in
+pub fn report(context: &mut Context, message_id: u64, reason: &str) -> CommandResult {
+    let peer = context.peer();
+    if context.reports.room_of(message_id) != Some(peer.room.as_str()) {
+        return Err(format!("the message was not sent to {}", peer.room));
+    }
+    let name = peer.name.clone();
+    context.reports.report(&name, message_id, reason)?;
+    Ok("reported the message to the moderators".to_string())
+}
+
+/// the open reports of the rooms the user moderates
+pub fn list_reports(context: &Context) -> Vec<MessageReport> {
+    let name = &context.peer().name;
+    context
+        .reports
+        .list(|room| context.rooms.role(room, name) >= Permission::Moderate.role())
+}
+
+/// act on a report of a room the user moderates
+pub fn resolve_report(
+    context: &mut Context,
+    report_id: u64,
+    action: ReportAction,
+) -> CommandResult {
+    let Some(report) = context.reports.get(report_id).cloned() else {
+        return Err("the report is already resolved".to_string());
+    };
+    let by = context.peer().name.clone();
+    let role = context.rooms.role(&report.room, &by);
+    permissions::check(role, Permission::Moderate)?;
+    let author = &report.message.name;
+    match action {
+        ReportAction::Dismiss => {}
+        ReportAction::Delete => {
+            let message_id = report.message.message_id;
+            context.reports.delete(message_id);
+            context.broadcast(
+                &report.room,
+                &WebSocketServerToClientMessage::MessageDeleted(message_id),
+            );
+            context.system(&report.room, format!("{by} deleted a message of {author}"));
+        }
+        ReportAction::Mute(duration) => {
+            // the same rule as for `/mute`
+            if context.rooms.role(&report.room, author) >= role {
+                return Err(format!("{author} is not below you in {}", report.room));
+            }
+            context.moderation.mute(author, duration);
+            context.system(
+                &report.room,
+                format!(
+                    "{by} muted {author} for {}",
+                    moderation::format_duration(duration)
+                ),
+            );
+        }
+    }
+    context.reports.resolve(report_id);
+    Ok(String::new())
+}
+
+fn who(context: &mut Context, _args: &str) -> CommandResult {
+    let room = &context.peer().room;
+    let mut names: Vec<&str> = context
+        .peers
+        .values()
+        .filter(|peer| &peer.room == room)
+        .map(|peer| peer.name.as_str())
+        .collect();
+    names.sort_unstable();
+    Ok(format!("in {}: {}", room, names.join(", ")))
+}
+
+fn help(context: &mut Context, _args: &str) -> CommandResult {
+    let mut help = context.commands.help();
+    // built-in commands take precedence
+    let mut listed: Vec<String> = Vec::new();
+    for command in context
+        .plugins
+        .commands()
+        .into_iter()
+        .chain(context.scripts.commands())
+        .filter(|command| !context.commands.contains(&command.name))
+    {
+        if !listed.contains(&command.name) {
+            help.push_str(&format!("\n{} - {}", command.usage, command.help));
+            listed.push(command.name);
+        }
+    }
+    Ok(help)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_channel::mpsc::unbounded;
+
+    use super::*;
+    use crate::server::filter::{ContentPolicy, Verdict};
+
+    #[test]
+    fn test_commands() {
+        let commands = Commands::with_builtins();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (tx, _rx) = unbounded();
+        let mut peers = HashMap::from([(
+            addr,
+            Peer {
+                tx,
+                id: 0,
+                name: "alice".to_string(),
+                room: LOBBY.to_string(),
+                markdown: false,
+            },
+        )]);
+        let mut rooms = RoomRegistry::default();
+        let mut moderation = Moderation::default();
+        let mut reports = Reports::default();
+        let mut plugins = Plugins::default();
+        let mut accounts = Accounts::default();
+        let mut filter = ContentFilter::default();
+        let message_counter = AtomicU64::new(1);
+        let mut context = Context {
+            addr,
+            peers: &mut peers,
+            rooms: &mut rooms,
+            moderation: &mut moderation,
+            reports: &mut reports,
+            plugins: &mut plugins,
+            scripts: &ScriptRunner::default(),
+            accounts: &mut accounts,
+            filter: &mut filter,
+            message_counter: &message_counter,
+            commands: &commands,
+        };
+
+        assert!(commands.run(&mut context, "leave", "").is_err());
+        commands.run(&mut context, "nick", "bob").unwrap();
+        commands.run(&mut context, "join", "rust").unwrap();
+        assert_eq!(
+            commands.run(&mut context, "who", "").unwrap(),
+            "in rust: bob"
+        );
+        assert_eq!(
+            commands.run(&mut context, "topic", "borrowing").unwrap(),
+            ""
+        );
+        assert_eq!(
+            commands.run(&mut context, "topic", "").unwrap(),
+            "topic of rust: borrowing"
+        );
+        assert!(commands.run(&mut context, "nope", "").is_err());
+        assert_eq!(commands.permission("role"), Permission::SetRole);
+        assert_eq!(commands.permission("who"), Permission::Chat);
+        commands.run(&mut context, "role", "bob guest").unwrap();
+        assert_eq!(context.role(), Role::Guest);
+        assert!(commands.run(&mut context, "topic", "lifetimes").is_err());
+        assert!(commands
+            .run(&mut context, "help", "")
+            .unwrap()
+            .contains("/who - list the users in the room"));
+    }
+
+    /// rejects ads, masks `darn` and flags `idiot`
+    struct Words;
+
+    impl ContentPolicy for Words {
+        fn check(&mut self, _user: &str, text: &str) -> Result<Verdict, String> {
+            if text.contains("buy now") {
+                return Err("no ads".to_string());
+            }
+            Ok(Verdict {
+                masked: text.contains("darn").then(|| text.replace("darn", "****")),
+                flags: text
+                    .contains("idiot")
+                    .then(|| "insult".to_string())
+                    .into_iter()
+                    .collect(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let commands = Commands::with_builtins();
+        let alice: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let bob: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let (tx, mut rx) = unbounded();
+        let mut peers = HashMap::from([
+            (
+                alice,
+                Peer {
+                    tx: tx.clone(),
+                    id: 0,
+                    name: "alice".to_string(),
+                    room: LOBBY.to_string(),
+                    markdown: false,
+                },
+            ),
+            (
+                bob,
+                Peer {
+                    tx,
+                    id: 1,
+                    name: "bob".to_string(),
+                    room: LOBBY.to_string(),
+                    markdown: false,
+                },
+            ),
+        ]);
+        let mut rooms = RoomRegistry::default();
+        rooms.get_or_create(LOBBY, "");
+        let mut moderation = Moderation::default();
+        let mut reports = Reports::default();
+        let mut plugins = Plugins::default();
+        let mut accounts = Accounts::default();
+        let mut filter = ContentFilter::default();
+        filter.add(Words);
+        let message_counter = AtomicU64::new(1);
+        let mut context = Context {
+            addr: alice,
+            peers: &mut peers,
+            rooms: &mut rooms,
+            moderation: &mut moderation,
+            reports: &mut reports,
+            plugins: &mut plugins,
+            scripts: &ScriptRunner::default(),
+            accounts: &mut accounts,
+            filter: &mut filter,
+            message_counter: &message_counter,
+            commands: &commands,
+        };
+
+        assert_eq!(
+            commands
+                .run(&mut context, "me", "says buy now")
+                .unwrap_err(),
+            "no ads"
+        );
+        assert_eq!(
+            commands
+                .run(&mut context, "msg", "bob buy now")
+                .unwrap_err(),
+            "no ads"
+        );
+        assert_eq!(
+            commands.run(&mut context, "msg", "bob darn it").unwrap(),
+            "-> bob: **** it"
+        );
+        commands
+            .run(&mut context, "me", "calls bob an idiot")
+            .unwrap();
+        commands.run(&mut context, "msg", "bob idiot").unwrap();
+        let reports = context.reports.list(|_| true);
+        assert_eq!(reports.len(), 2);
+        assert!(reports
+            .iter()
+            .all(|report| report.reporter == FILTER_REPORTER && report.reason == "insult"));
+        assert_eq!(reports[1].message.recipient.as_deref(), Some("bob"));
+        let mut received = Vec::new();
+        while let Ok(Some(message)) = rx.try_next() {
+            received.push(message.into_text().unwrap());
+        }
+        assert!(received.iter().any(|text| text.contains("**** it")));
+        assert!(!received.iter().any(|text| text.contains("buy now")));
+    }
+
+    #[test]
+    fn test_moderation() {
+        let commands = Commands::with_builtins();
+        let alice: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mallory: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let (tx, _rx) = unbounded();
+        let mut peers = HashMap::from([
+            (
+                alice,
+                Peer {
+                    tx: tx.clone(),
+                    id: 0,
+                    name: "alice".to_string(),
+                    room: LOBBY.to_string(),
+                    markdown: false,
+                },
+            ),
+            (
+                mallory,
+                Peer {
+                    tx,
+                    id: 1,
+                    name: "mallory".to_string(),
+                    room: LOBBY.to_string(),
+                    markdown: false,
+                },
+            ),
+        ]);
+        let mut rooms = RoomRegistry::default();
+        rooms.get_or_create(LOBBY, "");
+        rooms.set_server_role("alice", Role::Moderator);
+        let mut moderation = Moderation::default();
+        let mut reports = Reports::default();
+        let mut plugins = Plugins::default();
+        let mut accounts = Accounts::default();
+        let mut filter = ContentFilter::default();
+        let message_counter = AtomicU64::new(1);
+        let mut context = Context {
+            addr: mallory,
+            peers: &mut peers,
+            rooms: &mut rooms,
+            moderation: &mut moderation,
+            reports: &mut reports,
+            plugins: &mut plugins,
+            scripts: &ScriptRunner::default(),
+            accounts: &mut accounts,
+            filter: &mut filter,
+            message_counter: &message_counter,
+            commands: &commands,
+        };
+
+        assert!(kick(&mut context, 0, "").is_err());
+        context.addr = alice;
+        commands.run(&mut context, "mute", "mallory 10m").unwrap();
+        context.addr = mallory;
+        assert!(commands.run(&mut context, "me", "waves").is_err());
+        assert!(commands.run(&mut context, "nick", "eve").is_err());
+        context.addr = alice;
+        let spam = MessageData {
+            name: "mallory".to_string(),
+            message_id: 7,
+            ..Default::default()
+        };
+        context.reports.record(LOBBY, &spam);
+        report(&mut context, 7, "spam").unwrap();
+        let reports = list_reports(&context);
+        assert_eq!(reports.len(), 1);
+        resolve_report(&mut context, reports[0].id, ReportAction::Delete).unwrap();
+        assert!(list_reports(&context).is_empty());
+        // a report does not let a moderator mute someone of the same rank
+        let own = MessageData {
+            name: "alice".to_string(),
+            message_id: 8,
+            ..Default::default()
+        };
+        context.reports.record(LOBBY, &own);
+        report(&mut context, 8, "spam").unwrap();
+        let report_id = list_reports(&context)[0].id;
+        assert!(resolve_report(&mut context, report_id, ReportAction::Mute(60)).is_err());
+        assert!(context.moderation.muted("alice").is_none());
+        resolve_report(&mut context, report_id, ReportAction::Dismiss).unwrap();
+        assert!(report(&mut context, 7, "spam").is_err());
+        // the address is only banned with --ip
+        let eve: SocketAddr = "10.0.0.3:1".parse().unwrap();
+        let (tx, _eve_rx) = unbounded();
+        context.peers.insert(
+            eve,
+            Peer {
+                tx,
+                id: 2,
+                name: "eve".to_string(),
+                room: LOBBY.to_string(),
+                markdown: false,
+            },
+        );
+        commands.run(&mut context, "ban", "eve").unwrap();
+        assert!(context.moderation.banned("eve", eve.ip()).is_some());
+        assert!(context.moderation.banned("trudy", eve.ip()).is_none());
+        commands
+            .run(&mut context, "ban", "--ip mallory 1h spam")
+            .unwrap();
+        assert!(!context.peers.contains_key(&mallory));
+        assert!(context
+            .moderation
+            .banned("mallory", "127.0.0.2".parse().unwrap())
+            .is_some());
+        assert!(context.moderation.banned("trudy", mallory.ip()).is_some());
+        commands.run(&mut context, "unban", "10.0.0.2").unwrap();
+        assert!(context.moderation.banned("trudy", mallory.ip()).is_none());
+        assert!(commands.run(&mut context, "unban", "10.0.0.2").is_err());
+        // a ban has to end later than now and cannot cover everyone
+        assert!(commands.run(&mut context, "ban", "trudy 0").is_err());
+        assert!(commands.run(&mut context, "ban", "0.0.0.0/0").is_err());
+        context.moderation.mute("trudy", u64::MAX);
+        assert!(context.moderation.muted("trudy").is_some());
+        // outside the room only a moderator of the server bans, and not their peers
+        context.rooms.set_server_role("walter", Role::Moderator);
+        assert!(commands.run(&mut context, "ban", "walter").is_err());
+        commands.run(&mut context, "ban", "trudy 1h").unwrap();
+        context.rooms.get_or_create("rust", "alice");
+        context.rooms.set_server_role("alice", Role::Member);
+        context.peers.get_mut(&alice).unwrap().room = "rust".to_string();
+        assert_eq!(context.role(), Role::Owner);
+        assert!(commands.run(&mut context, "ban", "walter").is_err());
+        assert!(commands.run(&mut context, "ban", "10.9.0.0/16").is_err());
+        assert!(commands.run(&mut context, "unban", "trudy").is_err());
+        assert!(context.moderation.banned("trudy", eve.ip()).is_some());
+    }
+}
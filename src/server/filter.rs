@@ -0,0 +1,284 @@
+//! The content filter every message passes before it is relayed.
+//!
+//! The filter runs a list of [`ContentPolicy`] hooks, the built-in one is the
+//! [`WordFilter`] reading its rules from a json file like
+//!
+//! ```json
+//! [
+//!     {"pattern": "darn", "action": "Mask"},
+//!     {"pattern": "buy\\s+now", "regex": true, "action": "Reject", "reason": "no ads"},
+//!     {"pattern": "idiot", "action": "Flag"}
+//! ]
+//! ```
+//!
+//! Rules match the normalised text: compatibility forms like full-width
+//! letters are folded, accents and invisible characters are dropped and the
+//! text is lowercased, so `Ｄ\u{200b}árn` matches `darn`. The file is read
+//! again whenever it changes.
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use tracing::{info, warn};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// how often the rule file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// the outcome of a policy that lets the message through
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Verdict {
+    /// the text with the matches masked, `None` if nothing was masked
+    pub masked: Option<String>,
+    /// why a moderator should look at the message, empty if nothing was flagged
+    pub flags: Vec<String>,
+}
+
+/// a check of the text of every message, `Err` rejects the message with the reason
+pub trait ContentPolicy: Send {
+    fn check(&mut self, user: &str, text: &str) -> Result<Verdict, String>;
+}
+
+#[derive(Default)]
+pub struct ContentFilter {
+    policies: Vec<Box<dyn ContentPolicy>>,
+}
+
+impl ContentFilter {
+    pub fn add(&mut self, policy: impl ContentPolicy + 'static) {
+        self.policies.push(Box::new(policy));
+    }
+
+    /// run every policy on the text, a policy sees the text masked by the ones before it
+    pub fn check(&mut self, user: &str, text: &str) -> Result<Verdict, String> {
+        let mut verdict = Verdict::default();
+        for policy in &mut self.policies {
+            let current = verdict.masked.as_deref().unwrap_or(text);
+            let Verdict { masked, flags } = policy.check(user, current)?;
+            if masked.is_some() {
+                verdict.masked = masked;
+            }
+            verdict.flags.extend(flags);
+        }
+        Ok(verdict)
+    }
+}
+
+/// a filter is a policy of its own, so policies can be grouped
+impl ContentPolicy for ContentFilter {
+    fn check(&mut self, user: &str, text: &str) -> Result<Verdict, String> {
+        ContentFilter::check(self, user, text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum FilterAction {
+    /// refuse the message
+    Reject,
+    /// replace the matched characters with `*`
+    Mask,
+    /// relay the message and report it to the moderators
+    Flag,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RuleFile {
+    pattern: String,
+    /// a regular expression instead of a word
+    #[serde(default)]
+    regex: bool,
+    action: FilterAction,
+    /// shown to the sender of a rejected message or to the moderators
+    #[serde(default)]
+    reason: String,
+}
+
+struct Rule {
+    regex: Regex,
+    action: FilterAction,
+    reason: String,
+}
+
+impl Rule {
+    fn new(rule: RuleFile) -> Result<Self, regex::Error> {
+        let pattern = if rule.regex {
+            rule.pattern
+        } else {
+            format!(r"\b{}\b", regex::escape(&normalize(&rule.pattern).0))
+        };
+        let regex = RegexBuilder::new(&pattern).case_insensitive(true).build()?;
+        let reason = match rule.reason.as_str() {
+            "" => format!("matches the filter rule {pattern}"),
+            _ => rule.reason,
+        };
+        Ok(Self {
+            regex,
+            action: rule.action,
+            reason,
+        })
+    }
+}
+
+/// the blocklist of the server, read from a json file
+pub struct WordFilter {
+    path: PathBuf,
+    rules: Vec<Rule>,
+    /// the modification time of the file the rules were read from
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl WordFilter {
+    /// read the rules, a missing file is read once it is created
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let mut filter = Self {
+            path: path.into(),
+            rules: Vec::new(),
+            modified: None,
+            checked: Instant::now(),
+        };
+        filter.load()?;
+        Ok(filter)
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        let modified = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.rules.clear();
+                self.modified = None;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let rules: Vec<RuleFile> = serde_json::from_str(&fs::read_to_string(&self.path)?)?;
+        self.rules = rules
+            .into_iter()
+            .map(Rule::new)
+            .collect::<Result<_, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.modified = modified;
+        info!("read {} filter rules", self.rules.len());
+        Ok(())
+    }
+
+    /// read the rules again if the file changed, keeping the old ones if it is broken
+    fn reload(&mut self) {
+        if self.checked.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+        let modified = fs::metadata(&self.path)
+            .ok()
+            .and_then(|metadata| metadata.modified().ok());
+        if modified == self.modified {
+            return;
+        }
+        if let Err(e) = self.load() {
+            warn!("cannot read the filter rules: {}", e);
+            self.modified = modified;
+        }
+    }
+}
+
+impl ContentPolicy for WordFilter {
+    fn check(&mut self, _user: &str, text: &str) -> Result<Verdict, String> {
+        self.reload();
+        let (normalized, origins) = normalize(text);
+        let mut masked: Vec<char> = text.chars().collect();
+        let mut verdict = Verdict::default();
+        for rule in &self.rules {
+            let mut matches = rule.regex.find_iter(&normalized).peekable();
+            if matches.peek().is_none() {
+                continue;
+            }
+            match rule.action {
+                FilterAction::Reject => return Err(rule.reason.clone()),
+                FilterAction::Flag => verdict.flags.push(rule.reason.clone()),
+                FilterAction::Mask => {
+                    for found in matches.filter(|found| found.start() < found.end()) {
+                        let first = origins[found.start()];
+                        let last = origins[found.end() - 1];
+                        for c in &mut masked[first..=last] {
+                            if !c.is_whitespace() {
+                                *c = '*';
+                            }
+                        }
+                    }
+                    verdict.masked = Some(masked.iter().collect());
+                }
+            }
+        }
+        Ok(verdict)
+    }
+}
+
+/// whether the character is invisible and only used to break up words
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00ad}' | '\u{034f}' | '\u{180e}' | '\u{200b}'..='\u{200f}' | '\u{2060}' | '\u{feff}'
+    )
+}
+
+/// the text rules are matched against, and for every byte of it the index of
+/// the character of `text` it comes from
+fn normalize(text: &str) -> (String, Vec<usize>) {
+    let mut normalized = String::with_capacity(text.len());
+    let mut origins = Vec::with_capacity(text.len());
+    for (index, c) in text.chars().enumerate() {
+        for folded in c
+            .nfkd()
+            .filter(|c| !is_combining_mark(*c) && !is_invisible(*c))
+            .flat_map(char::to_lowercase)
+        {
+            normalized.push(folded);
+            origins.extend(std::iter::repeat_n(index, folded.len_utf8()));
+        }
+    }
+    (normalized, origins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_filter() {
+        let path = std::env::temp_dir().join(format!("filter-test-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"[
+                {"pattern": "darn", "action": "Mask"},
+                {"pattern": "buy\\s+now", "regex": true, "action": "Reject", "reason": "no ads"},
+                {"pattern": "idiot", "action": "Flag"}
+            ]"#,
+        )
+        .unwrap();
+        let mut filter = ContentFilter::default();
+        filter.add(WordFilter::open(&path).unwrap());
+
+        assert_eq!(filter.check("bob", "hello").unwrap(), Verdict::default());
+        assert_eq!(
+            filter.check("bob", "BUY  now").unwrap_err(),
+            "no ads".to_string()
+        );
+        assert_eq!(
+            filter.check("bob", "oh Ｄ\u{200b}árn it, darned").unwrap(),
+            Verdict {
+                masked: Some("oh ***** it, darned".to_string()),
+                flags: Vec::new(),
+            }
+        );
+        let verdict = filter.check("bob", "you idiot").unwrap();
+        assert_eq!(verdict.masked, None);
+        assert_eq!(verdict.flags.len(), 1);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+//! Plain HTTP on the port of the WebSocket.
+//!
+//! The head of every request is read before the WebSocket handshake: an
+//! upgrade is handed to the WebSocket with the bytes read so far (see
+//! [`Prefixed`]), anything else is answered here, e.g. with the files of the
+//! browser client.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// the longest head of a request, in bytes
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// the longest body of a request, in bytes
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+/// how long reading the head or the body of a request may take, so slow
+/// clients cannot hold connections open forever
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the paths a WebSocket may be opened on, `/` for the native clients
+pub const WEBSOCKET_PATHS: [&str; 2] = ["/", "/ws"];
+
+/// the browser client, built into the server
+const ASSETS: [(&str, &str, &str); 3] = [
+    (
+        "/",
+        "text/html; charset=utf-8",
+        include_str!("web/index.html"),
+    ),
+    (
+        "/app.js",
+        "text/javascript; charset=utf-8",
+        include_str!("web/app.js"),
+    ),
+    (
+        "/style.css",
+        "text/css; charset=utf-8",
+        include_str!("web/style.css"),
+    ),
+];
+
+/// the head of a request
+#[derive(Debug, Default)]
+pub struct Request {
+    pub method: String,
+    /// without the query
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    /// empty until it is read with [`read_body`]
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// the value of the header, the name is not case sensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// the value of the parameter in the query, not percent-decoded
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(other, _)| *other == name)
+            .map(|(_, value)| value)
+    }
+
+    /// whether the request opens a WebSocket
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// read the head of a request, the bytes read after it are returned as well
+pub async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<(Request, Vec<u8>)> {
+    let mut buffer = Vec::with_capacity(1024);
+    let end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(invalid("the head of the request is too long"));
+        }
+        let mut chunk = [0; 1024];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+    let head = std::str::from_utf8(&buffer[..end]).map_err(|_| invalid("not utf-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line
+        .next()
+        .ok_or_else(|| invalid("no request target"))?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    Ok((request, buffer))
+}
+
+/// read the body of the request, `read` are the bytes returned by [`read_head`]
+pub async fn read_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut Request,
+    read: &[u8],
+) -> io::Result<()> {
+    let length: usize = match request.header("content-length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| invalid("the content length is not a number"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(invalid("the body of the request is too long"));
+    }
+    let start = read
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(read.len(), |end| end + 4);
+    let mut body = read[start..].to_vec();
+    body.truncate(length);
+    let already = body.len();
+    body.resize(length, 0);
+    stream.read_exact(&mut body[already..]).await?;
+    request.body = body;
+    Ok(())
+}
+
+/// undo the percent-encoding of a part of the path or query, `None` if it is not utf-8
+pub fn decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let hex = after
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, hex) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &after[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(status: u16, text: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: text.into().into_bytes(),
+        }
+    }
+
+    pub fn json(status: u16, value: &impl serde::Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap(),
+        }
+    }
+
+    /// `{"error": "<reason>"}`
+    pub fn error(status: u16, reason: impl Into<String>) -> Self {
+        Self::json(status, &serde_json::json!({ "error": reason.into() }))
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "not found")
+    }
+}
+
+/// write the response and close the connection
+pub async fn respond(stream: &mut (impl AsyncWrite + Unpin), response: Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+/// the file of the browser client at the path
+pub fn asset(request: &Request) -> Response {
+    if request.method != "GET" {
+        return Response::text(405, "method not allowed");
+    }
+    match ASSETS.iter().find(|(path, ..)| *path == request.path) {
+        Some((_, content_type, content)) => Response {
+            status: 200,
+            content_type,
+            body: content.as_bytes().to_vec(),
+        },
+        None => Response::not_found(),
+    }
+}
+
+/// a stream that yields the bytes read ahead of time before the ones of the inner stream
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    /// how much of the prefix was read
+    position: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position < this.prefix.len() {
+            let rest = &this.prefix[this.position..];
+            let length = rest.len().min(buf.remaining());
+            buf.put_slice(&rest[..length]);
+            this.position += length;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_head() {
+        let mut stream: &[u8] =
+            b"GET /ws?x=1 HTTP/1.1\r\nHost: chat\r\nUpgrade: WebSocket\r\n\r\nframe";
+        let (request, read) = read_head(&mut stream).await.unwrap();
+        assert_eq!(
+            (request.path.as_str(), request.param("x")),
+            ("/ws", Some("1"))
+        );
+        assert_eq!(request.header("host"), Some("chat"));
+        assert!(request.is_upgrade());
+
+        let mut replayed = String::new();
+        Prefixed::new(read, stream)
+            .read_to_string(&mut replayed)
+            .await
+            .unwrap();
+        assert!(replayed.starts_with("GET /ws?x=1") && replayed.ends_with("\r\n\r\nframe"));
+        assert_eq!(decode("caf%C3%A9%20au%2").as_deref(), Some("café au%2"));
+
+        let request = Request {
+            method: "GET".to_string(),
+            path: "/app.js".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(asset(&request).status, 200);
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let sent = b"POST /api HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello";
+        let (mut stream, mut rest): (&[u8], &[u8]) = (sent, b" world, and more");
+        let (mut request, read) = read_head(&mut stream).await.unwrap();
+        read_body(&mut rest, &mut request, &read).await.unwrap();
+        assert_eq!(request.body, b"hello world");
+
+        request.headers = vec![("Content-Length".to_string(), "1000000".to_string())];
+        assert!(read_body(&mut rest, &mut request, &read).await.is_err());
+    }
+}
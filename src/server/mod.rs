@@ -0,0 +1,1294 @@
+//! A chat server that broadcasts a message to all connections.
+//!
+//! The server accepts WebSocket connections and relays the messages of every
+//! connection to the other connections in the same room. It is configured
+//! with a [`ServerBuilder`] and runs until it is shut down, so it can be
+//! embedded into other services and tests:
+//!
+//! ```no_run
+//! use websocket_chatroom::server::ChatServer;
+//!
+//! # async fn serve() -> std::io::Result<()> {
+//! let server = ChatServer::builder()
+//!     .data_dir("chatroom_data")
+//!     .bind("127.0.0.1:0")
+//!     .await?;
+//! println!("Listening on: {}", server.local_addr()?);
+//! let shutdown = server.shutdown_handle();
+//! tokio::spawn(server.run());
+//! // ...
+//! shutdown.shutdown();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! connected clients join the lobby and see everyone else's
+//! messages, `/join` switches to another room (see [`commands`]). What a user
+//! may do depends on their role in the room (see [`permissions`]), a name
+//! with a role is only given to the client holding its token (see
+//! [`accounts`]), moderators
+//! may kick, ban and mute other users (see [`moderation`]) and act on the
+//! messages reported by users (see [`reports`]). Every message passes the
+//! content filter before it is relayed (see [`filter`]), in-process bots see
+//! the events of the rooms (see [`bots`]) and operators add commands with
+//! scripts (see [`scripts`]).
+//!
+//! The same port serves a browser client over plain HTTP, which opens its
+//! WebSocket on `/ws` (see [`http`]), a REST API for scripts and dashboards
+//! (see [`api`]) and the webhooks of the rooms (see [`webhooks`]).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use accounts::Accounts;
+use api::ApiKeys;
+use attachments::{AttachmentStore, Upload};
+use commands::{CommandResult, Commands, Context};
+use filter::{ContentFilter, ContentPolicy, WordFilter};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
+use moderation::Moderation;
+use reports::Reports;
+use rooms::RoomRegistry;
+use scripts::{CommandReply, ScriptRunner, Scripts};
+
+use crate::{
+    command::{self, LOBBY},
+    markdown,
+    plugin::{ChatEvent, ChatPlugin, NamedActions, Plugins},
+    transfer, MessageData, MessageFormat, MessageKind, Role, WebSocketClientToServerMessage,
+    WebSocketServerToClientMessage,
+};
+use http::Prefixed;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{oneshot, Notify},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use tracing::{info, warn};
+
+mod accounts;
+mod api;
+mod attachments;
+mod bots;
+mod commands;
+pub mod filter;
+mod http;
+mod moderation;
+mod permissions;
+mod reports;
+mod rooms;
+mod scripts;
+pub mod webhooks;
+
+type Tx = UnboundedSender<Message>;
+type PeerMap = Arc<Mutex<HashMap<SocketAddr, Peer>>>;
+type Rooms = Arc<Mutex<RoomRegistry>>;
+/// the id that will be assigned to the next relayed message
+type MessageCounter = Arc<AtomicU64>;
+type Nonces = Arc<Mutex<RecentNonces>>;
+type Attachments = Arc<Mutex<AttachmentStore>>;
+type Bans = Arc<Mutex<Moderation>>;
+type ReportQueue = Arc<Mutex<Reports>>;
+type Filter = Arc<Mutex<ContentFilter>>;
+type PluginList = Arc<Mutex<Plugins>>;
+type Keys = Arc<Mutex<ApiKeys>>;
+type Names = Arc<Mutex<Accounts>>;
+
+/// a connected user
+pub(crate) struct Peer {
+    pub tx: Tx,
+    pub id: u32,
+    pub name: String,
+    /// messages of the user are only relayed to the users in the same room
+    pub room: String,
+    /// whether the client renders markdown, the others get its plain text
+    pub markdown: bool,
+}
+
+/// the state shared by all connections
+#[derive(Clone)]
+struct ServerState {
+    peers: PeerMap,
+    rooms: Rooms,
+    message_counter: MessageCounter,
+    nonces: Nonces,
+    attachments: Attachments,
+    bans: Bans,
+    reports: ReportQueue,
+    filter: Filter,
+    plugins: PluginList,
+    scripts: Arc<ScriptRunner>,
+    commands: Arc<Commands>,
+    api_keys: Keys,
+    accounts: Names,
+}
+
+/// the reporter of the messages flagged by the content filter, not a valid user name
+const FILTER_REPORTER: &str = "content filter";
+
+/// how many relayed nonces are remembered to drop resent messages
+const RECENT_NONCES: usize = 4096;
+
+/// the nonces of the most recently relayed messages, keyed by user name, so a
+/// client flushing its outbox after a reconnect never duplicates a message
+#[derive(Default)]
+struct RecentNonces {
+    message_ids: HashMap<(String, u64), u64>,
+    order: VecDeque<(String, u64)>,
+}
+
+impl RecentNonces {
+    /// nonce 0 is sent by clients that do not know about nonces and is never deduplicated
+    fn get(&self, name: &str, nonce: u64) -> Option<u64> {
+        if nonce == 0 {
+            return None;
+        }
+        self.message_ids.get(&(name.to_string(), nonce)).copied()
+    }
+
+    fn insert(&mut self, name: String, nonce: u64, message_id: u64) {
+        if nonce == 0 {
+            return;
+        }
+        if self.order.len() == RECENT_NONCES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.message_ids.remove(&oldest);
+            }
+        }
+        self.order.push_back((name.clone(), nonce));
+        self.message_ids.insert((name, nonce), message_id);
+    }
+}
+
+/// answer a request over plain HTTP or open a WebSocket
+async fn serve_connection(
+    state: ServerState,
+    mut raw_stream: TcpStream,
+    addr: SocketAddr,
+    user_id: u32,
+) -> eyre::Result<()> {
+    println!("Incoming TCP connection from: {}", addr);
+
+    let (mut request, read) =
+        tokio::time::timeout(http::READ_TIMEOUT, http::read_head(&mut raw_stream)).await??;
+    if request.is_upgrade() {
+        return handle_connection(state, Prefixed::new(read, raw_stream), addr, user_id).await;
+    }
+    let handle: Option<fn(&ServerState, &http::Request) -> http::Response> =
+        if request.path.starts_with("/api/") {
+            Some(api::handle)
+        } else if request.path.starts_with("/hooks/") {
+            Some(webhooks::handle)
+        } else {
+            None
+        };
+    let response = match handle {
+        Some(handle) => {
+            let body = http::read_body(&mut raw_stream, &mut request, &read);
+            match tokio::time::timeout(http::READ_TIMEOUT, body).await {
+                Ok(Ok(())) => handle(&state, &request),
+                Ok(Err(e)) => http::Response::error(400, e.to_string()),
+                Err(_) => http::Response::error(408, "the body was not sent in time"),
+            }
+        }
+        None => http::asset(&request),
+    };
+    http::respond(&mut raw_stream, response).await?;
+    Ok(())
+}
+
+/// refuse the handshake on paths other than the ones of [`http::WEBSOCKET_PATHS`]
+#[allow(clippy::result_large_err)]
+fn websocket_path(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if http::WEBSOCKET_PATHS.contains(&request.uri().path()) {
+        Ok(response)
+    } else {
+        let mut not_found = ErrorResponse::new(Some("not found".to_string()));
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        Err(not_found)
+    }
+}
+
+async fn handle_connection<S>(
+    state: ServerState,
+    raw_stream: S,
+    addr: SocketAddr,
+    user_id: u32,
+) -> eyre::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws_stream = tokio_tungstenite::accept_hdr_async(raw_stream, websocket_path).await?;
+    println!("WebSocket connection established: {}", addr);
+
+    // Insert the write part of this peer to the peer map.
+    let (tx, rx) = unbounded();
+
+    // the uploads of this connection that are not finished yet
+    let mut uploads: HashMap<u64, Upload> = HashMap::new();
+
+    let (outgoing, incoming) = ws_stream.split();
+    let broadcast_incoming = incoming.try_for_each(|msg| {
+        match &msg {
+            Message::Binary(frame) => {
+                println!("Received {} bytes from {}", frame.len(), addr)
+            }
+            _ => println!(
+                "Received a message from {}: {}",
+                addr,
+                msg.to_text().unwrap_or_default()
+            ),
+        }
+        match msg {
+            Message::Binary(frame) => {
+                let Some((upload_id, data)) = transfer::decode_chunk(&frame) else {
+                    return future::ok(());
+                };
+                let Some(upload) = uploads.get_mut(&upload_id) else {
+                    return future::ok(());
+                };
+                if let Err(reason) = upload.write_chunk(data) {
+                    uploads.remove(&upload_id);
+                    send(
+                        &tx,
+                        &WebSocketServerToClientMessage::Rejected {
+                            nonce: upload_id,
+                            reason,
+                        },
+                    );
+                }
+                future::ok(())
+            }
+            Message::Text(text) => {
+                // parsed before the peers are locked, a broken frame only fails itself
+                let message: WebSocketClientToServerMessage = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        let reason = format!("cannot read the message: {e}");
+                        send(
+                            &tx,
+                            &WebSocketServerToClientMessage::Rejected { nonce: 0, reason },
+                        );
+                        return future::ok(());
+                    }
+                };
+                let mut peers = state.peers.lock().unwrap();
+                if let Some(peer) = peers.get(&addr) {
+                    let (permission, room) = permissions::required(&message, &state.commands);
+                    let role = state
+                        .rooms
+                        .lock()
+                        .unwrap()
+                        .role(room.unwrap_or(&peer.room), &peer.name);
+                    let muted = permissions::posts(&message)
+                        .then(|| state.bans.lock().unwrap().muted(&peer.name))
+                        .flatten();
+                    let allowed = permissions::check(role, permission)
+                        .and_then(|_| muted.map_or(Ok(()), Err));
+                    if let Err(reason) = allowed {
+                        match permissions::nonce_of(&message) {
+                            Some(nonce) => send(
+                                &tx,
+                                &WebSocketServerToClientMessage::Rejected { nonce, reason },
+                            ),
+                            None => send(&tx, &WebSocketServerToClientMessage::Notice(reason)),
+                        }
+                        return future::ok(());
+                    }
+                }
+                match message {
+                    WebSocketClientToServerMessage::UserMessage(mut message_data) => {
+                        let Some(peer) = peers.get(&addr) else {
+                            return future::ok(());
+                        };
+                        // the server knows best who sent the message, private
+                        // messages are sent with `/msg`
+                        message_data.id = peer.id;
+                        message_data.name = peer.name.clone();
+                        message_data.recipient = None;
+                        message_data.kind = MessageKind::Normal;
+                        message_data.attachment = None;
+                        post(&state, &peers, &tx, addr, message_data);
+                    }
+                    WebSocketClientToServerMessage::Connect(user_name) => {
+                        connect(&state, &mut peers, &tx, addr, user_id, user_name, None);
+                    }
+                    WebSocketClientToServerMessage::Login { name, token } => {
+                        connect(&state, &mut peers, &tx, addr, user_id, name, Some(&token));
+                    }
+                    WebSocketClientToServerMessage::BeginUpload {
+                        upload_id,
+                        file_name,
+                        size,
+                        mime,
+                    } => {
+                        let Some(peer) = peers.get(&addr) else {
+                            return future::ok(());
+                        };
+                        match state
+                            .attachments
+                            .lock()
+                            .unwrap()
+                            .begin(&peer.name, upload_id, &file_name, size, &mime)
+                        {
+                            Ok(upload) => {
+                                uploads.insert(upload_id, upload);
+                            }
+                            Err(reason) => send(
+                                &tx,
+                                &WebSocketServerToClientMessage::Rejected {
+                                    nonce: upload_id,
+                                    reason,
+                                },
+                            ),
+                        }
+                    }
+                    WebSocketClientToServerMessage::FinishUpload { upload_id, sha256 } => {
+                        let (Some(upload), Some(peer)) =
+                            (uploads.remove(&upload_id), peers.get(&addr))
+                        else {
+                            return future::ok(());
+                        };
+                        let result = state
+                            .attachments
+                            .lock()
+                            .unwrap()
+                            .finish(&peer.name, upload, &sha256);
+                        match result {
+                            // the upload id is the nonce of the message, as it is
+                            // for the `Ack` and `Rejected` of the upload
+                            Ok(attachment) => {
+                                let message_data = MessageData {
+                                    id: peer.id,
+                                    name: peer.name.clone(),
+                                    data: attachment.file_name.clone(),
+                                    nonce: upload_id,
+                                    format: MessageFormat::Plain,
+                                    attachment: Some(attachment),
+                                    ..Default::default()
+                                };
+                                post(&state, &peers, &tx, addr, message_data);
+                            }
+                            Err(reason) => send(
+                                &tx,
+                                &WebSocketServerToClientMessage::Rejected {
+                                    nonce: upload_id,
+                                    reason,
+                                },
+                            ),
+                        }
+                    }
+                    WebSocketClientToServerMessage::Download {
+                        transfer_id,
+                        sha256,
+                    } => {
+                        let Some(peer) = peers.get(&addr) else {
+                            return future::ok(());
+                        };
+                        let rooms = state.rooms.lock().unwrap();
+                        let file =
+                            state
+                                .attachments
+                                .lock()
+                                .unwrap()
+                                .file(&sha256, &peer.name, |room| {
+                                    rooms
+                                        .get(room)
+                                        .is_some_and(|room| room.is_open_to(&peer.name))
+                                });
+                        drop(rooms);
+                        match file {
+                            Ok((path, size)) => {
+                                tokio::spawn(send_file(tx.clone(), transfer_id, path, size));
+                            }
+                            Err(reason) => send(
+                                &tx,
+                                &WebSocketServerToClientMessage::DownloadFailed {
+                                    transfer_id,
+                                    reason,
+                                },
+                            ),
+                        }
+                    }
+                    WebSocketClientToServerMessage::Command { nonce, name, args } => {
+                        let Some(peer) = peers.get(&addr) else {
+                            return future::ok(());
+                        };
+                        // the commands of the scripts are answered once they ran, without a lock
+                        let scripted = !state.commands.contains(&name)
+                            && !state.plugins.lock().unwrap().has_command(&name);
+                        if let Some(reply) = scripted
+                            .then(|| state.scripts.command(&peer.room, &peer.name, &name, &args))
+                            .flatten()
+                        {
+                            tokio::spawn(script_command(state.clone(), tx.clone(), nonce, reply));
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            state.commands.run(context, &name, &args)
+                        });
+                        answer(&tx, nonce, result);
+                    }
+                    WebSocketClientToServerMessage::SetTopic { nonce, room, topic } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        if let Err(reason) = with_context(&state, &mut peers, addr, |context| {
+                            commands::set_topic(context, &room, &topic)
+                        }) {
+                            send(
+                                &tx,
+                                &WebSocketServerToClientMessage::Rejected { nonce, reason },
+                            );
+                        }
+                    }
+                    WebSocketClientToServerMessage::Invite { room, user_id } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::invite(context, &room, user_id)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::AcceptInvite { room } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::answer_invite(context, &room, true)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::DeclineInvite { room } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::answer_invite(context, &room, false)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::SetRole {
+                        room,
+                        user_id,
+                        role,
+                    } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::set_role(context, &room, user_id, role)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::Kick { user_id, reason } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::kick(context, user_id, &reason)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::Ban {
+                        user_id,
+                        duration,
+                        reason,
+                        address,
+                    } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::ban(context, user_id, duration, &reason, address)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::Mute { user_id, duration } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::mute(context, user_id, duration)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::Unban { target } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::unban(context, &target)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::Report { message_id, reason } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let result = with_context(&state, &mut peers, addr, |context| {
+                            commands::report(context, message_id, &reason)
+                        });
+                        notify(&tx, result);
+                    }
+                    WebSocketClientToServerMessage::ListReports => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let reports = with_context(&state, &mut peers, addr, |context| {
+                            commands::list_reports(context)
+                        });
+                        send(&tx, &WebSocketServerToClientMessage::Reports(reports));
+                    }
+                    WebSocketClientToServerMessage::ResolveReport { report_id, action } => {
+                        if !peers.contains_key(&addr) {
+                            return future::ok(());
+                        }
+                        let (result, reports) = with_context(&state, &mut peers, addr, |context| {
+                            let result = commands::resolve_report(context, report_id, action);
+                            (result, commands::list_reports(context))
+                        });
+                        notify(&tx, result);
+                        send(&tx, &WebSocketServerToClientMessage::Reports(reports));
+                    }
+                    WebSocketClientToServerMessage::Formats(formats) => {
+                        if let Some(peer) = peers.get_mut(&addr) {
+                            peer.markdown = formats.contains(&MessageFormat::Markdown);
+                        }
+                    }
+                    WebSocketClientToServerMessage::ListRooms => {
+                        let Some(peer) = peers.get(&addr) else {
+                            return future::ok(());
+                        };
+                        let rooms = state.rooms.lock().unwrap().list(&peer.name);
+                        send(&tx, &WebSocketServerToClientMessage::RoomList(rooms));
+                    }
+                }
+
+                future::ok(())
+            }
+            _ => future::ok(()),
+        }
+    });
+
+    let receive_from_others = rx.map(Ok).forward(outgoing);
+
+    pin_mut!(broadcast_incoming, receive_from_others);
+    future::select(broadcast_incoming, receive_from_others).await;
+
+    info!("{} disconnected", &addr);
+    let mut peers = state.peers.lock().unwrap();
+    let Some(peer) = peers.remove(&addr) else {
+        // closed before connecting
+        return Ok(());
+    };
+    let message_server_to_client =
+        WebSocketServerToClientMessage::Disconnected(peer.id, peer.name.clone());
+    let msg = Message::Text(serde_json::to_string(&message_server_to_client).unwrap());
+    info!("Broadcasting message: {:?}", msg);
+    for peer in peers.values() {
+        let _ = peer.tx.unbounded_send(msg.clone());
+    }
+    let left = system_message(
+        format!("{} left", peer.name),
+        state.message_counter.fetch_add(1, Ordering::Relaxed),
+    );
+    for other in peers.values().filter(|other| other.room == peer.room) {
+        send(&other.tx, &left);
+    }
+    with_context(&state, &mut peers, addr, |context| {
+        context.plugin_event(ChatEvent::Left {
+            room: &peer.room,
+            user: &peer.name,
+        })
+    });
+    Ok(())
+}
+
+/// add the connection to the peers under the name and tell everyone
+fn connect(
+    state: &ServerState,
+    peers: &mut HashMap<SocketAddr, Peer>,
+    tx: &Tx,
+    addr: SocketAddr,
+    user_id: u32,
+    user_name: String,
+    token: Option<&str>,
+) {
+    if peers.contains_key(&addr) {
+        send(
+            tx,
+            &WebSocketServerToClientMessage::Notice("you are connected already".to_string()),
+        );
+        return;
+    }
+    if let Err(reason) = admit(state, peers, addr, &user_name, token) {
+        close(tx, reason);
+        return;
+    }
+    peers.insert(
+        addr,
+        Peer {
+            tx: tx.clone(),
+            id: user_id,
+            name: user_name.clone(),
+            room: LOBBY.to_string(),
+            markdown: false,
+        },
+    );
+
+    let recipient = peers.get(&addr).unwrap();
+    let message_server_to_client =
+        WebSocketServerToClientMessage::Connected(user_id, user_name.clone());
+    let recipient_others = peers
+        .iter()
+        .filter(|(peer_addr, _)| peer_addr != &&addr)
+        .map(|(_, peer)| &peer.tx);
+    let others_message = WebSocketServerToClientMessage::NewUserAdded(user_id, user_name.clone());
+    let all_usr_message = WebSocketServerToClientMessage::AllUsers(
+        peers
+            .values()
+            .map(|peer| (peer.id, peer.name.clone()))
+            .collect::<Vec<(u32, String)>>(),
+    );
+    let msg = Message::Text(serde_json::to_string(&message_server_to_client).unwrap());
+    let others_msg = Message::Text(serde_json::to_string(&others_message).unwrap());
+    info!("sending connected message: {:?}", msg);
+    let new_user_message = serde_json::to_string(&all_usr_message).unwrap();
+
+    recipient.tx.unbounded_send(msg).unwrap();
+    info!("sending all users message: {:?}", new_user_message);
+    recipient
+        .tx
+        .unbounded_send(Message::Text(new_user_message))
+        .unwrap();
+    send(
+        &recipient.tx,
+        &WebSocketServerToClientMessage::Joined {
+            room: state.rooms.lock().unwrap().info(LOBBY).unwrap(),
+        },
+    );
+    info!("sending new user message: {:?}", others_msg);
+    for recp in recipient_others {
+        let _ = recp.unbounded_send(others_msg.clone());
+    }
+    let joined = system_message(
+        format!("{user_name} joined"),
+        state.message_counter.fetch_add(1, Ordering::Relaxed),
+    );
+    for peer in peers.values().filter(|peer| peer.room == LOBBY) {
+        send(&peer.tx, &joined);
+    }
+    with_context(state, peers, addr, |context| {
+        commands::send_roles(context, LOBBY);
+        context.plugin_event(ChatEvent::Joined {
+            room: LOBBY,
+            user: &user_name,
+        });
+    });
+}
+
+/// why the user may not connect under the name, the names with a role above
+/// member are registered and need their token (see [`accounts`])
+fn admit(
+    state: &ServerState,
+    peers: &HashMap<SocketAddr, Peer>,
+    addr: SocketAddr,
+    name: &str,
+    token: Option<&str>,
+) -> Result<(), String> {
+    if let Some(ban) = state.bans.lock().unwrap().banned(name, addr.ip()) {
+        return Err(ban.describe());
+    }
+    if !command::is_valid_name(name) {
+        return Err("names are letters, digits, '-', '_' or '.'".to_string());
+    }
+    if peers.values().any(|peer| peer.name == name) {
+        return Err(format!("{name} is already taken"));
+    }
+    let accounts = state.accounts.lock().unwrap();
+    match token {
+        Some(token) if !accounts.verify(name, token) => Err(format!("wrong token for {name}")),
+        None if accounts.is_registered(name) => {
+            Err(format!("{name} is registered, connect with its token"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// let the plugins act on their own, e.g. for reminders
+async fn tick_plugins(state: ServerState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let peers = state.peers.lock().unwrap();
+        let actions = state.plugins.lock().unwrap().tick(SystemTime::now());
+        if !actions.is_empty() {
+            let mut reports = state.reports.lock().unwrap();
+            bots::perform(&peers, &state.message_counter, &mut reports, actions);
+        }
+    }
+}
+
+/// carry out the actions the scripts take on events
+async fn perform_script_actions(state: ServerState, mut actions: UnboundedReceiver<NamedActions>) {
+    while let Some(actions) = actions.next().await {
+        let peers = state.peers.lock().unwrap();
+        let mut reports = state.reports.lock().unwrap();
+        bots::perform(&peers, &state.message_counter, &mut reports, actions);
+    }
+}
+
+/// answer a command of the scripts once they ran it
+async fn script_command(
+    state: ServerState,
+    tx: Tx,
+    nonce: u64,
+    reply: oneshot::Receiver<CommandReply>,
+) {
+    let Ok((result, actions)) = reply.await else {
+        return;
+    };
+    {
+        let peers = state.peers.lock().unwrap();
+        let mut reports = state.reports.lock().unwrap();
+        bots::perform(&peers, &state.message_counter, &mut reports, actions);
+    }
+    answer(&tx, nonce, result);
+}
+
+/// send the output of a command, or why it failed
+fn answer(tx: &Tx, nonce: u64, result: CommandResult) {
+    match result {
+        Ok(text) if text.is_empty() => {}
+        Ok(text) => send(
+            tx,
+            &WebSocketServerToClientMessage::CommandOutput { nonce, text },
+        ),
+        Err(reason) => send(
+            tx,
+            &WebSocketServerToClientMessage::Rejected { nonce, reason },
+        ),
+    }
+}
+
+/// run `f` with the state a command may change, the connection must be in `peers`
+fn with_context<T>(
+    state: &ServerState,
+    peers: &mut HashMap<SocketAddr, Peer>,
+    addr: SocketAddr,
+    f: impl FnOnce(&mut Context) -> T,
+) -> T {
+    let mut rooms = state.rooms.lock().unwrap();
+    let mut bans = state.bans.lock().unwrap();
+    let mut reports = state.reports.lock().unwrap();
+    let mut plugins = state.plugins.lock().unwrap();
+    let mut accounts = state.accounts.lock().unwrap();
+    let mut filter = state.filter.lock().unwrap();
+    f(&mut Context {
+        addr,
+        peers,
+        rooms: &mut rooms,
+        moderation: &mut bans,
+        reports: &mut reports,
+        plugins: &mut plugins,
+        scripts: &state.scripts,
+        accounts: &mut accounts,
+        filter: &mut filter,
+        message_counter: &state.message_counter,
+        commands: &state.commands,
+    })
+}
+
+/// relay a message of the connection to its room and acknowledge it, a nonce
+/// that was relayed before is only acknowledged again
+fn post(
+    state: &ServerState,
+    peers: &HashMap<SocketAddr, Peer>,
+    tx: &Tx,
+    addr: SocketAddr,
+    message_data: MessageData,
+) {
+    let mut nonces = state.nonces.lock().unwrap();
+    let nonce = message_data.nonce;
+    if let Some(message_id) = nonces.get(&message_data.name, nonce) {
+        // already relayed before the client reconnected
+        send(
+            tx,
+            &WebSocketServerToClientMessage::Ack { nonce, message_id },
+        );
+        return;
+    }
+    let room = peers[&addr].room.clone();
+    let message_data = match relay(state, peers, &room, Some(addr), message_data) {
+        Ok(message_data) => message_data,
+        Err(reason) => {
+            send(
+                tx,
+                &WebSocketServerToClientMessage::Rejected { nonce, reason },
+            );
+            return;
+        }
+    };
+    nonces.insert(message_data.name.clone(), nonce, message_data.message_id);
+    drop(nonces);
+    if let Some(attachment) = &message_data.attachment {
+        state
+            .attachments
+            .lock()
+            .unwrap()
+            .posted(&attachment.sha256, &room);
+    }
+    // only acknowledge once the message is handed to every recipient
+    send(
+        tx,
+        &WebSocketServerToClientMessage::Ack {
+            nonce,
+            message_id: message_data.message_id,
+        },
+    );
+    relayed(state, peers, &room, &message_data);
+}
+
+/// pass a message of a user or bot through the content filter and the plugins
+/// and send it to everyone in the room but the sender, returning it with its
+/// message id
+fn relay(
+    state: &ServerState,
+    peers: &HashMap<SocketAddr, Peer>,
+    room: &str,
+    sender: Option<SocketAddr>,
+    mut message_data: MessageData,
+) -> Result<MessageData, String> {
+    if message_data.format == MessageFormat::Markdown {
+        message_data.data = markdown::sanitize(&message_data.data);
+    }
+    let flags = screen(&mut state.filter.lock().unwrap(), &mut message_data)?;
+    state.plugins.lock().unwrap().check(room, &message_data)?;
+    message_data.message_id = state.message_counter.fetch_add(1, Ordering::Relaxed);
+    let mut reports = state.reports.lock().unwrap();
+    reports.record(room, &message_data);
+    flag(&mut reports, &message_data, &flags);
+    drop(reports);
+    let msg = to_ws(&WebSocketServerToClientMessage::UserMessage(
+        message_data.clone(),
+    ));
+    // for the clients that do not render markdown
+    let plain = (message_data.format == MessageFormat::Markdown).then(|| {
+        to_ws(&WebSocketServerToClientMessage::UserMessage(MessageData {
+            data: message_data.plain_text(),
+            format: MessageFormat::Plain,
+            ..message_data.clone()
+        }))
+    });
+    for (_, other) in peers
+        .iter()
+        .filter(|(addr, other)| Some(**addr) != sender && other.room == room)
+    {
+        let msg = match &plain {
+            Some(plain) if !other.markdown => plain,
+            _ => &msg,
+        };
+        let _ = other.tx.unbounded_send(msg.clone());
+    }
+    Ok(message_data)
+}
+
+/// run the message through the content filter and mask what it masks, what
+/// it flagged is returned for [`flag`] once the message has its id
+fn screen(filter: &mut ContentFilter, message: &mut MessageData) -> Result<Vec<String>, String> {
+    let verdict = filter.check(&message.name, &message.data)?;
+    if let Some(masked) = verdict.masked {
+        message.data = masked;
+    }
+    Ok(verdict.flags)
+}
+
+/// report a recorded message the content filter flagged
+fn flag(reports: &mut Reports, message: &MessageData, flags: &[String]) {
+    if flags.is_empty() {
+        return;
+    }
+    if let Err(e) = reports.report(FILTER_REPORTER, message.message_id, &flags.join("; ")) {
+        warn!("cannot flag message {}: {}", message.message_id, e);
+    }
+}
+
+/// tell the plugins about a relayed message and carry out what they do about it
+fn relayed(
+    state: &ServerState,
+    peers: &HashMap<SocketAddr, Peer>,
+    room: &str,
+    message: &MessageData,
+) {
+    let actions = state
+        .plugins
+        .lock()
+        .unwrap()
+        .on_event(ChatEvent::Message { room, message });
+    state.scripts.event(ChatEvent::Message { room, message });
+    bots::perform(
+        peers,
+        &state.message_counter,
+        &mut state.reports.lock().unwrap(),
+        actions,
+    );
+}
+
+/// send a stored file in chunks, read in a task of its own so no lock is held
+async fn send_file(tx: Tx, transfer_id: u64, path: PathBuf, size: u64) {
+    let read = async {
+        let mut file = tokio::fs::File::open(&path).await?;
+        // the connection may be gone already, the receiver is dropped then
+        let _ = tx.unbounded_send(to_ws(&WebSocketServerToClientMessage::DownloadStarted {
+            transfer_id,
+            size,
+        }));
+        let mut chunk = vec![0; transfer::CHUNK_SIZE];
+        loop {
+            let length = file.read(&mut chunk).await?;
+            if length == 0 {
+                break;
+            }
+            let frame = Message::Binary(transfer::encode_chunk(transfer_id, &chunk[..length]));
+            if tx.unbounded_send(frame).is_err() {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    let finished = match read.await {
+        Ok(()) => WebSocketServerToClientMessage::DownloadFinished { transfer_id },
+        Err(e) => {
+            warn!("cannot read {}: {}", path.display(), e);
+            WebSocketServerToClientMessage::DownloadFailed {
+                transfer_id,
+                reason: "cannot read the attachment".to_string(),
+            }
+        }
+    };
+    let _ = tx.unbounded_send(to_ws(&finished));
+}
+
+/// tell the user the outcome of a request that has no nonce
+fn notify(tx: &Tx, result: Result<String, String>) {
+    match result {
+        Ok(text) if text.is_empty() => {}
+        Ok(text) | Err(text) => send(tx, &WebSocketServerToClientMessage::Notice(text)),
+    }
+}
+
+/// a system line about something that happened in a room
+fn system_message(text: String, message_id: u64) -> WebSocketServerToClientMessage {
+    WebSocketServerToClientMessage::UserMessage(MessageData {
+        data: text,
+        message_id,
+        kind: MessageKind::System,
+        ..Default::default()
+    })
+}
+
+fn to_ws(message: &WebSocketServerToClientMessage) -> Message {
+    Message::Text(serde_json::to_string(message).unwrap())
+}
+
+/// a connection that is closing dropped its receiver already and is about to
+/// leave the peers, what is sent to it is lost
+fn send(tx: &Tx, message: &WebSocketServerToClientMessage) {
+    let _ = tx.unbounded_send(to_ws(message));
+}
+
+/// close the connection, the reason is shown to the user
+fn close(tx: &Tx, reason: String) {
+    let frame = CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.into(),
+    };
+    let _ = tx.unbounded_send(Message::Close(Some(frame)));
+}
+
+/// the settings of a [`ChatServer`], every file of the server is kept in the data directory
+pub struct ServerBuilder {
+    data_dir: PathBuf,
+    max_upload_size: u64,
+    upload_quota: u64,
+    filter: Option<PathBuf>,
+    scripts: Option<PathBuf>,
+    api_keys: Option<PathBuf>,
+    /// the keys of the REST API given in code, name and key
+    fixed_keys: Vec<(String, String)>,
+    /// the users given a role in every room, in the order they were added
+    roles: Vec<(String, Role)>,
+    policies: ContentFilter,
+    plugins: Plugins,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("chatroom_data"),
+            max_upload_size: 20 * 1024 * 1024,
+            upload_quota: 200 * 1024 * 1024,
+            filter: None,
+            scripts: None,
+            api_keys: None,
+            fixed_keys: Vec::new(),
+            roles: Vec::new(),
+            policies: ContentFilter::default(),
+            plugins: Plugins::default(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// where the rooms, attachments, bans and reports are stored
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    /// the largest file a user may upload, in bytes
+    pub fn max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.max_upload_size = max_upload_size;
+        self
+    }
+
+    /// the total size of the files a single user may upload, in bytes
+    pub fn upload_quota(mut self, upload_quota: u64) -> Self {
+        self.upload_quota = upload_quota;
+        self
+    }
+
+    /// the rules of the word filter, `filter.json` in the data directory by default
+    pub fn filter_rules(mut self, path: impl Into<PathBuf>) -> Self {
+        self.filter = Some(path.into());
+        self
+    }
+
+    /// the directory of the scripts, `scripts` in the data directory by default
+    pub fn scripts(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.scripts = Some(dir.into());
+        self
+    }
+
+    /// the keys of the REST API, `api_keys.json` in the data directory by default
+    pub fn api_keys(mut self, path: impl Into<PathBuf>) -> Self {
+        self.api_keys = Some(path.into());
+        self
+    }
+
+    /// let the key post as the bot `bot:<name>`, in addition to the keys of the file
+    pub fn api_key(mut self, name: &str, key: &str) -> Self {
+        self.fixed_keys.push((name.to_string(), key.to_string()));
+        self
+    }
+
+    /// give the user the role in every room
+    pub fn role(mut self, user: &str, role: Role) -> Self {
+        self.roles.push((user.to_string(), role));
+        self
+    }
+
+    /// check every message with the policy after the word filter
+    pub fn policy(mut self, policy: impl ContentPolicy + 'static) -> Self {
+        self.policies.add(policy);
+        self
+    }
+
+    /// run an in-process bot, it sees every event before the scripts do and
+    /// runs while the connection waits, so it should answer quickly
+    pub fn plugin(mut self, plugin: impl ChatPlugin + 'static) -> Self {
+        self.plugins.register(plugin);
+        self
+    }
+
+    /// open the storage and listen on the address, port 0 picks a free port
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> io::Result<ChatServer> {
+        std::fs::create_dir_all(&self.data_dir)?;
+        let mut filter = ContentFilter::default();
+        filter.add(WordFilter::open(
+            self.filter
+                .unwrap_or_else(|| self.data_dir.join("filter.json")),
+        )?);
+        filter.add(self.policies);
+        let mut rooms = RoomRegistry::open(self.data_dir.join("rooms.json"))?;
+        for (user, role) in &self.roles {
+            rooms.set_server_role(user, *role);
+        }
+        let mut accounts = Accounts::open(self.data_dir.join("accounts.json"))?;
+        let tokens = rooms
+            .privileged()
+            .into_iter()
+            .filter_map(|user| Some((user.to_string(), accounts.register(user)?)))
+            .collect();
+        let (scripts, script_actions) = ScriptRunner::start(Scripts::open(
+            self.scripts
+                .unwrap_or_else(|| self.data_dir.join("scripts")),
+            self.data_dir.join("script_store.json"),
+        )?);
+        let state = ServerState {
+            peers: PeerMap::new(Mutex::new(HashMap::new())),
+            rooms: Rooms::new(Mutex::new(rooms)),
+            message_counter: MessageCounter::new(AtomicU64::new(1)),
+            nonces: Nonces::default(),
+            attachments: Attachments::new(Mutex::new(AttachmentStore::open(
+                self.data_dir.join("attachments"),
+                self.max_upload_size,
+                self.upload_quota,
+            )?)),
+            bans: Bans::new(Mutex::new(Moderation::open(
+                self.data_dir.join("bans.json"),
+            )?)),
+            reports: ReportQueue::new(Mutex::new(Reports::open(
+                self.data_dir.join("reports.json"),
+            )?)),
+            filter: Filter::new(Mutex::new(filter)),
+            plugins: PluginList::new(Mutex::new(self.plugins)),
+            scripts: Arc::new(scripts),
+            commands: Arc::new(Commands::with_builtins()),
+            api_keys: Keys::new(Mutex::new(ApiKeys::open(
+                self.api_keys
+                    .unwrap_or_else(|| self.data_dir.join("api_keys.json")),
+                self.fixed_keys,
+            )?)),
+            accounts: Names::new(Mutex::new(accounts)),
+        };
+        Ok(ChatServer {
+            listener: TcpListener::bind(addr).await?,
+            state,
+            tokens,
+            script_actions,
+            shutdown: Arc::default(),
+        })
+    }
+}
+
+/// a chat server listening for connections
+pub struct ChatServer {
+    listener: TcpListener,
+    state: ServerState,
+    /// the tokens of the names registered when the server started
+    tokens: Vec<(String, String)>,
+    /// the actions the scripts take on events, carried out while the server runs
+    script_actions: UnboundedReceiver<NamedActions>,
+    shutdown: Arc<Notify>,
+}
+
+/// stops the [`ChatServer`] it was taken from
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Notify>);
+
+impl ShutdownHandle {
+    /// stop accepting connections and close the connected ones, also if the
+    /// server is not running yet
+    pub fn shutdown(&self) {
+        self.0.notify_one();
+    }
+}
+
+impl ChatServer {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// the address the server listens on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// the names with a role that were registered when the server started and
+    /// their tokens, to hand to their users, who connect with a `Login`
+    pub fn tokens(&self) -> &[(String, String)] {
+        &self.tokens
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// accept connections until the server is shut down
+    pub async fn run(self) -> io::Result<()> {
+        let ticker = tokio::spawn(tick_plugins(self.state.clone()));
+        let scripts = tokio::spawn(perform_script_actions(
+            self.state.clone(),
+            self.script_actions,
+        ));
+        let mut user_id = 0;
+        let result = loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, addr)) => {
+                        tokio::spawn(serve_connection(self.state.clone(), stream, addr, user_id));
+                        user_id += 1;
+                    }
+                    Err(e) => break Err(e),
+                },
+                _ = self.shutdown.notified() => break Ok(()),
+            }
+        };
+        ticker.abort();
+        scripts.abort();
+        let frame = CloseFrame {
+            code: CloseCode::Away,
+            reason: "the server is shutting down".into(),
+        };
+        for peer in self.state.peers.lock().unwrap().values() {
+            let _ = peer.tx.unbounded_send(Message::Close(Some(frame.clone())));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{ChatClient, ClientEvent};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let data_dir = std::env::temp_dir().join(format!("server-test-{}", std::process::id()));
+        let server = ChatServer::builder()
+            .data_dir(&data_dir)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let shutdown = server.shutdown_handle();
+        let running = tokio::spawn(server.run());
+
+        let mut client = ChatClient::connect(&url, "alice").await.unwrap();
+        assert_eq!(client.name(), "alice");
+        shutdown.shutdown();
+        running.await.unwrap().unwrap();
+        loop {
+            match client.next_event().await {
+                Some(ClientEvent::Closed(reason)) => {
+                    assert_eq!(reason, "the server is shutting down");
+                    break;
+                }
+                Some(ClientEvent::Message(_)) => {}
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
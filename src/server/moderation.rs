@@ -0,0 +1,267 @@
+//! Bans and mutes.
+//!
+//! A ban refuses an account name or the addresses of a network, either for
+//! good or until it expires. Bans are written to a json file after every
+//! change so they survive a restart, mutes are only kept in memory.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::IpAddr,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// the widest networks that can be banned, a shorter prefix takes out whole providers
+const MIN_PREFIX_V4: u8 = 16;
+const MIN_PREFIX_V6: u8 = 48;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BanTarget {
+    Account(String),
+    Network(IpNet),
+}
+
+impl BanTarget {
+    /// an address or a network like `10.0.0.0/8`, otherwise an account name
+    pub fn parse(target: &str) -> Self {
+        if let Ok(network) = target.parse() {
+            BanTarget::Network(network)
+        } else if let Ok(address) = target.parse::<IpAddr>() {
+            BanTarget::Network(address.into())
+        } else {
+            BanTarget::Account(target.to_string())
+        }
+    }
+
+    /// whether the target is a network too wide to ban
+    pub fn too_wide(&self) -> bool {
+        match self {
+            BanTarget::Account(_) => false,
+            BanTarget::Network(IpNet::V4(network)) => network.prefix_len() < MIN_PREFIX_V4,
+            BanTarget::Network(IpNet::V6(network)) => network.prefix_len() < MIN_PREFIX_V6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    /// the name of the moderator
+    pub by: String,
+    /// seconds since the unix epoch, `None` for a ban that never expires
+    pub until: Option<u64>,
+}
+
+impl Ban {
+    /// why the connection is refused
+    pub fn describe(&self) -> String {
+        let until = match self.until {
+            Some(until) => format!(
+                " for another {}",
+                format_duration(until.saturating_sub(now()))
+            ),
+            None => String::new(),
+        };
+        match self.reason.as_str() {
+            "" => format!("you are banned{until}"),
+            reason => format!("you are banned{until}: {reason}"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Moderation {
+    /// where the bans are saved, `None` keeps them in memory only
+    path: Option<PathBuf>,
+    bans: Vec<Ban>,
+    /// when the mute of a user ends, keyed by user name
+    mutes: HashMap<String, u64>,
+}
+
+impl Moderation {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let bans = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path),
+            bans,
+            mutes: HashMap::new(),
+        })
+    }
+
+    /// ban the target for `duration` seconds, or for good if it is `None`
+    pub fn ban(&mut self, target: BanTarget, by: &str, reason: &str, duration: Option<u64>) {
+        self.bans.retain(|ban| ban.target != target);
+        self.bans.push(Ban {
+            target,
+            reason: reason.to_string(),
+            by: by.to_string(),
+            until: duration.map(|duration| now().saturating_add(duration)),
+        });
+        self.save();
+    }
+
+    /// lift the bans of the account or network, how many were lifted
+    pub fn unban(&mut self, target: &BanTarget) -> usize {
+        let count = self.bans.len();
+        self.bans.retain(|ban| &ban.target != target);
+        let lifted = count - self.bans.len();
+        if lifted > 0 {
+            self.save();
+        }
+        lifted
+    }
+
+    /// the ban refusing the user connecting from the address, if any
+    pub fn banned(&self, user: &str, address: IpAddr) -> Option<&Ban> {
+        let now = now();
+        self.bans
+            .iter()
+            .filter(|ban| ban.until.is_none_or(|until| until > now))
+            .find(|ban| match &ban.target {
+                BanTarget::Account(name) => name == user,
+                BanTarget::Network(network) => network.contains(&address),
+            })
+    }
+
+    /// mute the user for `duration` seconds, 0 lifts the mute
+    pub fn mute(&mut self, user: &str, duration: u64) {
+        if duration == 0 {
+            self.mutes.remove(user);
+        } else {
+            self.mutes
+                .insert(user.to_string(), now().saturating_add(duration));
+        }
+    }
+
+    /// why the messages of the user are dropped, if they are
+    pub fn muted(&self, user: &str) -> Option<String> {
+        let now = now();
+        self.mutes
+            .get(user)
+            .filter(|until| **until > now)
+            .map(|until| format!("you are muted for another {}", format_duration(until - now)))
+    }
+
+    fn save(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let now = now();
+        self.bans
+            .retain(|ban| ban.until.is_none_or(|until| until > now));
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&self.bans)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp, content))
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(e) = result {
+            warn!("cannot write the bans: {}", e);
+        }
+    }
+}
+
+/// a duration like `90`, `30s`, `10m`, `2h` or `7d`, in seconds
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => duration.split_at(index),
+        None => (duration, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+pub fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m", seconds.div_ceil(60)),
+        3600..=86399 => format!("{}h", seconds.div_ceil(3600)),
+        _ => format!("{}d", seconds.div_ceil(86400)),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bans() {
+        let path = std::env::temp_dir().join(format!("bans-test-{}.json", std::process::id()));
+        let local: IpAddr = "10.1.2.3".parse().unwrap();
+        let remote: IpAddr = "192.168.0.1".parse().unwrap();
+        let mut moderation = Moderation::open(&path).unwrap();
+        moderation.ban(BanTarget::parse("mallory"), "alice", "spam", None);
+        moderation.ban(BanTarget::parse("10.0.0.0/8"), "alice", "", Some(60));
+        assert!(moderation.banned("mallory", remote).is_some());
+        assert!(moderation.banned("bob", local).is_some());
+        assert!(moderation.banned("bob", remote).is_none());
+
+        let mut moderation = Moderation::open(&path).unwrap();
+        assert_eq!(
+            moderation.banned("mallory", remote).unwrap().describe(),
+            "you are banned: spam"
+        );
+        assert_eq!(moderation.unban(&BanTarget::parse("10.0.0.0/8")), 1);
+        assert!(moderation.banned("bob", local).is_none());
+        moderation.ban(BanTarget::parse("10.1.2.3"), "alice", "", Some(0));
+        assert!(moderation.banned("bob", local).is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mutes() {
+        let mut moderation = Moderation::default();
+        moderation.mute("bob", 300);
+        assert_eq!(
+            moderation.muted("bob").as_deref(),
+            Some("you are muted for another 5m")
+        );
+        moderation.mute("bob", 0);
+        assert!(moderation.muted("bob").is_none());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("10m"), Some(600));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("m"), None);
+    }
+
+    #[test]
+    fn test_too_wide() {
+        assert!(BanTarget::parse("0.0.0.0/0").too_wide());
+        assert!(BanTarget::parse("10.0.0.0/8").too_wide());
+        assert!(!BanTarget::parse("10.1.0.0/16").too_wide());
+        assert!(!BanTarget::parse("10.1.2.3").too_wide());
+        assert!(BanTarget::parse("2001:db8::/32").too_wide());
+        assert!(!BanTarget::parse("2001:db8::1").too_wide());
+        assert!(!BanTarget::parse("mallory").too_wide());
+    }
+}
@@ -0,0 +1,115 @@
+//! The permission check done before a client message is acted on.
+//!
+//! Every user has a [`Role`] in every room: the role given to them in the
+//! room, the role given to them for the whole server with `--owner` or
+//! `--moderator`, whichever is higher, and [`Role::Member`] if neither is set.
+
+use crate::{Role, WebSocketClientToServerMessage};
+
+use super::commands::Commands;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// send messages and run commands that change nothing, every role has it
+    Chat,
+    Upload,
+    Invite,
+    SetTopic,
+    /// kick, ban and mute users
+    Moderate,
+    /// change the description and who may join
+    ManageRoom,
+    SetRole,
+}
+
+impl Permission {
+    /// the least role that has the permission
+    pub fn role(self) -> Role {
+        match self {
+            Permission::Chat => Role::Guest,
+            Permission::Upload | Permission::Invite => Role::Member,
+            Permission::SetTopic | Permission::Moderate => Role::Moderator,
+            Permission::ManageRoom | Permission::SetRole => Role::Owner,
+        }
+    }
+}
+
+pub fn check(role: Role, permission: Permission) -> Result<(), String> {
+    if role >= permission.role() {
+        Ok(())
+    } else {
+        Err(format!(
+            "you are a {} here, this needs a {}",
+            role.name(),
+            permission.role().name()
+        ))
+    }
+}
+
+/// the permission needed to act on the message and the room it is needed in,
+/// `None` for the room the user is in
+pub fn required<'a>(
+    message: &'a WebSocketClientToServerMessage,
+    commands: &Commands,
+) -> (Permission, Option<&'a str>) {
+    match message {
+        WebSocketClientToServerMessage::BeginUpload { .. } => (Permission::Upload, None),
+        WebSocketClientToServerMessage::Command { name, .. } => (commands.permission(name), None),
+        WebSocketClientToServerMessage::SetTopic { room, .. } => (Permission::SetTopic, Some(room)),
+        WebSocketClientToServerMessage::Invite { room, .. } => (Permission::Invite, Some(room)),
+        WebSocketClientToServerMessage::SetRole { room, .. } => (Permission::SetRole, Some(room)),
+        WebSocketClientToServerMessage::Kick { .. }
+        | WebSocketClientToServerMessage::Ban { .. }
+        | WebSocketClientToServerMessage::Mute { .. }
+        | WebSocketClientToServerMessage::Unban { .. } => (Permission::Moderate, None),
+        WebSocketClientToServerMessage::UserMessage(_)
+        | WebSocketClientToServerMessage::Connect(_)
+        | WebSocketClientToServerMessage::Login { .. }
+        | WebSocketClientToServerMessage::Formats(_)
+        | WebSocketClientToServerMessage::FinishUpload { .. }
+        | WebSocketClientToServerMessage::Download { .. }
+        | WebSocketClientToServerMessage::AcceptInvite { .. }
+        | WebSocketClientToServerMessage::DeclineInvite { .. }
+        | WebSocketClientToServerMessage::ListRooms
+        | WebSocketClientToServerMessage::Report { .. } => (Permission::Chat, None),
+        // checked for the room of every report
+        WebSocketClientToServerMessage::ListReports
+        | WebSocketClientToServerMessage::ResolveReport { .. } => (Permission::Chat, None),
+    }
+}
+
+/// whether the message is shown to other users, which muted users may not do
+pub fn posts(message: &WebSocketClientToServerMessage) -> bool {
+    matches!(
+        message,
+        WebSocketClientToServerMessage::UserMessage(_)
+            | WebSocketClientToServerMessage::BeginUpload { .. }
+    )
+}
+
+/// the nonce a refusal of the message is reported with, `None` if it has none
+pub fn nonce_of(message: &WebSocketClientToServerMessage) -> Option<u64> {
+    match message {
+        WebSocketClientToServerMessage::UserMessage(data) => Some(data.nonce),
+        WebSocketClientToServerMessage::BeginUpload { upload_id, .. }
+        | WebSocketClientToServerMessage::FinishUpload { upload_id, .. } => Some(*upload_id),
+        WebSocketClientToServerMessage::Command { nonce, .. }
+        | WebSocketClientToServerMessage::SetTopic { nonce, .. } => Some(*nonce),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_are_ordered() {
+        assert!(check(Role::Guest, Permission::Chat).is_ok());
+        assert!(check(Role::Guest, Permission::Upload).is_err());
+        assert!(check(Role::Moderator, Permission::SetTopic).is_ok());
+        assert!(check(Role::Member, Permission::Moderate).is_err());
+        assert!(check(Role::Moderator, Permission::SetRole).is_err());
+        assert!(check(Role::Owner, Permission::SetRole).is_ok());
+    }
+}
@@ -0,0 +1,236 @@
+//! Reported messages waiting for a moderator.
+//!
+//! The server remembers the last messages relayed to the rooms so a report
+//! carries the messages around the reported one, they are also the history
+//! served by the REST API. Open reports are written to
+//! a json file after every change so they survive a restart.
+
+use std::{collections::VecDeque, fs, io, path::PathBuf};
+
+use crate::{MessageData, MessageKind, MessageReport};
+use tracing::warn;
+
+/// how many relayed messages are remembered for reports
+const RECENT_MESSAGES: usize = 1000;
+/// how many messages before and after the reported one are kept with it
+const CONTEXT: usize = 3;
+/// the longest reason of a report, in characters
+pub const MAX_REASON_LENGTH: usize = 256;
+
+#[derive(Default)]
+pub struct Reports {
+    /// where the reports are saved, `None` keeps them in memory only
+    path: Option<PathBuf>,
+    open: Vec<MessageReport>,
+    next_id: u64,
+    /// the room and the message, oldest first
+    recent: VecDeque<(String, MessageData)>,
+}
+
+impl Reports {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let open: Vec<MessageReport> = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let next_id = open.iter().map(|report| report.id + 1).max().unwrap_or(1);
+        Ok(Self {
+            path: Some(path),
+            open,
+            next_id,
+            recent: VecDeque::new(),
+        })
+    }
+
+    /// remember a message relayed to the room so it can be reported
+    pub fn record(&mut self, room: &str, message: &MessageData) {
+        if self.recent.len() == RECENT_MESSAGES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((room.to_string(), message.clone()));
+    }
+
+    /// the last `limit` messages relayed to the room, oldest first
+    pub fn history(&self, room: &str, limit: usize) -> Vec<MessageData> {
+        let mut messages: Vec<MessageData> = self
+            .recent
+            .iter()
+            .rev()
+            .filter(|(other, _)| other == room)
+            .take(limit)
+            .map(|(_, message)| message.clone())
+            .collect();
+        messages.reverse();
+        messages
+    }
+
+    /// the room of a recent message
+    pub fn room_of(&self, message_id: u64) -> Option<&str> {
+        self.recent
+            .iter()
+            .find(|(_, message)| message.message_id == message_id)
+            .map(|(room, _)| room.as_str())
+    }
+
+    pub fn report(&mut self, reporter: &str, message_id: u64, reason: &str) -> Result<(), String> {
+        if reason.chars().count() > MAX_REASON_LENGTH {
+            return Err(format!(
+                "the reason is longer than {MAX_REASON_LENGTH} characters"
+            ));
+        }
+        let Some(index) = self
+            .recent
+            .iter()
+            .position(|(_, message)| message.message_id == message_id)
+        else {
+            return Err("the message is too old to be reported".to_string());
+        };
+        let (room, message) = &self.recent[index];
+        if message.kind == MessageKind::System {
+            return Err("notices of the server cannot be reported".to_string());
+        }
+        if self
+            .open
+            .iter()
+            .any(|report| report.message.message_id == message_id && report.reporter == reporter)
+        {
+            return Err("you already reported the message".to_string());
+        }
+        let context = self
+            .recent
+            .iter()
+            .enumerate()
+            .skip(index.saturating_sub(CONTEXT))
+            .take_while(|(other, _)| *other <= index + CONTEXT)
+            .filter(|(_, (other_room, _))| other_room == room)
+            .map(|(_, (_, message))| message.clone())
+            .collect();
+        self.open.push(MessageReport {
+            id: self.next_id,
+            reporter: reporter.to_string(),
+            reason: reason.to_string(),
+            room: room.clone(),
+            message: message.clone(),
+            context,
+        });
+        self.next_id += 1;
+        self.save();
+        Ok(())
+    }
+
+    /// report a private message, it is not among the recent ones so it comes
+    /// without context and is filed under the room of the sender
+    pub fn report_private(
+        &mut self,
+        reporter: &str,
+        room: &str,
+        message: &MessageData,
+        reason: &str,
+    ) {
+        self.open.push(MessageReport {
+            id: self.next_id,
+            reporter: reporter.to_string(),
+            reason: reason.to_string(),
+            room: room.to_string(),
+            message: message.clone(),
+            context: Vec::new(),
+        });
+        self.next_id += 1;
+        self.save();
+    }
+
+    /// the open reports of the rooms `moderates` is true for
+    pub fn list(&self, moderates: impl Fn(&str) -> bool) -> Vec<MessageReport> {
+        self.open
+            .iter()
+            .filter(|report| moderates(&report.room))
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, report_id: u64) -> Option<&MessageReport> {
+        self.open.iter().find(|report| report.id == report_id)
+    }
+
+    /// close the report and every other report of the same message
+    pub fn resolve(&mut self, report_id: u64) {
+        let Some(message_id) = self.get(report_id).map(|report| report.message.message_id) else {
+            return;
+        };
+        self.open
+            .retain(|report| report.message.message_id != message_id);
+        self.save();
+    }
+
+    /// forget a deleted message so it is not shown with later reports
+    pub fn delete(&mut self, message_id: u64) {
+        self.recent
+            .retain(|(_, message)| message.message_id != message_id);
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&self.open)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp, content))
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(e) = result {
+            warn!("cannot write the reports: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(message_id: u64, data: &str) -> MessageData {
+        MessageData {
+            name: "mallory".to_string(),
+            data: data.to_string(),
+            message_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reports() {
+        let path = std::env::temp_dir().join(format!("reports-test-{}.json", std::process::id()));
+        let mut reports = Reports::open(&path).unwrap();
+        for message_id in 1..=10 {
+            let room = if message_id == 6 { "other" } else { "rust" };
+            reports.record(room, &message(message_id, "hi"));
+        }
+        let history: Vec<u64> = reports
+            .history("rust", 3)
+            .iter()
+            .map(|message| message.message_id)
+            .collect();
+        assert_eq!(history, [8, 9, 10]);
+        assert_eq!(reports.history("other", 50).len(), 1);
+        assert!(reports.report("alice", 42, "spam").is_err());
+        reports.report("alice", 5, "spam").unwrap();
+        assert!(reports.report("alice", 5, "spam").is_err());
+        reports.report("bob", 5, "rude").unwrap();
+
+        let mut reports = Reports::open(&path).unwrap();
+        let open = reports.list(|room| room == "rust");
+        assert_eq!(open.len(), 2);
+        let context: Vec<u64> = open[0]
+            .context
+            .iter()
+            .map(|message| message.message_id)
+            .collect();
+        assert_eq!(context, [2, 3, 4, 5, 7, 8]);
+        assert!(reports.list(|room| room == "other").is_empty());
+        reports.resolve(open[0].id);
+        assert!(reports.list(|_| true).is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
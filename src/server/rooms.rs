@@ -0,0 +1,390 @@
+//! The rooms of the server and their metadata.
+//!
+//! A room is created by the first user joining it. The registry is written to
+//! a json file after every change so topics survive a restart.
+//!
+//! Private rooms can only be joined by their members, users become members by
+//! accepting an invitation. Password protected rooms can be joined by anyone
+//! knowing the password, who is a member from then on.
+//!
+//! The creator of a room owns it until an owner gives them another role.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{command::LOBBY, transfer, Role, RoomInfo, RoomVisibility};
+use serde::{Deserialize, Serialize};
+
+use super::{api::same, webhooks::Webhook};
+use tracing::warn;
+
+/// the longest topic or description of a room, in characters
+pub const MAX_TOPIC_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Room {
+    pub topic: String,
+    pub description: String,
+    /// the name of the user that created the room, empty for the lobby
+    pub created_by: String,
+    /// seconds since the unix epoch
+    pub created_at: u64,
+    #[serde(default)]
+    pub visibility: RoomVisibility,
+    /// set for password protected rooms
+    #[serde(default)]
+    password: Option<Password>,
+    /// the names of the users that may join the room even if it is not public
+    #[serde(default)]
+    pub members: BTreeSet<String>,
+    /// the names of the users with a pending invitation
+    #[serde(default)]
+    pub invited: BTreeSet<String>,
+    /// the roles given in the room, keyed by user name
+    #[serde(default)]
+    pub roles: BTreeMap<String, Role>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<Webhook>,
+}
+
+/// a salted sha-256 of the password of a room
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Password {
+    salt: String,
+    hash: String,
+}
+
+impl Password {
+    fn new(password: &str) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+        let salt = transfer::sha256_hex(format!("{nanos}{password}").as_bytes())[..16].to_string();
+        Self {
+            hash: Self::hash(&salt, password),
+            salt,
+        }
+    }
+
+    fn hash(salt: &str, password: &str) -> String {
+        transfer::sha256_hex(format!("{salt}{password}").as_bytes())
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        Self::hash(&self.salt, password) == self.hash
+    }
+}
+
+impl Room {
+    /// whether the room shows up in the room list of the user
+    pub fn is_listed_for(&self, user: &str) -> bool {
+        self.visibility != RoomVisibility::Private || self.members.contains(user)
+    }
+
+    /// whether the user may read and post without joining, as bots of the REST API do
+    pub fn is_open_to(&self, user: &str) -> bool {
+        self.visibility == RoomVisibility::Public || self.members.contains(user)
+    }
+
+    /// the role given to the user in the room, `None` if no role was given
+    pub fn role_of(&self, user: &str) -> Option<Role> {
+        match self.roles.get(user) {
+            Some(role) => Some(*role),
+            None if !self.created_by.is_empty() && self.created_by == user => Some(Role::Owner),
+            None => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RoomRegistry {
+    /// where the rooms are saved, `None` keeps them in memory only
+    path: Option<PathBuf>,
+    rooms: BTreeMap<String, Room>,
+    /// the roles given for every room from the command line, never saved
+    server_roles: HashMap<String, Role>,
+}
+
+impl RoomRegistry {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let rooms = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        let mut registry = Self {
+            path: Some(path),
+            rooms,
+            server_roles: HashMap::new(),
+        };
+        registry.get_or_create(LOBBY, "");
+        Ok(registry)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    /// the room, created by the user if it does not exist yet
+    pub fn get_or_create(&mut self, name: &str, user: &str) -> &Room {
+        if !self.rooms.contains_key(name) {
+            let created_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            self.rooms.insert(
+                name.to_string(),
+                Room {
+                    created_by: user.to_string(),
+                    created_at,
+                    members: BTreeSet::from([user.to_string()]),
+                    ..Default::default()
+                },
+            );
+            self.save();
+        }
+        &self.rooms[name]
+    }
+
+    pub fn info(&self, name: &str) -> Option<RoomInfo> {
+        self.rooms.get(name).map(|room| RoomInfo {
+            name: name.to_string(),
+            topic: room.topic.clone(),
+            description: room.description.clone(),
+            created_by: room.created_by.clone(),
+            created_at: room.created_at,
+            visibility: room.visibility,
+        })
+    }
+
+    /// the rooms listed for the user
+    pub fn list(&self, user: &str) -> Vec<RoomInfo> {
+        self.rooms
+            .iter()
+            .filter(|(_, room)| room.is_listed_for(user))
+            .filter_map(|(name, _)| self.info(name))
+            .collect()
+    }
+
+    /// check whether the user may join the existing room, making them a member
+    pub fn enter(&mut self, name: &str, user: &str, password: &str) -> Result<(), String> {
+        let room = self
+            .rooms
+            .get_mut(name)
+            .ok_or_else(|| format!("{name} does not exist"))?;
+        if room.members.contains(user) || room.visibility == RoomVisibility::Public {
+            return Ok(());
+        }
+        match &room.password {
+            Some(expected) if room.visibility == RoomVisibility::Password => {
+                if !expected.matches(password) {
+                    return Err(format!("wrong password for {name}"));
+                }
+            }
+            _ => return Err(format!("{name} is invite only")),
+        }
+        room.members.insert(user.to_string());
+        self.save();
+        Ok(())
+    }
+
+    /// set the visibility, password protected rooms need a password
+    pub fn set_visibility(
+        &mut self,
+        name: &str,
+        visibility: RoomVisibility,
+        password: &str,
+    ) -> Result<RoomInfo, String> {
+        if name == LOBBY {
+            return Err("the lobby is always public".to_string());
+        }
+        if visibility == RoomVisibility::Password && password.is_empty() {
+            return Err("a password is needed".to_string());
+        }
+        self.update(name, |room| {
+            room.visibility = visibility;
+            room.password =
+                (visibility == RoomVisibility::Password).then(|| Password::new(password));
+        })
+        .ok_or_else(|| format!("{name} does not exist"))
+    }
+
+    /// record a pending invitation of the user
+    pub fn invite(&mut self, name: &str, user: &str) -> Result<(), String> {
+        let room = self
+            .rooms
+            .get_mut(name)
+            .ok_or_else(|| format!("{name} does not exist"))?;
+        if room.members.contains(user) {
+            return Err(format!("{user} is already a member of {name}"));
+        }
+        room.invited.insert(user.to_string());
+        self.save();
+        Ok(())
+    }
+
+    /// remove the pending invitation, making the user a member if it is accepted
+    pub fn answer(&mut self, name: &str, user: &str, accept: bool) -> Result<(), String> {
+        let Some(room) = self
+            .rooms
+            .get_mut(name)
+            .filter(|room| room.invited.contains(user))
+        else {
+            return Err(format!("you are not invited to {name}"));
+        };
+        room.invited.remove(user);
+        if accept {
+            room.members.insert(user.to_string());
+        }
+        self.save();
+        Ok(())
+    }
+
+    /// give the user the role in every room
+    pub fn set_server_role(&mut self, user: &str, role: Role) {
+        self.server_roles.insert(user.to_string(), role);
+    }
+
+    /// the role of the user for the whole server
+    pub fn server_role(&self, user: &str) -> Role {
+        self.server_roles.get(user).copied().unwrap_or_default()
+    }
+
+    /// the role of the user in the room, the higher of the role in the room
+    /// and the role for the whole server
+    pub fn role(&self, room: &str, user: &str) -> Role {
+        let in_room = self.rooms.get(room).and_then(|room| room.role_of(user));
+        let on_server = self.server_roles.get(user).copied();
+        in_room.max(on_server).unwrap_or_default()
+    }
+
+    /// the names holding a role above member in some room
+    pub fn privileged(&self) -> BTreeSet<&str> {
+        let in_rooms = self.rooms.values().flat_map(|room| {
+            room.roles
+                .keys()
+                .chain([&room.created_by])
+                .filter(|user| room.role_of(user) > Some(Role::Member))
+                .map(String::as_str)
+        });
+        self.server_roles
+            .iter()
+            .filter(|(_, role)| **role > Role::Member)
+            .map(|(user, _)| user.as_str())
+            .chain(in_rooms)
+            .collect()
+    }
+
+    pub fn set_role(&mut self, name: &str, user: &str, role: Role) -> Result<(), String> {
+        self.update(name, |room| {
+            room.roles.insert(user.to_string(), role);
+        })
+        .map(|_| ())
+        .ok_or_else(|| format!("{name} does not exist"))
+    }
+
+    /// the room the webhook with the token posts to and the webhook
+    pub fn webhook(&self, token: &str) -> Option<(String, Webhook)> {
+        self.rooms.iter().find_map(|(name, room)| {
+            room.webhooks
+                .iter()
+                .find(|webhook| same(&webhook.token, token))
+                .map(|webhook| (name.clone(), webhook.clone()))
+        })
+    }
+
+    /// change the room and save the registry
+    pub fn update(&mut self, name: &str, change: impl FnOnce(&mut Room)) -> Option<RoomInfo> {
+        change(self.rooms.get_mut(name)?);
+        self.save();
+        self.info(name)
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&self.rooms)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp, content))
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(e) = result {
+            warn!("cannot write the rooms: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rooms_survive_reopen() {
+        let path = std::env::temp_dir().join(format!("rooms-test-{}.json", std::process::id()));
+        let mut rooms = RoomRegistry::open(&path).unwrap();
+        assert!(rooms.get(LOBBY).is_some());
+        rooms.get_or_create("rust", "alice");
+        rooms.update("rust", |room| room.topic = "borrowing".to_string());
+
+        let rooms = RoomRegistry::open(&path).unwrap();
+        let info = rooms.info("rust").unwrap();
+        assert_eq!(info.topic, "borrowing");
+        assert_eq!(info.created_by, "alice");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_private_and_password_rooms() {
+        let mut rooms = RoomRegistry::default();
+        rooms.get_or_create("secret", "alice");
+        rooms
+            .set_visibility("secret", RoomVisibility::Private, "")
+            .unwrap();
+        assert!(rooms.enter("secret", "alice", "").is_ok());
+        assert!(rooms.enter("secret", "bob", "").is_err());
+        rooms.invite("secret", "carol").unwrap();
+        assert!(rooms.answer("secret", "dave", true).is_err());
+        rooms.answer("secret", "carol", true).unwrap();
+        assert!(rooms.enter("secret", "carol", "").is_ok());
+        assert!(rooms.list("bob").iter().all(|room| room.name != "secret"));
+        assert!(rooms.list("alice").iter().any(|room| room.name == "secret"));
+
+        rooms
+            .set_visibility("secret", RoomVisibility::Password, "hunter2")
+            .unwrap();
+        assert!(rooms.enter("secret", "bob", "wrong").is_err());
+        assert!(rooms.enter("secret", "bob", "hunter2").is_ok());
+        // bob is a member now
+        assert!(rooms.enter("secret", "bob", "").is_ok());
+        assert!(rooms
+            .set_visibility(LOBBY, RoomVisibility::Private, "")
+            .is_err());
+    }
+
+    #[test]
+    fn test_roles() {
+        let mut rooms = RoomRegistry::default();
+        rooms.get_or_create("rust", "alice");
+        assert_eq!(rooms.role("rust", "alice"), Role::Owner);
+        assert_eq!(rooms.role("rust", "bob"), Role::Member);
+        rooms.set_role("rust", "bob", Role::Guest).unwrap();
+        assert_eq!(rooms.role("rust", "bob"), Role::Guest);
+        rooms.set_role("rust", "alice", Role::Member).unwrap();
+        assert_eq!(rooms.role("rust", "alice"), Role::Member);
+        // the role for the server wins if it is higher
+        rooms.set_server_role("bob", Role::Moderator);
+        assert_eq!(rooms.role("rust", "bob"), Role::Moderator);
+        assert_eq!(rooms.role("anywhere", "bob"), Role::Moderator);
+        rooms.set_role("rust", "carol", Role::Owner).unwrap();
+        assert_eq!(rooms.privileged(), BTreeSet::from(["bob", "carol"]));
+        assert!(rooms.set_role("nowhere", "bob", Role::Owner).is_err());
+    }
+}
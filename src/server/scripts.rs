@@ -0,0 +1,814 @@
+//! Slash commands and event handlers written by the operator in Rhai.
+//!
+//! Every `*.rhai` file in the script directory is a script named after the
+//! file. At the top level a script registers its commands, each command calls
+//! the function of the same name with the room, the user and the arguments:
+//!
+//! ```rhai
+//! command("roll", "/roll [sides]", "roll a die");
+//!
+//! fn roll(room, user, args) {
+//!     let sides = if args == "" { 6 } else { parse_int(args) };
+//!     say(room, `${user} rolled ${rand_between(1, sides)}`);
+//!     ""
+//! }
+//!
+//! fn on_join(room, user) {
+//!     let seen = store_get(user);
+//!     store_set(user, "yes");
+//!     if seen == "" { reply(user, `welcome to ${room}`) }
+//! }
+//! ```
+//!
+//! The text a command returns is shown to the user who ran it. The handlers
+//! `on_join(room, user)`, `on_leave(room, user)`, `on_rename(room, old, new)`
+//! and `on_message(room, user, text)` are called for the events of the rooms.
+//! Scripts can only reach the server through `say(room, text)`,
+//! `reply(user, text)`, `user(name)` (the room of an online user, `""` if
+//! offline) and `store_get(key)` / `store_set(key, value)`, a key/value store
+//! of their own saved in a json file. A call is stopped once it runs too many
+//! operations or for too long, scripts cannot import modules or `eval`. The
+//! directory is read again whenever a script changes.
+//!
+//! The scripts run on a thread of their own with [`ScriptRunner`], so a slow
+//! script holds up no connection: what they say follows the event shortly
+//! after, and the store is written at most once every second.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    command,
+    plugin::{
+        ChatEvent, ChatPlugin, NamedActions, PluginAction, PluginActions, PluginCommand, Plugins,
+    },
+    MessageData,
+};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use rhai::{
+    module_resolvers::DummyModuleResolver, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs,
+    Scope, AST,
+};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+/// how often the script directory is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// how often the store is written at most
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+/// how long a single call into a script may run
+const TIME_LIMIT: Duration = Duration::from_millis(100);
+/// how many operations a single call into a script may run
+const MAX_OPERATIONS: u64 = 1_000_000;
+/// how many keys a script may keep in its store
+const MAX_STORE_KEYS: usize = 1000;
+
+/// what the functions of the API share with the plugin while a script runs
+#[derive(Default)]
+struct Shared {
+    /// the name of the running script
+    script: String,
+    deadline: Option<Instant>,
+    actions: Vec<PluginAction>,
+    /// the commands registered while a script is loaded
+    commands: Vec<PluginCommand>,
+    /// the room of every online user
+    users: HashMap<String, String>,
+    /// the keys and values of every script
+    store: BTreeMap<String, BTreeMap<String, String>>,
+    store_path: PathBuf,
+    /// whether the store changed since it was written
+    dirty: bool,
+    saved: Option<Instant>,
+}
+
+impl Shared {
+    /// write the store if it changed, unless it was written less than
+    /// `SAVE_INTERVAL` ago and `now` is not set
+    fn flush(&mut self, now: bool) {
+        let due = now
+            || self
+                .saved
+                .is_none_or(|saved| saved.elapsed() >= SAVE_INTERVAL);
+        if self.dirty && due {
+            self.save_store();
+            self.dirty = false;
+            self.saved = Some(Instant::now());
+        }
+    }
+
+    fn save_store(&self) {
+        let tmp = self.store_path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&self.store)
+            .map_err(io::Error::from)
+            .and_then(|content| fs::write(&tmp, content))
+            .and_then(|_| fs::rename(&tmp, &self.store_path));
+        if let Err(e) = result {
+            warn!("cannot write the script store: {}", e);
+        }
+    }
+}
+
+struct Script {
+    name: String,
+    path: PathBuf,
+    ast: AST,
+    commands: Vec<PluginCommand>,
+}
+
+impl Script {
+    fn has_fn(&self, name: &str, params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|function| function.name == name && function.params.len() == params)
+    }
+}
+
+/// the scripts of the directory, a single plugin to the server
+pub struct Scripts {
+    dir: PathBuf,
+    engine: Engine,
+    scripts: Vec<Script>,
+    shared: Arc<Mutex<Shared>>,
+    /// the files and modification times the scripts were read from
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+    checked: Instant,
+}
+
+impl Scripts {
+    /// read the scripts of the directory, a missing directory is read once it is created
+    pub fn open(dir: impl Into<PathBuf>, store_path: impl Into<PathBuf>) -> io::Result<Self> {
+        let store_path = store_path.into();
+        let store = match fs::read_to_string(&store_path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        let shared = Arc::new(Mutex::new(Shared {
+            store,
+            store_path,
+            ..Default::default()
+        }));
+        let mut scripts = Self {
+            dir: dir.into(),
+            engine: engine(&shared),
+            scripts: Vec::new(),
+            shared,
+            modified: Vec::new(),
+            checked: Instant::now(),
+        };
+        scripts.load()?;
+        Ok(scripts)
+    }
+
+    /// the script files of the directory, sorted by path
+    fn files(&self) -> io::Result<Vec<(PathBuf, Option<SystemTime>)>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "rhai")
+            {
+                let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+                files.push((path, modified.ok()));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// read every script, a broken script keeps its last working version
+    fn load(&mut self) -> io::Result<()> {
+        let files = self.files()?;
+        let mut scripts = Vec::new();
+        for (path, _) in &files {
+            match self.compile(path) {
+                Ok(script) => scripts.push(script),
+                Err(e) => {
+                    warn!("cannot load the script {}: {}", path.display(), e);
+                    if let Some(index) = self.scripts.iter().position(|old| &old.path == path) {
+                        scripts.push(self.scripts.swap_remove(index));
+                    }
+                }
+            }
+        }
+        self.scripts = scripts;
+        self.modified = files;
+        info!("loaded {} scripts", self.scripts.len());
+        Ok(())
+    }
+
+    /// compile the script and run its top level to register its commands
+    fn compile(&self, path: &Path) -> Result<Script, String> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| command::is_valid_name(stem))
+            .ok_or("the name of the file is not a valid name")?
+            .to_string();
+        let ast = self
+            .engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| e.to_string())?;
+        self.start(&name);
+        let result = self.engine.run_ast_with_scope(&mut Scope::new(), &ast);
+        let commands = std::mem::take(&mut self.shared.lock().unwrap().commands);
+        result.map_err(|e| e.to_string())?;
+        let script = Script {
+            name,
+            path: path.to_path_buf(),
+            ast,
+            commands,
+        };
+        if let Some(command) = script
+            .commands
+            .iter()
+            .find(|command| !script.has_fn(&command.name, 3))
+        {
+            return Err(format!(
+                "the command {} has no function {}(room, user, args)",
+                command.name, command.name
+            ));
+        }
+        Ok(script)
+    }
+
+    /// read the scripts again if a file changed
+    fn reload(&mut self) {
+        if self.checked.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+        match self.files() {
+            Ok(files) if files == self.modified => {}
+            Ok(_) => {
+                if let Err(e) = self.load() {
+                    warn!("cannot read the scripts: {}", e);
+                }
+            }
+            Err(e) => warn!("cannot read the scripts: {}", e),
+        }
+    }
+
+    /// prepare the shared state for a call into the script
+    fn start(&self, name: &str) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.script = name.to_string();
+        shared.deadline = Some(Instant::now() + TIME_LIMIT);
+        shared.actions.clear();
+        shared.commands.clear();
+    }
+
+    /// call the function of the script, the actions it took are added to `actions`
+    fn call(
+        &self,
+        script: &Script,
+        function: &str,
+        args: impl FuncArgs,
+        actions: &mut PluginActions,
+    ) -> Result<Dynamic, String> {
+        self.start(&script.name);
+        let options = CallFnOptions::new().eval_ast(false).rewind_scope(true);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &script.ast,
+            function,
+            args,
+        );
+        let mut shared = self.shared.lock().unwrap();
+        shared.deadline = None;
+        for action in shared.actions.drain(..) {
+            match action {
+                PluginAction::Say { room, text } => actions.say(&room, text),
+                PluginAction::Reply { user, text } => actions.reply(&user, text),
+            }
+        }
+        result.map_err(|e| {
+            warn!("the script {} failed in {}: {}", script.name, function, e);
+            format!("the script {} failed: {}", script.name, e)
+        })
+    }
+
+    /// call the handler of every script that has it
+    fn handle(&self, function: &str, args: &[&str], actions: &mut PluginActions) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        for script in self
+            .scripts
+            .iter()
+            .filter(|script| script.has_fn(function, args.len()))
+        {
+            let _ = self.call(script, function, args.clone(), actions);
+        }
+    }
+}
+
+impl ChatPlugin for Scripts {
+    fn name(&self) -> &str {
+        "scripts"
+    }
+
+    fn on_event(&mut self, event: ChatEvent, actions: &mut PluginActions) {
+        self.reload();
+        let mut shared = self.shared.lock().unwrap();
+        match event {
+            ChatEvent::Joined { room, user } => {
+                shared.users.insert(user.to_string(), room.to_string());
+                drop(shared);
+                self.handle("on_join", &[room, user], actions);
+            }
+            ChatEvent::Left { room, user } => {
+                if shared
+                    .users
+                    .get(user)
+                    .is_some_and(|current| current == room)
+                {
+                    shared.users.remove(user);
+                }
+                drop(shared);
+                self.handle("on_leave", &[room, user], actions);
+            }
+            ChatEvent::Renamed { room, old, new } => {
+                shared.users.remove(old);
+                shared.users.insert(new.to_string(), room.to_string());
+                drop(shared);
+                self.handle("on_rename", &[room, old, new], actions);
+            }
+            ChatEvent::Message { room, message } => {
+                drop(shared);
+                let args = [room, message.name.as_str(), message.data.as_str()];
+                self.handle("on_message", &args, actions);
+            }
+        }
+    }
+
+    fn commands(&self) -> Vec<PluginCommand> {
+        self.scripts
+            .iter()
+            .flat_map(|script| script.commands.iter().cloned())
+            .collect()
+    }
+
+    fn on_command(
+        &mut self,
+        room: &str,
+        user: &str,
+        name: &str,
+        args: &str,
+        actions: &mut PluginActions,
+    ) -> Option<Result<String, String>> {
+        self.reload();
+        let script = self
+            .scripts
+            .iter()
+            .find(|script| script.commands.iter().any(|command| command.name == name))?;
+        let args = (room.to_string(), user.to_string(), args.to_string());
+        Some(self.call(script, name, args, actions).map(|text| {
+            if text.is_unit() {
+                String::new()
+            } else {
+                text.to_string()
+            }
+        }))
+    }
+}
+
+/// a [`ChatEvent`] owning its data, to be sent to the thread of the scripts
+enum Event {
+    Joined {
+        room: String,
+        user: String,
+    },
+    Left {
+        room: String,
+        user: String,
+    },
+    Renamed {
+        room: String,
+        old: String,
+        new: String,
+    },
+    Message {
+        room: String,
+        message: MessageData,
+    },
+}
+
+impl Event {
+    fn new(event: ChatEvent) -> Self {
+        match event {
+            ChatEvent::Joined { room, user } => Self::Joined {
+                room: room.to_string(),
+                user: user.to_string(),
+            },
+            ChatEvent::Left { room, user } => Self::Left {
+                room: room.to_string(),
+                user: user.to_string(),
+            },
+            ChatEvent::Renamed { room, old, new } => Self::Renamed {
+                room: room.to_string(),
+                old: old.to_string(),
+                new: new.to_string(),
+            },
+            ChatEvent::Message { room, message } => Self::Message {
+                room: room.to_string(),
+                message: message.clone(),
+            },
+        }
+    }
+
+    fn as_event(&self) -> ChatEvent<'_> {
+        match self {
+            Self::Joined { room, user } => ChatEvent::Joined { room, user },
+            Self::Left { room, user } => ChatEvent::Left { room, user },
+            Self::Renamed { room, old, new } => ChatEvent::Renamed { room, old, new },
+            Self::Message { room, message } => ChatEvent::Message { room, message },
+        }
+    }
+}
+
+/// the text of a command for the user who ran it, and the actions it took
+pub type CommandReply = (Result<String, String>, NamedActions);
+
+enum Job {
+    Event(Event),
+    Command {
+        room: String,
+        user: String,
+        name: String,
+        args: String,
+        reply: oneshot::Sender<CommandReply>,
+    },
+}
+
+/// the scripts running on a thread of their own
+#[derive(Default)]
+pub struct ScriptRunner {
+    /// `None` if no scripts run
+    jobs: Option<mpsc::Sender<Job>>,
+    /// the commands of the scripts as of their last call
+    commands: Arc<Mutex<Vec<PluginCommand>>>,
+}
+
+impl ScriptRunner {
+    /// run the scripts on a new thread, the actions they take on events are
+    /// sent to the receiver; the thread ends once the runner is dropped
+    pub fn start(scripts: Scripts) -> (Self, UnboundedReceiver<NamedActions>) {
+        let (jobs, queue) = mpsc::channel();
+        let (actions, receiver) = unbounded();
+        let shared = scripts.shared.clone();
+        let commands = Arc::new(Mutex::new(scripts.commands()));
+        let mut plugins = Plugins::default();
+        plugins.register(scripts);
+        let listed = commands.clone();
+        thread::spawn(move || run(plugins, &shared, &listed, &queue, &actions));
+        let runner = Self {
+            jobs: Some(jobs),
+            commands,
+        };
+        (runner, receiver)
+    }
+
+    /// tell the scripts about the event
+    pub fn event(&self, event: ChatEvent) {
+        self.send(Job::Event(Event::new(event)));
+    }
+
+    pub fn commands(&self) -> Vec<PluginCommand> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    /// run the command of the scripts for the user in the room, answered once
+    /// the script is done, `None` if no script has it
+    pub fn command(
+        &self,
+        room: &str,
+        user: &str,
+        name: &str,
+        args: &str,
+    ) -> Option<oneshot::Receiver<CommandReply>> {
+        if !self
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|command| command.name == name)
+        {
+            return None;
+        }
+        let (reply, receiver) = oneshot::channel();
+        self.send(Job::Command {
+            room: room.to_string(),
+            user: user.to_string(),
+            name: name.to_string(),
+            args: args.to_string(),
+            reply,
+        });
+        Some(receiver)
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            // the thread only ends once the scripts panicked
+            let _ = jobs.send(job);
+        }
+    }
+}
+
+/// the thread of the scripts, running the jobs until the runner is dropped
+fn run(
+    mut plugins: Plugins,
+    shared: &Mutex<Shared>,
+    commands: &Mutex<Vec<PluginCommand>>,
+    queue: &mpsc::Receiver<Job>,
+    actions: &UnboundedSender<NamedActions>,
+) {
+    loop {
+        match queue.recv_timeout(SAVE_INTERVAL) {
+            Ok(Job::Event(event)) => {
+                let taken = plugins.on_event(event.as_event());
+                if !taken.is_empty() {
+                    let _ = actions.unbounded_send(taken);
+                }
+            }
+            Ok(Job::Command {
+                room,
+                user,
+                name,
+                args,
+                reply,
+            }) => {
+                let answer = plugins
+                    .command(&room, &user, &name, &args)
+                    .unwrap_or_else(|| {
+                        (
+                            Err(format!("unknown command /{name}, see /help")),
+                            Vec::new(),
+                        )
+                    });
+                let _ = reply.send(answer);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        *commands.lock().unwrap() = plugins.commands();
+        if plugins.is_empty() {
+            break;
+        }
+        shared.lock().unwrap().flush(false);
+    }
+    shared.lock().unwrap().flush(true);
+}
+
+/// the sandboxed engine with the API of the scripts
+fn engine(shared: &Arc<Mutex<Shared>>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(64 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        .on_print(|text| info!("script: {}", text))
+        .on_debug(|text, _, _| info!("script: {}", text));
+
+    let progress = shared.clone();
+    engine.on_progress(move |operations| {
+        if operations % 1000 != 0 {
+            return None;
+        }
+        let deadline = progress.lock().unwrap().deadline;
+        deadline
+            .filter(|deadline| Instant::now() > *deadline)
+            .map(|_| "the script ran for too long".into())
+    });
+
+    let state = shared.clone();
+    engine.register_fn("command", move |name: &str, usage: &str, help: &str| {
+        if !command::is_valid_name(name) {
+            return Err(format!("{name} is not a valid command name").into());
+        }
+        state.lock().unwrap().commands.push(PluginCommand {
+            name: name.to_string(),
+            usage: usage.to_string(),
+            help: help.to_string(),
+        });
+        Ok::<_, Box<EvalAltResult>>(())
+    });
+    let state = shared.clone();
+    engine.register_fn("say", move |room: &str, text: &str| {
+        state.lock().unwrap().actions.push(PluginAction::Say {
+            room: room.to_string(),
+            text: text.to_string(),
+        });
+    });
+    let state = shared.clone();
+    engine.register_fn("reply", move |user: &str, text: &str| {
+        state.lock().unwrap().actions.push(PluginAction::Reply {
+            user: user.to_string(),
+            text: text.to_string(),
+        });
+    });
+    let state = shared.clone();
+    engine.register_fn("user", move |name: &str| {
+        state
+            .lock()
+            .unwrap()
+            .users
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    });
+    let state = shared.clone();
+    engine.register_fn("store_get", move |key: &str| {
+        let shared = state.lock().unwrap();
+        shared
+            .store
+            .get(&shared.script)
+            .and_then(|store| store.get(key))
+            .cloned()
+            .unwrap_or_default()
+    });
+    let state = shared.clone();
+    engine.register_fn("store_set", move |key: &str, value: &str| {
+        let mut shared = state.lock().unwrap();
+        let script = shared.script.clone();
+        let store = shared.store.entry(script).or_default();
+        if store.len() >= MAX_STORE_KEYS && !store.contains_key(key) {
+            return Err(format!("the store is limited to {MAX_STORE_KEYS} keys").into());
+        }
+        store.insert(key.to_string(), value.to_string());
+        shared.dirty = true;
+        Ok::<_, Box<EvalAltResult>>(())
+    });
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugin::Plugins;
+
+    use super::*;
+
+    #[test]
+    fn test_scripts() {
+        let dir = std::env::temp_dir().join(format!("scripts-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let store_path = dir.join("store.json");
+        fs::write(
+            dir.join("greet.rhai"),
+            r#"
+                command("greet", "/greet", "greet the room");
+                command("spin", "/spin", "never stops");
+
+                fn greet(room, name, args) {
+                    say(room, `bob is in ${user("bob")}`);
+                    "greeted"
+                }
+
+                fn spin(room, user, args) {
+                    loop {}
+                }
+
+                fn on_join(room, user) {
+                    let joins = store_get("joins");
+                    let joins = if joins == "" { 0 } else { parse_int(joins) };
+                    store_set("joins", `${joins + 1}`);
+                    reply(user, `welcome to ${room}`);
+                }
+            "#,
+        )
+        .unwrap();
+        fs::write(dir.join("broken.rhai"), "fn oops( {").unwrap();
+
+        let mut plugins = Plugins::default();
+        let scripts = Scripts::open(&dir, &store_path).unwrap();
+        let shared = scripts.shared.clone();
+        plugins.register(scripts);
+        assert_eq!(plugins.commands().len(), 2);
+        assert_eq!(
+            plugins.on_event(ChatEvent::Joined {
+                room: "lobby",
+                user: "bob",
+            }),
+            [(
+                "scripts".to_string(),
+                PluginAction::Reply {
+                    user: "bob".to_string(),
+                    text: "welcome to lobby".to_string()
+                }
+            )]
+        );
+        let (result, actions) = plugins.command("lobby", "alice", "greet", "").unwrap();
+        assert_eq!(result, Ok("greeted".to_string()));
+        assert_eq!(
+            actions[0].1,
+            PluginAction::Say {
+                room: "lobby".to_string(),
+                text: "bob is in lobby".to_string()
+            }
+        );
+        // the endless loop is stopped
+        assert!(plugins
+            .command("lobby", "alice", "spin", "")
+            .unwrap()
+            .0
+            .is_err());
+        assert!(plugins.command("lobby", "alice", "nope", "").is_none());
+        // written once the thread of the scripts gets to it
+        assert!(shared.lock().unwrap().dirty);
+        shared.lock().unwrap().flush(false);
+
+        let mut scripts = Scripts::open(&dir, &store_path).unwrap();
+        scripts.on_event(
+            ChatEvent::Joined {
+                room: "lobby",
+                user: "carol",
+            },
+            &mut PluginActions::default(),
+        );
+        scripts.shared.lock().unwrap().flush(true);
+        // the store survives a restart
+        let store: BTreeMap<String, BTreeMap<String, String>> =
+            serde_json::from_str(&fs::read_to_string(&store_path).unwrap()).unwrap();
+        assert_eq!(store["greet"]["joins"], "2");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_runner() {
+        use futures_util::StreamExt;
+
+        let dir = std::env::temp_dir().join(format!("runner-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("echo.rhai"),
+            r#"
+                command("echo", "/echo <text>", "repeat the text");
+
+                fn echo(room, user, args) {
+                    args
+                }
+
+                fn on_message(room, user, text) {
+                    store_set("last", text);
+                    say(room, `${user} said ${text}`);
+                }
+            "#,
+        )
+        .unwrap();
+        let store_path = dir.join("store.json");
+        let (runner, mut actions) = ScriptRunner::start(Scripts::open(&dir, &store_path).unwrap());
+        assert_eq!(runner.commands().len(), 1);
+        assert!(runner.command("lobby", "bob", "nope", "").is_none());
+        let reply = runner.command("lobby", "bob", "echo", "hi").unwrap();
+        assert_eq!(reply.await.unwrap().0, Ok("hi".to_string()));
+
+        let message = MessageData {
+            name: "bob".to_string(),
+            data: "hello".to_string(),
+            ..Default::default()
+        };
+        runner.event(ChatEvent::Message {
+            room: "lobby",
+            message: &message,
+        });
+        assert_eq!(
+            actions.next().await.unwrap(),
+            [(
+                "scripts".to_string(),
+                PluginAction::Say {
+                    room: "lobby".to_string(),
+                    text: "bob said hello".to_string()
+                }
+            )]
+        );
+        // the thread writes the store once it ends
+        drop(runner);
+        assert!(actions.next().await.is_none());
+        let store: BTreeMap<String, BTreeMap<String, String>> =
+            serde_json::from_str(&fs::read_to_string(&store_path).unwrap()).unwrap();
+        assert_eq!(store["echo"]["last"], "hello");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
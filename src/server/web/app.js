@@ -0,0 +1,207 @@
+// The browser client, speaking the JSON protocol of the native clients
+// (`WebSocketClientToServerMessage` and `WebSocketServerToClientMessage`).
+"use strict";
+
+const $ = (id) => document.getElementById(id);
+
+let socket = null;
+let me = null;
+let nonce = 0;
+// user id -> name
+const users = new Map();
+
+function append(className, ...parts) {
+  const item = document.createElement("li");
+  item.className = className;
+  for (const part of parts) {
+    if (typeof part === "string") {
+      item.append(part);
+    } else {
+      const span = document.createElement("span");
+      span.className = part.className;
+      span.textContent = part.text;
+      item.append(span);
+    }
+  }
+  const messages = $("messages");
+  const atBottom = messages.scrollTop + messages.clientHeight >= messages.scrollHeight - 4;
+  messages.append(item);
+  if (atBottom) {
+    messages.scrollTop = messages.scrollHeight;
+  }
+}
+
+function renderUsers() {
+  const list = $("users");
+  list.replaceChildren();
+  for (const name of [...users.values()].sort()) {
+    const item = document.createElement("li");
+    item.textContent = name;
+    list.append(item);
+  }
+}
+
+function renderRoom(room) {
+  $("room").textContent = "#" + room.name;
+  $("topic").textContent = room.topic;
+}
+
+function showMessage(message) {
+  const own = me !== null && message.id === me.id && message.name === me.name;
+  const name = { className: "name", text: message.name };
+  const file = message.attachment ? ` [${message.attachment.file_name}]` : "";
+  switch (message.kind) {
+    case "System":
+      append("system", message.data);
+      break;
+    case "Action":
+      append(own ? "own" : "", "* ", name, " " + message.data + file);
+      break;
+    default:
+      if (message.recipient) {
+        append("private" + (own ? " own" : ""), name, ` → ${message.recipient}: ${message.data}${file}`);
+      } else {
+        append(own ? "own" : "", name, ": " + message.data + file);
+      }
+  }
+}
+
+function receive(message) {
+  if (typeof message === "string") {
+    return;
+  }
+  const [type, value] = Object.entries(message)[0];
+  switch (type) {
+    case "Connected":
+      me = { id: value[0], name: value[1] };
+      users.set(value[0], value[1]);
+      $("login").hidden = true;
+      $("chat").hidden = false;
+      $("status").textContent = "signed in as " + me.name;
+      $("text").focus();
+      break;
+    case "AllUsers":
+      users.clear();
+      for (const [id, name] of value) {
+        users.set(id, name);
+      }
+      break;
+    case "NewUserAdded":
+      users.set(value[0], value[1]);
+      break;
+    case "Disconnected":
+      users.delete(value[0]);
+      break;
+    case "Renamed":
+      users.set(value[0], value[1]);
+      if (me !== null && value[0] === me.id) {
+        me.name = value[1];
+        $("status").textContent = "signed in as " + me.name;
+      }
+      break;
+    case "UserMessage":
+      showMessage(value);
+      break;
+    case "Joined":
+      renderRoom(value.room);
+      break;
+    case "RoomUpdated":
+      if ($("room").textContent === "#" + value.name) {
+        renderRoom(value);
+      }
+      break;
+    case "CommandOutput":
+      append("output", value.text);
+      break;
+    case "Notice":
+      append("output", value);
+      break;
+    case "Invited":
+      append("output", `${value.from} invited you to #${value.room}, /accept ${value.room} to join`);
+      break;
+    case "Rejected":
+      append("error", value.reason);
+      break;
+    case "MessageDeleted":
+      append("system", "a message was deleted by a moderator");
+      break;
+    case "Token":
+      localStorage.setItem("token:" + me.name, value);
+      append("output", `${me.name} is registered now, this browser keeps its token`);
+      break;
+  }
+  renderUsers();
+}
+
+// false while the connection is down
+function send(message) {
+  if (socket === null || socket.readyState !== WebSocket.OPEN) {
+    append("error", "not connected, the message was not sent");
+    return false;
+  }
+  socket.send(JSON.stringify(message));
+  return true;
+}
+
+// the same rules as `command::parse`, `//` starts a message with a slash
+function submit(line) {
+  const trimmed = line.trim();
+  if (trimmed === "") {
+    return;
+  }
+  nonce += 1;
+  const command = trimmed.startsWith("/") && !trimmed.startsWith("//") ? trimmed.slice(1) : null;
+  if (command !== null && command !== "") {
+    const split = command.search(/\s/);
+    const name = split === -1 ? command : command.slice(0, split);
+    const args = split === -1 ? "" : command.slice(split).trim();
+    send({ Command: { nonce, name, args } });
+  } else {
+    const data = line.startsWith("//") ? line.slice(1) : line;
+    const message = { id: me.id, name: me.name, data, nonce, format: "Plain", kind: "Normal" };
+    // the server relays a message to everyone but its sender
+    if (send({ UserMessage: message })) {
+      showMessage(message);
+    }
+  }
+}
+
+function connect(name) {
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  socket = new WebSocket(`${scheme}//${location.host}/ws`);
+  // a registered name needs the token the server sent for it
+  const token = localStorage.getItem("token:" + name);
+  socket.onopen = () => send(token === null ? { Connect: name } : { Login: { name, token } });
+  socket.onmessage = (event) => {
+    if (typeof event.data === "string") {
+      receive(JSON.parse(event.data));
+    }
+  };
+  socket.onclose = (event) => {
+    socket = null;
+    if (me === null) {
+      $("login-error").textContent = event.reason || "cannot connect to the server";
+      return;
+    }
+    if (event.reason) {
+      // closed by the server, e.g. when kicked or shut down
+      append("error", "closed: " + event.reason);
+      $("status").textContent = "closed";
+      return;
+    }
+    $("status").textContent = "reconnecting…";
+    setTimeout(() => connect(me.name), 1000);
+  };
+}
+
+$("login").addEventListener("submit", (event) => {
+  event.preventDefault();
+  $("login-error").textContent = "";
+  connect($("name").value.trim());
+});
+
+$("send").addEventListener("submit", (event) => {
+  event.preventDefault();
+  submit($("text").value);
+  $("text").value = "";
+});
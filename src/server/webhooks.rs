@@ -0,0 +1,131 @@
+//! Incoming webhooks that post into a room, e.g. for CI systems.
+//!
+//! The owner of a room adds a webhook with `/webhook add <name> [signed]` and
+//! gets a secret URL `/hooks/<token>`. Every
+//!
+//! ```text
+//! POST /hooks/<token>
+//! {"text": "build #42 passed", "format": "Markdown"}
+//! ```
+//!
+//! is relayed to the room as a message of the bot `bot:<name>`, through the
+//! content filter and the plugins like any other message. A signed webhook
+//! also needs the HMAC-SHA256 of the body with its secret, sent as
+//! `X-Signature-256: sha256=<hex>`. The webhooks are saved with their room.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{
+    api,
+    bots::bot_name,
+    http::{Request, Response},
+    ServerState,
+};
+
+/// the header carrying the signature of a signed webhook
+const SIGNATURE_HEADER: &str = "x-signature-256";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Webhook {
+    /// the bot the messages are attributed to
+    pub name: String,
+    /// the last part of the URL
+    pub token: String,
+    /// the key of the signatures, `None` for webhooks that are not signed
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+impl Webhook {
+    /// a webhook with a random token and, if signed, a random secret
+    pub fn new(name: &str, signed: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            token: format!("{:032x}", rand::random::<u128>()),
+            secret: signed.then(|| format!("{:032x}", rand::random::<u128>())),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        format!("/hooks/{}", self.token)
+    }
+
+    /// whether the body may be posted with the signature header
+    fn verify(&self, body: &[u8], signature: Option<&str>) -> bool {
+        let Some(secret) = &self.secret else {
+            return true;
+        };
+        // compared in constant time by the mac
+        signature
+            .and_then(|signature| signature.strip_prefix("sha256="))
+            .and_then(decode_hex)
+            .is_some_and(|signature| mac(secret, body).verify_slice(&signature).is_ok())
+    }
+}
+
+fn mac(secret: &str, body: &[u8]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("an hmac takes keys of any length");
+    mac.update(body);
+    mac
+}
+
+/// the HMAC-SHA256 of the body with the secret, in hex
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    format!("{:x}", mac(secret, body).finalize().into_bytes())
+}
+
+/// the bytes of the hex string, `None` if it is not one
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// answer a request to `/hooks/<token>`, the body is read already
+pub(super) fn handle(state: &ServerState, request: &Request) -> Response {
+    let token = request.path.trim_start_matches("/hooks/");
+    let Some((room, webhook)) = state.rooms.lock().unwrap().webhook(token) else {
+        return Response::error(404, "no such webhook");
+    };
+    if request.method != "POST" {
+        return Response::error(405, "method not allowed");
+    }
+    if !webhook.verify(&request.body, request.header(SIGNATURE_HEADER)) {
+        return Response::error(401, "the signature does not match the body");
+    }
+    api::post(state, &bot_name(&webhook.name), &room, &request.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signatures() {
+        // the second test case of RFC 4231
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let webhook = Webhook::new("ci", true);
+        let secret = webhook.secret.clone().unwrap();
+        let signature = format!("sha256={}", sign(&secret, b"{}"));
+        assert!(webhook.verify(b"{}", Some(&signature)));
+        let upper = format!("sha256={}", sign(&secret, b"{}").to_ascii_uppercase());
+        assert!(webhook.verify(b"{}", Some(&upper)));
+        assert!(!webhook.verify(b"{}", Some("sha256=+f")));
+        assert!(!webhook.verify(b"{ }", Some(&signature)));
+        assert!(!webhook.verify(b"{}", None));
+        assert!(Webhook::new("ci", false).verify(b"{}", None));
+        assert_ne!(webhook.token, Webhook::new("ci", true).token);
+    }
+}
@@ -0,0 +1,257 @@
+//! Helpers for tests running a real server, enabled by the `test-util` feature.
+//!
+//! [`TestServer::start`] runs a server on a free local port with a data
+//! directory of its own, [`TestServer::connect`] and [`TestServer::join`]
+//! connect a [`TestClient`] whose `expect_*` methods fail the test when the server does
+//! not answer in time:
+//!
+//! ```no_run
+//! use websocket_chatroom::{test_util::TestServer, WebSocketServerToClientMessage};
+//!
+//! # async fn test() {
+//! let server = TestServer::start().await;
+//! let mut alice = server.join("alice").await;
+//! let bob = server.join("bob").await;
+//! alice
+//!     .wait_for(|message| match message {
+//!         WebSocketServerToClientMessage::NewUserAdded(id, name) => Some((*id, name.clone())),
+//!         _ => None,
+//!     })
+//!     .await;
+//! # }
+//! ```
+
+use std::{
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    client::{ChatClient, ClientEvent},
+    server::{ChatServer, ServerBuilder, ShutdownHandle},
+    MessageData, WebSocketServerToClientMessage,
+};
+
+/// how long a client waits for the server before the test fails
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the servers started by this process, to give each a data directory of its own
+static SERVERS: AtomicU32 = AtomicU32::new(0);
+
+/// a server running in the background, shut down and deleted when dropped
+pub struct TestServer {
+    url: String,
+    data_dir: PathBuf,
+    /// the tokens of the names registered when the server started
+    tokens: Vec<(String, String)>,
+    shutdown: ShutdownHandle,
+}
+
+impl TestServer {
+    /// start a server with the default settings
+    pub async fn start() -> Self {
+        Self::start_with(|builder| builder).await
+    }
+
+    /// start a server with the settings of `configure`, the data directory is
+    /// set by the harness
+    pub async fn start_with(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> Self {
+        let data_dir = std::env::temp_dir().join(format!(
+            "chatroom-test-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let server = configure(ChatServer::builder())
+            .data_dir(&data_dir)
+            .bind("127.0.0.1:0")
+            .await
+            .expect("cannot start the test server");
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let shutdown = server.shutdown_handle();
+        let tokens = server.tokens().to_vec();
+        tokio::spawn(server.run());
+        Self {
+            url,
+            data_dir,
+            tokens,
+            shutdown,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// send a plain HTTP request, the headers are lines like `Authorization: Bearer <key>`,
+    /// answers the status and the body
+    pub async fn http(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[&str],
+        body: &str,
+    ) -> (u16, String) {
+        let address = self.url.trim_start_matches("ws://");
+        let exchange = async {
+            let mut stream = TcpStream::connect(address).await?;
+            let mut request = format!("{method} {path} HTTP/1.1\r\nHost: {address}\r\n");
+            for header in headers {
+                request.push_str(&format!("{header}\r\n"));
+            }
+            request.push_str(&format!("Content-Length: {}\r\n\r\n{body}", body.len()));
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+        let response = tokio::time::timeout(TIMEOUT, exchange)
+            .await
+            .unwrap_or_else(|_| panic!("{method} {path} timed out"))
+            .unwrap_or_else(|e| panic!("{method} {path} failed: {e}"));
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .unwrap_or_else(|| panic!("{method} {path} got no status: {head}"));
+        (status, body.to_string())
+    }
+
+    /// the token of a name given a role with [`ServerBuilder::role`]
+    pub fn token(&self, name: &str) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, token)| token.as_str())
+    }
+
+    /// connect a client, logging in if the name was given a role, the
+    /// `Connected` and `AllUsers` of the handshake are read already, see
+    /// [`ChatClient::id`] and [`ChatClient::users`]
+    pub async fn connect(&self, name: &str) -> TestClient {
+        let connect = async {
+            match self.token(name) {
+                Some(token) => ChatClient::login(&self.url, name, token).await,
+                None => ChatClient::connect(&self.url, name).await,
+            }
+        };
+        let client = tokio::time::timeout(TIMEOUT, connect)
+            .await
+            .unwrap_or_else(|_| panic!("{name} timed out connecting"))
+            .unwrap_or_else(|e| panic!("{name} cannot connect: {e}"));
+        TestClient { client }
+    }
+
+    /// connect a client and skip the messages up to the roles of the lobby
+    pub async fn join(&self, name: &str) -> TestClient {
+        let mut client = self.connect(name).await;
+        client
+            .wait_for(|message| match message {
+                WebSocketServerToClientMessage::RoomRoles(_) => Some(()),
+                _ => None,
+            })
+            .await;
+        client
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+/// a [`ChatClient`] that fails the test instead of waiting forever
+pub struct TestClient {
+    client: ChatClient,
+}
+
+impl Deref for TestClient {
+    type Target = ChatClient;
+
+    fn deref(&self) -> &ChatClient {
+        &self.client
+    }
+}
+
+impl DerefMut for TestClient {
+    fn deref_mut(&mut self) -> &mut ChatClient {
+        &mut self.client
+    }
+}
+
+impl TestClient {
+    /// the next event, failing if the server sends nothing in time
+    pub async fn expect_event(&mut self) -> ClientEvent {
+        let name = self.client.name().to_string();
+        tokio::time::timeout(TIMEOUT, self.client.next_event())
+            .await
+            .unwrap_or_else(|_| panic!("{name} timed out waiting for an event"))
+            .unwrap_or(ClientEvent::Disconnected)
+    }
+
+    /// the next message, failing on anything else
+    pub async fn expect_message(&mut self) -> WebSocketServerToClientMessage {
+        match self.expect_event().await {
+            ClientEvent::Message(message) => message,
+            event => panic!("{} expected a message, got {event:?}", self.client.name()),
+        }
+    }
+
+    /// the next message relayed to the room, skipping acks and the like
+    pub async fn expect_user_message(&mut self) -> MessageData {
+        self.wait_for(|message| match message {
+            WebSocketServerToClientMessage::UserMessage(message) => Some(message.clone()),
+            _ => None,
+        })
+        .await
+    }
+
+    /// skip messages until `f` accepts one, failing if none comes in time
+    pub async fn wait_for<T>(
+        &mut self,
+        mut f: impl FnMut(&WebSocketServerToClientMessage) -> Option<T>,
+    ) -> T {
+        let name = self.client.name().to_string();
+        let wait = async {
+            loop {
+                match self.client.next_event().await {
+                    Some(ClientEvent::Message(message)) => {
+                        if let Some(found) = f(&message) {
+                            return found;
+                        }
+                    }
+                    Some(ClientEvent::Chunk(..)) => {}
+                    event => panic!("{name} lost the connection waiting: {event:?}"),
+                }
+            }
+        };
+        tokio::time::timeout(TIMEOUT, wait)
+            .await
+            .unwrap_or_else(|_| panic!("{name} timed out waiting for a message"))
+    }
+
+    /// fail if the server sends anything within `wait`
+    pub async fn expect_silence(&mut self, wait: Duration) {
+        if let Ok(event) = tokio::time::timeout(wait, self.client.next_event()).await {
+            panic!("{} expected nothing, got {event:?}", self.client.name());
+        }
+    }
+
+    /// close the connection and wait for the server to acknowledge it
+    pub async fn close(self) {
+        self.client.close().await;
+    }
+}
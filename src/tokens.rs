@@ -0,0 +1,91 @@
+//! The tokens of the registered names of the user, kept by the clients.
+//!
+//! A server registers a name once it is given a role and sends its token a
+//! single time, so the clients store it in a json file and log in with it
+//! whenever they connect under the name again.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use tracing::warn;
+
+pub struct Tokens {
+    path: PathBuf,
+    /// keyed by `<name>@<url>`
+    tokens: BTreeMap<String, String>,
+}
+
+impl Tokens {
+    /// load the tokens stored at `path`, a missing or broken file gives no tokens
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let tokens = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("ignoring broken tokens {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                warn!("cannot read tokens {}: {}", path.display(), e);
+                BTreeMap::new()
+            }
+        };
+        Self { path, tokens }
+    }
+
+    /// the default location of the tokens file
+    pub fn default_path() -> PathBuf {
+        dirs::data_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("websocket_chatroom")
+            .join("tokens.json")
+    }
+
+    /// the token of the name on the server at `url`
+    pub fn get(&self, url: &str, name: &str) -> Option<&str> {
+        self.tokens.get(&key(url, name)).map(String::as_str)
+    }
+
+    /// keep the token the server sent for the name
+    pub fn insert(&mut self, url: &str, name: &str, token: String) {
+        self.tokens.insert(key(url, name), token);
+        if let Err(e) = write_tokens(&self.path, &self.tokens) {
+            warn!("cannot write tokens {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn key(url: &str, name: &str) -> String {
+    format!("{name}@{url}")
+}
+
+fn write_tokens(path: &Path, tokens: &BTreeMap<String, String>) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(tokens)?)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_survive_reload() {
+        let path = std::env::temp_dir().join(format!("tokens-test-{}.json", std::process::id()));
+        let mut tokens = Tokens::load(&path);
+        tokens.insert("ws://chat", "alice", "secret".to_string());
+
+        let reloaded = Tokens::load(&path);
+        assert_eq!(reloaded.get("ws://chat", "alice"), Some("secret"));
+        assert_eq!(reloaded.get("ws://other", "alice"), None);
+        assert_eq!(reloaded.get("ws://chat", "bob"), None);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//! Chunked file transfer over binary websocket frames.
+//!
+//! Every binary frame starts with the 8 byte big endian id of the transfer it
+//! belongs to, the rest of the frame is the payload. Uploads are announced with
+//! [`crate::WebSocketClientToServerMessage::BeginUpload`], downloads with
+//! [`crate::WebSocketServerToClientMessage::DownloadStarted`].
+
+use sha2::{Digest, Sha256};
+
+/// the size of the payload of a single binary frame
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn encode_chunk(transfer_id: u64, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(8 + data.len());
+    frame.extend_from_slice(&transfer_id.to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// split a binary frame into the transfer id and the payload
+pub fn decode_chunk(frame: &[u8]) -> Option<(u64, &[u8])> {
+    if frame.len() < 8 {
+        return None;
+    }
+    let (id, data) = frame.split_at(8);
+    Some((u64::from_be_bytes(id.try_into().unwrap()), data))
+}
+
+/// the lowercase hex sha-256 of the data, attachments are addressed by it
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// guess the mime type from the extension of the file name
+pub fn guess_mime(file_name: &str) -> &'static str {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "txt" | "log" | "rs" | "toml" | "md" => "text/plain",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// the last component of the path, so a file name from the network can never
+/// point outside of the directory it is saved to
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let name = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if name.is_empty() {
+        "attachment".to_string()
+    } else {
+        name.chars().filter(|c| !c.is_control()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_round_trip() {
+        let frame = encode_chunk(42, b"hello");
+        assert_eq!(decode_chunk(&frame), Some((42, &b"hello"[..])));
+        assert_eq!(decode_chunk(b"short"), None);
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_file_name("C:\\Users\\a\\b.png"), "b.png");
+        assert_eq!(sanitize_file_name(".."), "attachment");
+    }
+}
@@ -0,0 +1,446 @@
+//! End-to-end tests of a server with headless clients (see `test_util`).
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message;
+
+use websocket_chatroom::{
+    client::{ChatClient, ClientEvent, ConnectError},
+    command::LOBBY,
+    server::webhooks,
+    test_util::{TestClient, TestServer},
+    transfer, MessageData, MessageFormat, MessageKind, Role, RoomInfo,
+    WebSocketClientToServerMessage, WebSocketServerToClientMessage,
+};
+
+/// a system line of the server
+fn system(message: WebSocketServerToClientMessage) -> String {
+    match message {
+        WebSocketServerToClientMessage::UserMessage(MessageData {
+            kind: MessageKind::System,
+            data,
+            ..
+        }) => data,
+        message => panic!("expected a system line, got {message:?}"),
+    }
+}
+
+/// the roles of the room, sorted by user id
+async fn roles(client: &mut TestClient) -> Vec<(u32, Role)> {
+    match client.expect_message().await {
+        WebSocketServerToClientMessage::RoomRoles(mut roles) => {
+            roles.sort();
+            roles
+        }
+        message => panic!("expected the roles, got {message:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_connect_broadcast_disconnect() {
+    let server = TestServer::start().await;
+
+    let mut alice = server.connect("alice").await;
+    assert_eq!(alice.users(), [(alice.id(), "alice".to_string())]);
+    match alice.expect_message().await {
+        WebSocketServerToClientMessage::Joined { room } => assert_eq!(room.name, LOBBY),
+        message => panic!("expected the lobby, got {message:?}"),
+    }
+    assert_eq!(system(alice.expect_message().await), "alice joined");
+    assert_eq!(roles(&mut alice).await, [(alice.id(), Role::Member)]);
+
+    let mut bob = server.connect("bob").await;
+    let mut users = bob.users().to_vec();
+    users.sort();
+    assert_eq!(
+        users,
+        [
+            (alice.id(), "alice".to_string()),
+            (bob.id(), "bob".to_string())
+        ]
+    );
+    assert_eq!(
+        alice.expect_message().await,
+        WebSocketServerToClientMessage::NewUserAdded(bob.id(), "bob".to_string())
+    );
+    assert_eq!(system(alice.expect_message().await), "bob joined");
+    assert_eq!(
+        roles(&mut alice).await,
+        [(alice.id(), Role::Member), (bob.id(), Role::Member)]
+    );
+
+    bob.say("hello").await.unwrap();
+    let message = alice.expect_user_message().await;
+    assert_eq!((message.id, message.data.as_str()), (bob.id(), "hello"));
+    let ack = bob
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::Ack { message_id, .. } => Some(*message_id),
+            _ => None,
+        })
+        .await;
+    assert_eq!(ack, message.message_id);
+
+    let bob_id = bob.id();
+    bob.close().await;
+    assert_eq!(
+        alice.expect_message().await,
+        WebSocketServerToClientMessage::Disconnected(bob_id, "bob".to_string())
+    );
+    assert_eq!(system(alice.expect_message().await), "bob left");
+}
+
+#[tokio::test]
+async fn test_rooms_keep_messages_apart() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    let mut carol = server.join("carol").await;
+    alice
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::UserMessage(message)
+                if message.data == "carol joined" =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await;
+
+    bob.command("join", "rust").await.unwrap();
+    carol.command("join", "rust").await.unwrap();
+    carol
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::RoomRoles(roles) if roles.len() == 2 => Some(()),
+            _ => None,
+        })
+        .await;
+    bob.say("borrowing").await.unwrap();
+    assert_eq!(carol.expect_user_message().await.data, "borrowing");
+
+    // alice only saw bob and carol leave the lobby
+    let mut lines = Vec::new();
+    while lines.len() < 2 {
+        if let WebSocketServerToClientMessage::UserMessage(message) = alice.expect_message().await {
+            lines.push(message.data);
+        }
+    }
+    assert_eq!(lines, ["bob left", "carol left"]);
+    alice.expect_silence(Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn test_markdown_falls_back_to_plain_text() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    let mut carol = server.join("carol").await;
+    let formats = WebSocketClientToServerMessage::Formats(vec![MessageFormat::Markdown]);
+    bob.send(formats).await.unwrap();
+    // answered once the formats are read
+    bob.command("who", "").await.unwrap();
+    bob.wait_for(|message| match message {
+        WebSocketServerToClientMessage::CommandOutput { .. } => Some(()),
+        _ => None,
+    })
+    .await;
+
+    let message = MessageData {
+        data: "**build** [passed](https://ci.example)".to_string(),
+        nonce: alice.next_nonce(),
+        format: MessageFormat::Markdown,
+        ..Default::default()
+    };
+    alice
+        .send(WebSocketClientToServerMessage::UserMessage(message))
+        .await
+        .unwrap();
+    let rendered = bob.expect_user_message().await;
+    assert_eq!(rendered.format, MessageFormat::Markdown);
+    assert_eq!(rendered.data, "**build** [passed](https://ci.example)");
+    // carol did not say she renders markdown
+    let plain = carol.expect_user_message().await;
+    assert_eq!(plain.format, MessageFormat::Plain);
+    assert_eq!(plain.data, "build passed (https://ci.example)");
+    assert_eq!(plain.message_id, rendered.message_id);
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_clients() {
+    let server = TestServer::start_with(|builder| builder.role("alice", Role::Owner)).await;
+    let mut alice = server.join("alice").await;
+    assert!(alice.users().iter().any(|(_, name)| name == "alice"));
+
+    server.shutdown();
+    loop {
+        match alice.expect_event().await {
+            ClientEvent::Closed(reason) => {
+                assert_eq!(reason, "the server is shutting down");
+                break;
+            }
+            ClientEvent::Message(_) => {}
+            event => panic!("expected the server to close, got {event:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_names_are_protected() {
+    let server = TestServer::start_with(|builder| builder.role("alice", Role::Owner)).await;
+    let refused = |result: Result<ChatClient, ConnectError>| match result {
+        Err(ConnectError::Refused(reason)) => reason,
+        result => panic!("expected a refusal, got {result:?}"),
+    };
+    let alice = server.join("alice").await;
+    let mut mallory = server.join("mallory").await;
+
+    // the owner's name is taken while alice is online and needs her token after
+    let reason = refused(ChatClient::connect(server.url(), "alice").await);
+    assert!(reason.contains("taken"), "{reason}");
+    alice.close().await;
+    let reason = refused(ChatClient::connect(server.url(), "alice").await);
+    assert!(reason.contains("registered"), "{reason}");
+    let reason = refused(ChatClient::login(server.url(), "alice", "0123456789abcdef").await);
+    assert!(reason.contains("wrong token"), "{reason}");
+    refused(ChatClient::connect(server.url(), "mallory").await);
+    refused(ChatClient::connect(server.url(), "not a name").await);
+
+    mallory.command("nick", "alice").await.unwrap();
+    let reason = mallory
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::Rejected { reason, .. } => Some(reason.clone()),
+            _ => None,
+        })
+        .await;
+    assert!(reason.contains("registered"), "{reason}");
+
+    // creating a room makes mallory its owner, so the name is registered
+    mallory.command("join", "den").await.unwrap();
+    let token = mallory
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::Token(token) => Some(token.clone()),
+            _ => None,
+        })
+        .await;
+    mallory.close().await;
+    refused(ChatClient::connect(server.url(), "mallory").await);
+    let mallory = ChatClient::login(server.url(), "mallory", &token)
+        .await
+        .unwrap();
+    assert_eq!(mallory.name(), "mallory");
+}
+
+#[tokio::test]
+async fn test_browser_client() {
+    let server = TestServer::start().await;
+    let (status, page) = server.http("GET", "/", &[], "").await;
+    assert_eq!(status, 200);
+    assert!(page.contains("<script src=\"/app.js\">"));
+    assert!(server
+        .http("GET", "/app.js", &[], "")
+        .await
+        .1
+        .contains("/ws"));
+    assert_eq!(server.http("GET", "/missing", &[], "").await.0, 404);
+
+    let mut alice = server.join("alice").await;
+    let url = format!("{}/ws", server.url());
+    let mut browser = ChatClient::connect(&url, "bob").await.unwrap();
+    assert_eq!(alice.expect_user_message().await.data, "bob joined");
+    browser.say("from the browser").await.unwrap();
+    let message = alice.expect_user_message().await;
+    assert_eq!(
+        (message.name.as_str(), message.data.as_str()),
+        ("bob", "from the browser")
+    );
+    browser.close().await;
+
+    // a frame that is not a message is refused without harming anyone else
+    let (mut raw, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    raw.send(Message::Text(r#"{"Nonsense": 1}"#.to_string()))
+        .await
+        .unwrap();
+    match raw.next().await {
+        Some(Ok(Message::Text(text))) => assert!(text.contains("Rejected"), "{text}"),
+        frame => panic!("expected a refusal, got {frame:?}"),
+    }
+    let mut carol = server.join("carol").await;
+    alice.say("still here").await.unwrap();
+    assert_eq!(carol.expect_user_message().await.data, "still here");
+
+    let other = format!("{}/other", server.url());
+    assert!(ChatClient::connect(&other, "carol").await.is_err());
+}
+
+#[tokio::test]
+async fn test_rest_api() {
+    const KEY: &str = "0123456789abcdef";
+    const ALICE_KEY: &str = "fedcba9876543210";
+    let server =
+        TestServer::start_with(|builder| builder.api_key("ci", KEY).api_key("alice", ALICE_KEY))
+            .await;
+    let auth = format!("Authorization: Bearer {KEY}");
+    let auth = [auth.as_str()];
+    let mut alice = server.join("alice").await;
+    alice.say("hi there").await.unwrap();
+
+    assert_eq!(server.http("GET", "/api/users", &[], "").await.0, 401);
+    let wrong = ["Authorization: Bearer 0123456789abcdeF"];
+    assert_eq!(server.http("GET", "/api/users", &wrong, "").await.0, 401);
+
+    let (status, users) = server.http("GET", "/api/users", &auth, "").await;
+    assert_eq!(status, 200);
+    let users: Vec<(u32, String)> = serde_json::from_str(&users).unwrap();
+    assert_eq!(users, [(alice.id(), "alice".to_string())]);
+    let (_, rooms) = server.http("GET", "/api/rooms", &auth, "").await;
+    let rooms: Vec<RoomInfo> = serde_json::from_str(&rooms).unwrap();
+    assert!(rooms.iter().any(|room| room.name == LOBBY));
+
+    let post = r#"{"text": "build passed"}"#;
+    let (status, answer) = server
+        .http("POST", "/api/rooms/lobby/messages", &auth, post)
+        .await;
+    assert_eq!(status, 200, "{answer}");
+    let message = alice.expect_user_message().await;
+    assert_eq!(
+        (message.name.as_str(), message.data.as_str()),
+        ("bot:ci", "build passed")
+    );
+    assert!(answer.contains(&message.message_id.to_string()));
+
+    let (_, history) = server
+        .http("GET", "/api/rooms/lobby/messages?limit=1", &auth, "")
+        .await;
+    let history: Vec<MessageData> = serde_json::from_str(&history).unwrap();
+    assert_eq!(history, [message]);
+
+    // a key named after a user does not post as the user
+    let alice_auth = format!("Authorization: Bearer {ALICE_KEY}");
+    let (status, _) = server
+        .http("POST", "/api/rooms/lobby/messages", &[&alice_auth], post)
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(alice.expect_user_message().await.name, "bot:alice");
+    let (status, _) = server
+        .http("POST", "/api/rooms/nowhere/messages", &auth, post)
+        .await;
+    assert_eq!(status, 404);
+    let (status, _) = server
+        .http("POST", "/api/rooms/lobby/messages", &auth, "{}")
+        .await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn test_webhooks() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    for client in [&mut alice, &mut bob] {
+        client.command("join", "builds").await.unwrap();
+    }
+    bob.wait_for(|message| match message {
+        WebSocketServerToClientMessage::RoomRoles(roles) if roles.len() == 2 => Some(()),
+        _ => None,
+    })
+    .await;
+    // alice created the room and owns it
+    alice.command("webhook", "add ci signed").await.unwrap();
+    let output = alice
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::CommandOutput { text, .. } => Some(text.clone()),
+            _ => None,
+        })
+        .await;
+    let (_, rest) = output.split_once("POST ").unwrap();
+    let (path, secret) = rest.split_once(", signed with the secret ").unwrap();
+
+    let body = r#"{"text": "build #42 passed"}"#;
+    assert_eq!(server.http("POST", path, &[], body).await.0, 401);
+    let signature = format!(
+        "X-Signature-256: sha256={}",
+        webhooks::sign(secret, body.as_bytes())
+    );
+    let (status, answer) = server.http("POST", path, &[&signature], body).await;
+    assert_eq!(status, 200, "{answer}");
+    let message = bob.expect_user_message().await;
+    assert_eq!(
+        (message.name.as_str(), message.data.as_str()),
+        ("bot:ci", "build #42 passed")
+    );
+    assert_eq!(alice.expect_user_message().await, message);
+
+    assert_eq!(
+        server.http("POST", "/hooks/unknown", &[], body).await.0,
+        404
+    );
+    bob.command("webhook", "remove ci").await.unwrap();
+    let refused = bob
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::Rejected { reason, .. } => Some(reason.clone()),
+            _ => None,
+        })
+        .await;
+    assert!(refused.contains("owner"), "{refused}");
+}
+
+#[tokio::test]
+async fn test_attachments_stay_in_their_room() {
+    let server = TestServer::start().await;
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice.command("join", "secret").await.unwrap();
+    alice.command("visibility", "private").await.unwrap();
+
+    let content = b"the plans";
+    let sha256 = transfer::sha256_hex(content);
+    alice
+        .send(WebSocketClientToServerMessage::BeginUpload {
+            upload_id: 7,
+            file_name: "plans.txt".to_string(),
+            size: content.len() as u64,
+            mime: "text/plain".to_string(),
+        })
+        .await
+        .unwrap();
+    alice.connection().send_chunk(7, content).await.unwrap();
+    alice
+        .send(WebSocketClientToServerMessage::FinishUpload {
+            upload_id: 7,
+            sha256: sha256.clone(),
+        })
+        .await
+        .unwrap();
+    alice
+        .wait_for(|message| match message {
+            WebSocketServerToClientMessage::Ack { nonce: 7, .. } => Some(()),
+            WebSocketServerToClientMessage::Rejected { reason, .. } => panic!("{reason}"),
+            _ => None,
+        })
+        .await;
+
+    // bob is not a member of the private room the file was posted to
+    let download = |transfer_id| WebSocketClientToServerMessage::Download {
+        transfer_id,
+        sha256: sha256.clone(),
+    };
+    bob.send(download(1)).await.unwrap();
+    bob.wait_for(|message| match message {
+        WebSocketServerToClientMessage::DownloadFailed { transfer_id: 1, .. } => Some(()),
+        _ => None,
+    })
+    .await;
+    alice.send(download(2)).await.unwrap();
+    let mut downloaded = Vec::new();
+    loop {
+        match alice.expect_event().await {
+            ClientEvent::Chunk(2, data) => downloaded.extend(data),
+            ClientEvent::Message(WebSocketServerToClientMessage::DownloadFinished {
+                transfer_id: 2,
+            }) => break,
+            ClientEvent::Message(_) => {}
+            event => panic!("expected the download, got {event:?}"),
+        }
+    }
+    assert_eq!(downloaded, content);
+}